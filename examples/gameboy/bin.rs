@@ -0,0 +1,503 @@
+extern crate gameboy;
+
+use clap::{Parser, ValueEnum};
+use gameboy::gameboy::{
+    read_window_size, ColorCorrection, Colorization, DmgPalettes, Filter, Gameboy, Ppu,
+    RenderMode, RenderOptions, Target, TerminalRenderer,
+};
+use gameboy::keys::{Action, KeyBindings};
+use gameboy::link::tcp::TcpLink;
+use gameboy::printer::{PrintedImage, Printer};
+use gameboy::scale::Scaler;
+use gameboy::shaders::Preset;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, process};
+
+#[derive(ValueEnum, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Frontend {
+    Desktop,
+    Terminal,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Model {
+    Dmg,
+    Cgb,
+    Sgb,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+enum Renderer {
+    Auto,
+    Image,
+    HalfBlock,
+    Braille,
+    Ascii,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Scaling {
+    Nearest,
+    Linear,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "gameboy", about = "Game Boy and Game Boy Color emulator")]
+struct Args {
+    rom: PathBuf,
+
+    #[arg(short, long, value_enum)]
+    frontend: Option<Frontend>,
+
+    #[arg(short, long, value_enum)]
+    model: Option<Model>,
+
+    /// Initial window size in multiples of 160x144, instead of the last one
+    #[arg(short, long)]
+    scale: Option<u32>,
+
+    /// How the desktop window scales the screen
+    #[arg(long, value_enum)]
+    filter: Option<Scaling>,
+
+    /// Fills the window instead of scaling by whole multiples
+    #[arg(long)]
+    stretch: bool,
+
+    #[arg(long)]
+    fullscreen: bool,
+
+    /// `none`, `lcd`, `dmg`, `scanlines`, `crt`, or a `.glsl` file or directory
+    #[arg(long, value_name = "PRESET")]
+    shader: Option<String>,
+
+    /// Upscaler of the terminal, web and screenshots: `nearest`, `scale2x` (or
+    /// `epx`), `scale3x`, `hq2x`, `hq3x` or `xbr2x`
+    #[arg(long, value_name = "NAME")]
+    scaler: Option<String>,
+
+    /// `gray`, `green`, `dmg`, `pocket`, `light` or four comma separated colors
+    /// such as `#e0f8d0,...`. Up to three separated by `;` color the
+    /// background, OBJ0 and OBJ1 differently.
+    #[arg(short, long)]
+    palette: Option<String>,
+
+    /// How CGB colors are shown: `none`, `gambatte` or `accurate`
+    #[arg(long, value_name = "NAME")]
+    color_correction: Option<String>,
+
+    /// `scanline`, or `fifo` for the slower dot by dot renderer that shows
+    /// mid-scanline raster effects
+    #[arg(long, value_name = "NAME")]
+    ppu: Option<String>,
+
+    /// Colors of DMG games on a CGB: `auto` for the boot ROM's pick from the
+    /// title, or the buttons held at boot, such as `left+a`
+    #[arg(long, value_name = "BUTTONS")]
+    colorize: Option<String>,
+
+    /// Binds keys to a button or hotkey, e.g. `--key start=Return,Space`
+    #[arg(long = "key", value_name = "ACTION=KEYS")]
+    keys: Vec<String>,
+
+    /// Binds controller buttons or stick directions, e.g. `--pad a=South,West`
+    #[arg(long = "pad", value_name = "ACTION=BUTTONS")]
+    pad: Vec<String>,
+
+    /// How far sticks move before they count as the d-pad, from 0 to 1
+    #[arg(long)]
+    deadzone: Option<f32>,
+
+    /// Which controller to follow, in the order they were plugged in
+    #[arg(long)]
+    player: Option<usize>,
+
+    /// Milliseconds a key stays down in terminals that cannot report releases
+    #[arg(long, value_name = "MS")]
+    key_hold: Option<u64>,
+
+    /// How the terminal frontend draws the screen
+    #[arg(long, value_enum)]
+    renderer: Option<Renderer>,
+
+    #[arg(long)]
+    boot_rom: Option<PathBuf>,
+
+    /// Where battery saves are kept, next to the ROM by default
+    #[arg(long)]
+    save_dir: Option<PathBuf>,
+
+    /// IPS or BPS patch applied to the ROM
+    #[arg(long)]
+    patch: Option<PathBuf>,
+
+    /// Defaults to config.toml in the `gameboy` user config directory
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Waits for a peer to connect its link cable to <ADDR>
+    #[arg(long, value_name = "ADDR", conflicts_with = "join")]
+    host: Option<String>,
+
+    /// Connects the link cable to a peer hosting on <ADDR>
+    #[arg(long, value_name = "ADDR")]
+    join: Option<String>,
+
+    /// Plugs in a Game Boy Printer that saves what it prints to <DIR>
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["host", "join"])]
+    printer: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum KeyList {
+    One(String),
+    Many(Vec<String>),
+}
+
+// Everything that can be set from the config file, globally or per ROM.
+// Unset values fall through to the next, less specific, layer.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Settings {
+    frontend: Option<Frontend>,
+    model: Option<Model>,
+    scale: Option<u32>,
+    filter: Option<Scaling>,
+    integer_scaling: Option<bool>,
+    fullscreen: Option<bool>,
+    shader: Option<String>,
+    scaler: Option<String>,
+    palette: Option<String>,
+    color_correction: Option<String>,
+    ppu: Option<String>,
+    colorize: Option<String>,
+    keys: HashMap<String, KeyList>,
+    pad: HashMap<String, KeyList>,
+    deadzone: Option<f32>,
+    player: Option<usize>,
+    key_hold: Option<u64>,
+    renderer: Option<Renderer>,
+    boot_rom: Option<PathBuf>,
+    save_dir: Option<PathBuf>,
+    patch: Option<PathBuf>,
+    printer: Option<PathBuf>,
+}
+
+impl Settings {
+    fn merge(&mut self, other: Settings) {
+        self.frontend = other.frontend.or(self.frontend);
+        self.model = other.model.or(self.model);
+        self.scale = other.scale.or(self.scale);
+        self.filter = other.filter.or(self.filter);
+        self.integer_scaling = other.integer_scaling.or(self.integer_scaling);
+        self.fullscreen = other.fullscreen.or(self.fullscreen);
+        self.shader = other.shader.or(self.shader.take());
+        self.scaler = other.scaler.or(self.scaler.take());
+        self.palette = other.palette.or(self.palette.take());
+        self.color_correction = other.color_correction.or(self.color_correction.take());
+        self.ppu = other.ppu.or(self.ppu.take());
+        self.colorize = other.colorize.or(self.colorize.take());
+        self.keys.extend(other.keys);
+        self.pad.extend(other.pad);
+        self.deadzone = other.deadzone.or(self.deadzone);
+        self.player = other.player.or(self.player);
+        self.key_hold = other.key_hold.or(self.key_hold);
+        self.renderer = other.renderer.or(self.renderer);
+        self.boot_rom = other.boot_rom.or(self.boot_rom.take());
+        self.save_dir = other.save_dir.or(self.save_dir.take());
+        self.patch = other.patch.or(self.patch.take());
+        self.printer = other.printer.or(self.printer.take());
+    }
+}
+
+// Overrides for one cartridge, matched on the header title and optionally on
+// the global checksum to tell revisions apart.
+#[derive(Deserialize, Debug)]
+struct RomSettings {
+    title: String,
+    checksum: Option<u16>,
+    #[serde(flatten)]
+    settings: Settings,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Config {
+    #[serde(flatten)]
+    settings: Settings,
+    #[serde(default)]
+    rom: Vec<RomSettings>,
+}
+
+fn load_config(path: Option<&Path>) -> Result<Config, String> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match dirs::config_dir() {
+            Some(dir) => (dir.join("gameboy").join("config.toml"), false),
+            None => return Ok(Config::default()),
+        },
+    };
+    match fs::read_to_string(&path) {
+        Ok(text) => {
+            toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+        }
+        Err(_) if !required => Ok(Config::default()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn header_title(rom: &[u8]) -> String {
+    let title = rom.get(0x134..0x144).unwrap_or(&[]);
+    let end = title.iter().position(|&b| b == 0).unwrap_or(title.len());
+    String::from_utf8_lossy(&title[..end]).trim().to_string()
+}
+
+fn header_checksum(rom: &[u8]) -> Option<u16> {
+    rom.get(0x14E..0x150)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+}
+
+fn settings_for(config: Config, rom: &[u8], args: &Args) -> Settings {
+    let title = header_title(rom);
+    let checksum = header_checksum(rom);
+
+    let mut settings = config.settings;
+    let (mut by_title, mut exact): (Vec<_>, Vec<_>) = config
+        .rom
+        .into_iter()
+        .filter(|r| r.title.eq_ignore_ascii_case(&title))
+        .filter(|r| r.checksum.is_none() || r.checksum == checksum)
+        .partition(|r| r.checksum.is_none());
+    for r in by_title.drain(..).chain(exact.drain(..)) {
+        settings.merge(r.settings);
+    }
+
+    settings.merge(Settings {
+        frontend: args.frontend,
+        model: args.model,
+        scale: args.scale,
+        filter: args.filter,
+        integer_scaling: args.stretch.then_some(false),
+        fullscreen: args.fullscreen.then_some(true),
+        shader: args.shader.clone(),
+        scaler: args.scaler.clone(),
+        palette: args.palette.clone(),
+        color_correction: args.color_correction.clone(),
+        ppu: args.ppu.clone(),
+        colorize: args.colorize.clone(),
+        keys: bindings(&args.keys),
+        pad: bindings(&args.pad),
+        deadzone: args.deadzone,
+        player: args.player,
+        key_hold: args.key_hold,
+        renderer: args.renderer,
+        boot_rom: args.boot_rom.clone(),
+        save_dir: args.save_dir.clone(),
+        patch: args.patch.clone(),
+        printer: args.printer.clone(),
+    });
+    settings
+}
+
+// `--key` and `--pad` values, as `action=key,key`
+fn bindings(args: &[String]) -> HashMap<String, KeyList> {
+    let mut bindings = HashMap::new();
+    for binding in args {
+        if let Some((action, list)) = binding.split_once('=') {
+            let list = list.split(',').map(str::to_string).collect();
+            bindings.insert(action.to_string(), KeyList::Many(list));
+        }
+    }
+    bindings
+}
+
+fn set_bindings(
+    bindings: &mut KeyBindings,
+    settings: &HashMap<String, KeyList>,
+) -> Result<(), String> {
+    for (action, keys) in settings {
+        let action = Action::from_name(action)
+            .ok_or_else(|| format!("unknown button or hotkey {:?}", action))?;
+        let keys: Vec<&str> = match keys {
+            KeyList::One(key) => vec![key.as_str()],
+            KeyList::Many(keys) => keys.iter().map(String::as_str).collect(),
+        };
+        bindings.set_keys(action, &keys);
+    }
+    Ok(())
+}
+
+fn render_options(
+    settings: &Settings,
+    save_path: &Path,
+) -> Result<RenderOptions, String> {
+    // The desktop window opens the size it was last closed at, unless a
+    // scale is asked for
+    let window_path = dirs::config_dir().map(|dir| dir.join("gameboy").join("window"));
+    let window_size = match settings.scale {
+        Some(_) => None,
+        None => window_path.as_deref().and_then(read_window_size),
+    };
+    let mut options = RenderOptions {
+        scale: settings.scale.unwrap_or(3),
+        filter: match settings.filter.unwrap_or(Scaling::Nearest) {
+            Scaling::Nearest => Filter::Nearest,
+            Scaling::Linear => Filter::Linear,
+        },
+        integer_scaling: settings.integer_scaling.unwrap_or(true),
+        fullscreen: settings.fullscreen.unwrap_or(false),
+        window_size,
+        window_path,
+        state_path: Some(save_path.with_extension("state")),
+        screenshot_dir: settings.save_dir.clone(),
+        ..RenderOptions::default()
+    };
+    if let Some(deadzone) = settings.deadzone {
+        options.deadzone = deadzone.clamp(0.0, 1.0);
+    }
+    options.player = settings.player.unwrap_or(0);
+    if let Some(ms) = settings.key_hold {
+        options.key_hold = Duration::from_millis(ms);
+    }
+    options.terminal = match settings.renderer.unwrap_or(Renderer::Auto) {
+        Renderer::Auto => TerminalRenderer::Auto,
+        Renderer::Image => TerminalRenderer::Image,
+        Renderer::HalfBlock => TerminalRenderer::HalfBlock,
+        Renderer::Braille => TerminalRenderer::Braille,
+        Renderer::Ascii => TerminalRenderer::Ascii,
+    };
+    if let Some(ref shader) = settings.shader {
+        options.shader = match options.shaders.iter().position(|p| &p.name == shader) {
+            Some(builtin) => builtin,
+            None => {
+                options.shaders.push(Preset::load(Path::new(shader))?);
+                options.shaders.len() - 1
+            }
+        };
+    }
+    // A palette that is not one of the built-in ones joins them, for the
+    // hotkey to come back to
+    if let Some(ref palette) = settings.palette {
+        let palettes = DmgPalettes::parse(palette)?;
+        options.palette = match options.palettes.iter().position(|p| p.1 == palettes) {
+            Some(builtin) => builtin,
+            None => {
+                options.palettes.push((palette.clone(), palettes));
+                options.palettes.len() - 1
+            }
+        };
+    }
+    if let Some(ref scaler) = settings.scaler {
+        options.scaler =
+            Scaler::from_name(scaler).ok_or(format!("unknown scaler {:?}", scaler))?;
+    }
+    set_bindings(&mut options.keys, &settings.keys)?;
+    set_bindings(&mut options.pad, &settings.pad)?;
+    Ok(options)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let read =
+        |path: &Path| fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+
+    let config = load_config(args.config.as_deref())?;
+    let mut rom = read(&args.rom)?;
+    let settings = settings_for(config, &rom, &args);
+
+    if let Some(ref patch) = settings.patch {
+        rom = gameboy::patch::apply(&rom, &read(patch)?)
+            .map_err(|e| format!("{}: {}", patch.display(), e))?;
+    }
+
+    // The cartridge saves next to the file it was given, see `MBC::new`
+    let save_path = match settings.save_dir {
+        Some(ref dir) => {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            dir.join(args.rom.file_name().unwrap())
+        }
+        None => args.rom.clone(),
+    };
+
+    let target = match settings.model.unwrap_or(Model::Cgb) {
+        Model::Dmg => Target::GameBoy,
+        Model::Cgb => Target::GameBoyColor,
+        Model::Sgb => Target::SuperGameBoy,
+    };
+    let mut gb = Gameboy::with_target(rom, Some(save_path.clone()), target);
+
+    if let Some(ref boot_rom) = settings.boot_rom {
+        gb.load_boot_rom(read(boot_rom)?)
+            .map_err(|e| format!("{}: {}", boot_rom.display(), e))?;
+    }
+    let options = render_options(&settings, &save_path)?;
+    // DMG games on a CGB keep the boot ROM's colors unless a palette is given
+    if settings.palette.is_some() {
+        gb.set_palettes(options.palettes[options.palette].1);
+    }
+    if let Some(ref colorize) = settings.colorize {
+        let colorization = Colorization::from_name(colorize)
+            .ok_or(format!("unknown colorization {:?}", colorize))?;
+        gb.set_colorization(colorization);
+    }
+    if let Some(ref correction) = settings.color_correction {
+        gb.set_color_correction(
+            ColorCorrection::from_name(correction)
+                .ok_or(format!("unknown color correction {:?}", correction))?,
+        );
+    }
+    if let Some(ref ppu) = settings.ppu {
+        gb.set_ppu(Ppu::from_name(ppu).ok_or(format!("unknown PPU {:?}", ppu))?);
+    }
+
+    let link = match (&args.host, &args.join) {
+        (Some(addr), _) => Some(TcpLink::host(addr)),
+        (_, Some(addr)) => Some(TcpLink::join(addr)),
+        _ => None,
+    };
+    if let Some(link) = link {
+        let link = link.map_err(|e| format!("link cable: {}", e))?;
+        gb.set_serial_device(Box::new(link));
+    } else if let Some(ref dir) = settings.printer {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        // Paper comes out in the background shades of the DMG palette
+        let palette = options.palettes[options.palette]
+            .1
+            .bg
+            .map(|[r, g, b]| [r, g, b, 255]);
+        let dir = dir.clone();
+        let printer = Printer::with_palette(
+            palette,
+            Box::new(move |image: PrintedImage| {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let path = dir.join(format!("print-{}.png", time.as_millis()));
+                if let Err(e) = image.save(&path) {
+                    eprintln!("gameboy: {}: {}", path.display(), e);
+                }
+            }),
+        );
+        gb.set_serial_device(Box::new(printer));
+    }
+
+    let mode = match settings.frontend.unwrap_or(Frontend::Desktop) {
+        Frontend::Desktop => RenderMode::Desktop,
+        Frontend::Terminal => RenderMode::Terminal,
+    };
+    gb.render_with(mode, options);
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("gameboy: {}", e);
+        process::exit(1);
+    }
+}
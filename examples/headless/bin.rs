@@ -0,0 +1,228 @@
+extern crate gameboy;
+
+use gameboy::gameboy::{Gameboy, KeypadKey, Ppu, Target};
+use std::fs;
+use std::process::exit;
+
+const USAGE: &str = "usage: headless <rom> [options]
+
+  --frames <n>          frames to run (default 600)
+  --model <dmg|cgb|sgb> hardware to emulate (default cgb)
+  --ppu <scanline|fifo> renderer, fifo for mid-scanline effects (default scanline)
+  --input <file>        input script, lines of `<frame> <down|up> <key>`
+  --screenshot <file>   save the last frame as a PNG
+  --serial <file>       save everything sent over the link port, `-` for stdout
+  --expect <text>       stop once the serial output contains <text>, fail if it never does
+  --load-state <file>   start from a save state
+  --save-state <file>   write a save state after the last frame";
+
+struct Options {
+    rom: String,
+    frames: u32,
+    target: Target,
+    ppu: Ppu,
+    input: Option<String>,
+    screenshot: Option<String>,
+    serial: Option<String>,
+    expect: Option<String>,
+    load_state: Option<String>,
+    save_state: Option<String>,
+}
+
+#[derive(Debug)]
+struct InputEvent {
+    frame: u32,
+    down: bool,
+    key: KeypadKey,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        rom: String::new(),
+        frames: 600,
+        target: Target::GameBoyColor,
+        ppu: Ppu::Scanline,
+        input: None,
+        screenshot: None,
+        serial: None,
+        expect: None,
+        load_state: None,
+        save_state: None,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.rom = arg;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--frames" => {
+                options.frames = value
+                    .parse()
+                    .map_err(|_| format!("invalid frame count {:?}", value))?
+            }
+            "--model" => {
+                options.target = match value.as_str() {
+                    "dmg" => Target::GameBoy,
+                    "cgb" => Target::GameBoyColor,
+                    "sgb" => Target::SuperGameBoy,
+                    _ => return Err(format!("unknown model {:?}", value)),
+                }
+            }
+            "--ppu" => {
+                options.ppu =
+                    Ppu::from_name(&value).ok_or(format!("unknown PPU {:?}", value))?
+            }
+            "--input" => options.input = Some(value),
+            "--screenshot" => options.screenshot = Some(value),
+            "--serial" => options.serial = Some(value),
+            "--expect" => options.expect = Some(value),
+            "--load-state" => options.load_state = Some(value),
+            "--save-state" => options.save_state = Some(value),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err(String::from("missing ROM path"));
+    }
+    Ok(options)
+}
+
+fn parse_key(name: &str) -> Option<KeypadKey> {
+    match name.to_ascii_lowercase().as_str() {
+        "right" => Some(KeypadKey::Right),
+        "left" => Some(KeypadKey::Left),
+        "up" => Some(KeypadKey::Up),
+        "down" => Some(KeypadKey::Down),
+        "a" => Some(KeypadKey::A),
+        "b" => Some(KeypadKey::B),
+        "select" => Some(KeypadKey::Select),
+        "start" => Some(KeypadKey::Start),
+        _ => None,
+    }
+}
+
+fn parse_input(script: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("invalid input on line {}: {:?}", i + 1, line);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let [frame, action, key] = parts[..] else {
+            return Err(invalid());
+        };
+        events.push(InputEvent {
+            frame: frame.parse().map_err(|_| invalid())?,
+            down: match action {
+                "down" => true,
+                "up" => false,
+                _ => return Err(invalid()),
+            },
+            key: parse_key(key).ok_or_else(invalid)?,
+        });
+    }
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+fn run(options: Options) -> Result<bool, String> {
+    let data = fs::read(&options.rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    let mut gb = Gameboy::try_with_target(data, None, options.target)
+        .map_err(|e| format!("{}: {}", options.rom, e))?;
+    gb.set_ppu(options.ppu);
+
+    if let Some(ref path) = options.load_state {
+        let state = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        gb.load_state(&state)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    let events = match options.input {
+        Some(ref path) => {
+            let script =
+                fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            parse_input(&script)?
+        }
+        None => Vec::new(),
+    };
+
+    if options.serial.is_some() || options.expect.is_some() {
+        gb.capture_serial();
+    }
+
+    let mut events = events.iter().peekable();
+    let mut found = false;
+    for frame in 0..options.frames {
+        while let Some(event) = events.next_if(|e| e.frame <= frame) {
+            if event.down {
+                gb.keydown(event.key);
+            } else {
+                gb.keyup(event.key);
+            }
+        }
+        gb.frame();
+
+        if let Some(ref text) = options.expect {
+            if gb.serial_output().contains(text.as_str()) {
+                found = true;
+                break;
+            }
+        }
+    }
+
+    if let Some(ref path) = options.screenshot {
+        let (width, height) = (gb.width, gb.height);
+        let rgb: Vec<u8> = gb
+            .image()
+            .chunks(4)
+            .flat_map(|p| [p[0], p[1], p[2]])
+            .collect();
+        image::save_buffer(path, &rgb, width, height, image::ExtendedColorType::Rgb8)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    match options.serial.as_deref() {
+        Some("-") => print!("{}", gb.serial_output()),
+        Some(path) => {
+            fs::write(path, gb.serial_output()).map_err(|e| format!("{}: {}", path, e))?
+        }
+        None => {}
+    }
+
+    if let Some(ref path) = options.save_state {
+        fs::write(path, gb.save_state()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    Ok(options.expect.is_none() || found)
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            exit(2);
+        }
+    };
+
+    let expect = options.expect.clone();
+    match run(options) {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("serial output never contained {:?}", expect.unwrap());
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
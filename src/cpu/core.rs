@@ -1,6 +1,8 @@
 use crate::cpu::registers::Registers;
 use crate::cpu::{data, ld, misc, stack};
-use crate::mmu::MemoryManagementUnit;
+use crate::gameboy::Target;
+use crate::mmu::{MemoryManagementUnit, StrResult};
+use crate::state::{StateReader, StateWriter};
 
 #[allow(dead_code)]
 pub enum Interrupt {
@@ -26,10 +28,23 @@ pub struct Cpu<'a> {
 
 impl Cpu<'_> {
     pub fn new(data: Vec<u8>, file: Option<std::path::PathBuf>) -> Self {
-        let memory = MemoryManagementUnit::new_cgb(data, file).unwrap();
+        Cpu::new_for(data, file, Target::GameBoyColor).unwrap()
+    }
+
+    // The Super Game Boy runs cartridges like a classic Game Boy.
+    pub fn new_for(
+        data: Vec<u8>,
+        file: Option<std::path::PathBuf>,
+        target: Target,
+    ) -> StrResult<Self> {
+        let memory = match target {
+            Target::GameBoyColor => MemoryManagementUnit::new_cgb(data, file),
+            Target::GameBoy => MemoryManagementUnit::new(data, file),
+            Target::SuperGameBoy => MemoryManagementUnit::new_sgb(data, file),
+        }?;
         let registers = Registers::new(memory.gbmode);
 
-        Cpu {
+        Ok(Cpu {
             registers,
             memory,
             ime: false,
@@ -38,7 +53,7 @@ impl Cpu<'_> {
             halt: 0,
             stop: 0,
             _executed_operations: Vec::new(),
-        }
+        })
     }
     fn mut_find_or_insert<T: PartialEq>(vec: &mut Vec<T>, val: T) -> &mut T {
         if let Some(i) = vec.iter().position(|each| *each == val) {
@@ -53,6 +68,39 @@ impl Cpu<'_> {
         // println!("{} {:#01x} {}", op, op, format!("{:?}", self.registers));
         // println!("{} {}", op, format!("{:?}", self.registers));
     }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.registers;
+        for v in [r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.f] {
+            w.u8(v);
+        }
+        w.u16(r.pc);
+        w.u16(r.sp);
+        w.bool(self.ime);
+        w.u32(self.setdi);
+        w.u32(self.setei);
+        w.u32(self.halt);
+        w.u32(self.stop);
+        self.memory.save_state(w);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> StrResult<()> {
+        let r = &mut self.registers;
+        for v in [
+            &mut r.a, &mut r.b, &mut r.c, &mut r.d, &mut r.e, &mut r.h, &mut r.l,
+            &mut r.f,
+        ] {
+            *v = reader.u8()?;
+        }
+        r.pc = reader.u16()?;
+        r.sp = reader.u16()?;
+        self.ime = reader.bool()?;
+        self.setdi = reader.u32()?;
+        self.setei = reader.u32()?;
+        self.halt = reader.u32()?;
+        self.stop = reader.u32()?;
+        self.memory.load_state(reader)
+    }
+
     pub fn get_byte(&mut self) -> u8 {
         let pc = self.memory.rb(self.registers.pc);
         self.registers.pc = self.registers.pc.wrapping_add(1);
@@ -121,6 +169,11 @@ impl Cpu<'_> {
             // Emulate an noop instruction
             1
         } else {
+            if self.memory.hooks.active() {
+                let pc = self.registers.pc;
+                let opcode = self.memory.peek(pc);
+                self.memory.hooks.execute(pc, opcode, self.memory.clock);
+            }
             self.operation()
         }
     }
@@ -0,0 +1,137 @@
+// Turns machine code back into assembly, for debuggers and traces. Opcodes
+// are decoded from their bit fields rather than from a table, the same way
+// the instruction set is laid out.
+
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STACK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const R16_MEMORY: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// Decodes the instruction at `address`, whose bytes start `code`. Returns the
+// assembly and the length of the instruction in bytes. Missing operand bytes
+// read as zero.
+pub fn disassemble(code: &[u8], address: u16) -> (String, u16) {
+    let byte = |i: usize| code.get(i).copied().unwrap_or(0);
+    let op = byte(0);
+    let d8 = byte(1);
+    let d16 = u16::from_le_bytes([byte(1), byte(2)]);
+    // Relative jumps are shown with the address they land on
+    let relative = address.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+    let offset = match d8 as i8 {
+        e if e < 0 => format!("-${:02X}", e.unsigned_abs()),
+        e => format!("+${:02X}", e),
+    };
+
+    let (x, y, z) = (
+        (op >> 6) as usize,
+        (op >> 3 & 7) as usize,
+        (op & 7) as usize,
+    );
+    let (p, q) = (y >> 1, y & 1);
+
+    let (text, len) = match (x, z) {
+        (0, 0) => match y {
+            0 => (String::from("NOP"), 1),
+            1 => (format!("LD (${:04X}),SP", d16), 3),
+            2 => (String::from("STOP"), 2),
+            3 => (format!("JR ${:04X}", relative), 2),
+            _ => (format!("JR {},${:04X}", CONDITIONS[y - 4], relative), 2),
+        },
+        (0, 1) if q == 0 => (format!("LD {},${:04X}", R16[p], d16), 3),
+        (0, 1) => (format!("ADD HL,{}", R16[p]), 1),
+        (0, 2) if q == 0 => (format!("LD {},A", R16_MEMORY[p]), 1),
+        (0, 2) => (format!("LD A,{}", R16_MEMORY[p]), 1),
+        (0, 3) if q == 0 => (format!("INC {}", R16[p]), 1),
+        (0, 3) => (format!("DEC {}", R16[p]), 1),
+        (0, 4) => (format!("INC {}", R8[y]), 1),
+        (0, 5) => (format!("DEC {}", R8[y]), 1),
+        (0, 6) => (format!("LD {},${:02X}", R8[y], d8), 2),
+        (0, _) => {
+            let ops = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+            (String::from(ops[y]), 1)
+        }
+        (1, _) if op == 0x76 => (String::from("HALT"), 1),
+        (1, _) => (format!("LD {},{}", R8[y], R8[z]), 1),
+        (2, _) => (format!("{}{}", ALU[y], R8[z]), 1),
+        _ => match op {
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => (format!("RET {}", CONDITIONS[y]), 1),
+            0xC9 => (String::from("RET"), 1),
+            0xD9 => (String::from("RETI"), 1),
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                (format!("JP {},${:04X}", CONDITIONS[y], d16), 3)
+            }
+            0xC3 => (format!("JP ${:04X}", d16), 3),
+            0xE9 => (String::from("JP HL"), 1),
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                (format!("CALL {},${:04X}", CONDITIONS[y], d16), 3)
+            }
+            0xCD => (format!("CALL ${:04X}", d16), 3),
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => (format!("POP {}", R16_STACK[p]), 1),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => (format!("PUSH {}", R16_STACK[p]), 1),
+            _ if z == 6 => (format!("{}${:02X}", ALU[y], d8), 2),
+            _ if z == 7 => (format!("RST ${:02X}", y * 8), 1),
+            0xCB => (prefixed(d8), 2),
+            0xE0 => (format!("LDH ($FF{:02X}),A", d8), 2),
+            0xF0 => (format!("LDH A,($FF{:02X})", d8), 2),
+            0xE2 => (String::from("LD ($FF00+C),A"), 1),
+            0xF2 => (String::from("LD A,($FF00+C)"), 1),
+            0xEA => (format!("LD (${:04X}),A", d16), 3),
+            0xFA => (format!("LD A,(${:04X})", d16), 3),
+            0xE8 => (format!("ADD SP,{}", offset), 2),
+            0xF8 => (format!("LD HL,SP{}", offset), 2),
+            0xF9 => (String::from("LD SP,HL"), 1),
+            0xF3 => (String::from("DI"), 1),
+            0xFB => (String::from("EI"), 1),
+            // Locks up the CPU on hardware, most likely data
+            _ => (format!("DB ${:02X}", op), 1),
+        },
+    };
+    (text, len)
+}
+
+fn prefixed(op: u8) -> String {
+    let (x, y, z) = (
+        (op >> 6) as usize,
+        (op >> 3 & 7) as usize,
+        (op & 7) as usize,
+    );
+    match x {
+        0 => format!("{} {}", ROTATIONS[y], R8[z]),
+        1 => format!("BIT {},{}", y, R8[z]),
+        2 => format!("RES {},{}", y, R8[z]),
+        _ => format!("SET {},{}", y, R8[z]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::disassemble;
+
+    #[test]
+    fn decodes_operands_and_lengths() {
+        let cases: [(&[u8], &str, u16); 12] = [
+            (&[0x00], "NOP", 1),
+            (&[0x31, 0xFE, 0xFF], "LD SP,$FFFE", 3),
+            (&[0x22], "LD (HL+),A", 1),
+            (&[0x3E, 0x91], "LD A,$91", 2),
+            (&[0x18, 0xFE], "JR $0150", 2),
+            (&[0x20, 0x05], "JR NZ,$0157", 2),
+            (&[0x7E], "LD A,(HL)", 1),
+            (&[0xAF], "XOR A", 1),
+            (&[0xFE, 0x90], "CP $90", 2),
+            (&[0xE0, 0x40], "LDH ($FF40),A", 2),
+            (&[0xCB, 0x7C], "BIT 7,H", 2),
+            (&[0xF8, 0xF0], "LD HL,SP-$10", 2),
+        ];
+        for (code, text, len) in cases {
+            assert_eq!(disassemble(code, 0x150), (String::from(text), len));
+        }
+        assert_eq!(disassemble(&[0xCD, 0x34, 0x12], 0).0, "CALL $1234");
+        assert_eq!(disassemble(&[0xD3], 0).0, "DB $D3");
+    }
+}
@@ -1,5 +1,6 @@
 pub mod core;
-mod registers;
+pub mod disasm;
+pub mod registers;
 
 mod data;
 mod ld;
@@ -1,8 +1,27 @@
 use crate::cpu::core::Cpu;
-use crate::input::KeypadKey;
+use crate::cpu::registers::Registers;
+use crate::keys::KeyBindings;
+use crate::mode::GbMode;
+use crate::search::Cheat;
+use crate::state::{StateReader, StateWriter, MAGIC, VERSION};
+use std::ops::RangeInclusive;
+
+pub use crate::gpu::{
+    ColorCorrection, Colorization, DmgPalettes, Palette, Ppu, BUILTIN as PALETTES, DMG,
+    GRAYSCALE, GREEN, LIGHT, POCKET,
+};
+pub use crate::input::KeypadKey;
+pub use crate::mmu::hooks::{AccessKind, HookId, MemoryAccess};
+use crate::mmu::serial::SerialCapture;
+pub use crate::mmu::serial::SerialDevice;
 
 pub struct Gameboy {
     cpu: Cpu<'static>,
+    cheats: Vec<Cheat>,
+    serial_capture: Option<SerialCapture>,
+    // Snapshot of the machine right after power on, see `reset`
+    power_on: Vec<u8>,
+    boot_rom: Option<Vec<u8>>,
     pub width: u32,
     pub height: u32,
 }
@@ -21,6 +40,105 @@ pub enum RenderMode {
     WebAssembly,
 }
 
+// How the terminal frontend draws the screen
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum TerminalRenderer {
+    // An image protocol if the terminal has one, half blocks otherwise
+    #[default]
+    Auto,
+    Image,
+    HalfBlock,
+    Braille,
+    Ascii,
+}
+
+// How the desktop frontend samples the screen when scaling it
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum Filter {
+    // Sharp pixels
+    #[default]
+    Nearest,
+    Linear,
+}
+
+// Settings of the interactive frontends
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    // Initial size of the screen in multiples of 160x144
+    pub scale: u32,
+    pub keys: KeyBindings,
+    // Where the save and load state hotkeys keep their state, in memory if unset
+    pub state_path: Option<std::path::PathBuf>,
+    // Screenshots go to the working directory if unset
+    pub screenshot_dir: Option<std::path::PathBuf>,
+    // Controller layout, see `gamepad::default_bindings`
+    pub pad: KeyBindings,
+    pub deadzone: f32,
+    // Which of the connected controllers plays, for linked instances
+    pub player: usize,
+    // How long terminals without key release events hold a key after it is
+    // pressed, it should cover the delay before the terminal repeats it
+    pub key_hold: std::time::Duration,
+    pub terminal: TerminalRenderer,
+    pub filter: Filter,
+    // Scales the screen by whole multiples only, with black borders around it
+    pub integer_scaling: bool,
+    pub fullscreen: bool,
+    // Desktop window size to start with instead of `scale`, in logical pixels
+    pub window_size: Option<(u32, u32)>,
+    // Where the desktop window size is written when it closes, see
+    // `read_window_size`
+    pub window_path: Option<std::path::PathBuf>,
+    // Post-processing presets of the desktop frontend, and the one to start with
+    pub shaders: Vec<crate::shaders::Preset>,
+    pub shader: usize,
+    // Software upscaler of the terminal and web frontends and of screenshots
+    pub scaler: crate::scale::Scaler,
+    // DMG palettes the hotkey cycles through, starting after `palette`. The
+    // one in use is set on the `Gameboy`, see `set_palettes`.
+    pub palettes: Vec<(String, DmgPalettes)>,
+    pub palette: usize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            scale: 1,
+            keys: KeyBindings::default(),
+            state_path: None,
+            screenshot_dir: None,
+            pad: crate::gamepad::default_bindings(),
+            deadzone: 0.5,
+            player: 0,
+            key_hold: std::time::Duration::from_millis(500),
+            terminal: TerminalRenderer::Auto,
+            filter: Filter::Nearest,
+            integer_scaling: true,
+            fullscreen: false,
+            window_size: None,
+            window_path: None,
+            shaders: crate::shaders::builtin(),
+            shader: 0,
+            scaler: crate::scale::Scaler::Nearest,
+            palettes: PALETTES
+                .iter()
+                .map(|&(name, palette)| (name.to_string(), DmgPalettes::new(palette)))
+                .collect(),
+            palette: 0,
+        }
+    }
+}
+
+// A bank of one of the memory areas, addressed independently of what is
+// currently mapped into the CPU address space.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum MemoryBank {
+    Rom(usize),
+    Vram(usize),
+    Wram(usize),
+    CartRam(usize),
+}
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum Target {
     GameBoy,
@@ -39,13 +157,20 @@ pub fn load_rom(filepath: &str) -> Result<(Vec<u8>, std::path::PathBuf), String>
     }
 
     let file = File::open(filepath);
-    let filepath = Default::default();
     match file.and_then(|mut f| f.read_to_end(&mut rom)) {
         Ok(_) => {}
         Err(e) => return Err(format!("Failed to read {:?}: {}", filepath, e)),
     };
 
-    Ok((rom, filepath))
+    Ok((rom, std::path::PathBuf::from(filepath)))
+}
+
+// Window size saved by the desktop frontend, as `<width>x<height>`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_window_size(path: &std::path::Path) -> Option<(u32, u32)> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let (width, height) = text.trim().split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
 }
 
 pub const CYCLES: u32 = 70224;
@@ -54,20 +179,54 @@ impl Gameboy {
     pub fn new(data: Vec<u8>, filepath: Option<std::path::PathBuf>) -> Gameboy {
         // let rom = load_rom();
 
-        let gb = Gameboy {
-            cpu: Cpu::new(data, filepath),
-            width: 160,
-            height: 144,
+        Gameboy::with_target(data, filepath, GameBoyColor)
+    }
+
+    pub fn with_target(
+        data: Vec<u8>,
+        filepath: Option<std::path::PathBuf>,
+        target: Target,
+    ) -> Gameboy {
+        Gameboy::try_with_target(data, filepath, target).unwrap()
+    }
+
+    // Like `with_target`, for ROMs that may not run on the target or use an
+    // unsupported cartridge
+    pub fn try_with_target(
+        data: Vec<u8>,
+        filepath: Option<std::path::PathBuf>,
+        target: Target,
+    ) -> Result<Gameboy, &'static str> {
+        let cpu = Cpu::new_for(data, filepath, target)?;
+        // The SGB shows its border around the screen
+        let (width, height) = match cpu.memory.sgb {
+            Some(_) => (256, 224),
+            None => (160, 144),
         };
+        let mut gb = Gameboy {
+            cpu,
+            cheats: Vec::new(),
+            serial_capture: None,
+            power_on: Vec::new(),
+            boot_rom: None,
+            width,
+            height,
+        };
+        gb.power_on = gb.save_state();
 
-        gb
+        Ok(gb)
     }
 
     pub fn render(self, render_mode: RenderMode) {
+        self.render_with(render_mode, RenderOptions::default());
+    }
+
+    #[allow(unused_variables)]
+    pub fn render_with(self, render_mode: RenderMode, options: RenderOptions) {
         match render_mode {
             #[cfg(not(target_arch = "wasm32"))]
             RenderMode::Desktop => {
-                self.render_desktop();
+                self.render_desktop(options);
             }
             #[cfg(target_arch = "wasm32")]
             RenderMode::WebAssembly => {
@@ -75,24 +234,37 @@ impl Gameboy {
             }
             #[cfg(not(target_arch = "wasm32"))]
             RenderMode::Terminal => {
-                self.render_terminal();
+                self.render_terminal(options);
             }
         }
     }
 
     #[cfg(not(target_arch = "wasm32"))]
-    pub fn render_desktop(mut self) {
+    pub fn render_desktop(mut self, options: RenderOptions) {
+        use crate::gamepad::{Gamepads, Gilrs};
+        use crate::screen::controls::Controls;
         use crate::screen::desktop::*;
 
         let event_loop: glutin::event_loop::EventLoop<()> =
             glutin::event_loop::EventLoop::with_user_event();
-        let inner_size = glutin::dpi::LogicalSize {
-            width: self.width,
-            height: self.height,
-        };
+        let scale = options.scale.max(1);
+        let (width, height) = options
+            .window_size
+            .unwrap_or((self.width * scale, self.height * scale));
+        let inner_size = glutin::dpi::LogicalSize { width, height };
+        let borderless = || Some(glutin::window::Fullscreen::Borderless(None));
         let window_builder = glutin::window::WindowBuilder::new()
             .with_title("Gameboy")
             .with_inner_size(inner_size)
+            .with_min_inner_size(glutin::dpi::LogicalSize {
+                width: self.width,
+                height: self.height,
+            })
+            .with_fullscreen(if options.fullscreen {
+                borderless()
+            } else {
+                None
+            })
             .with_resizable(true);
         let gl_window = glutin::ContextBuilder::new()
             .build_windowed(window_builder, &event_loop)
@@ -101,8 +273,22 @@ impl Gameboy {
 
         gl::load_with(|s| gl_window.get_proc_address(s) as *const _);
 
-        let cx = Glcx::new();
+        let mut cx = Glcx::new(options.filter, options.integer_scaling);
+        let mut controls = Controls::new(&options);
+        // Keyboard only if the platform has no gamepad support
+        let mut gamepads = Gilrs::new()
+            .ok()
+            .map(|gilrs| Gamepads::new(gilrs, options.pad.clone(), options.deadzone));
+        // Shader preset in use, the plain one until another compiles
+        let mut shader = controls.shader;
+        if let Some(preset) = options.shaders.get(shader) {
+            if let Err(e) = cx.set_preset(preset) {
+                eprintln!("{}", e);
+            }
+        }
         let mut focused = true;
+        // Last size outside of fullscreen, to be remembered
+        let mut windowed = inner_size;
         event_loop.run(move |event, _, control_flow| {
             let window = gl_window.window();
             match event {
@@ -110,13 +296,64 @@ impl Gameboy {
                     window_id: _,
                     event: wevent,
                 } => {
-                    *control_flow =
-                        process_window(window, &wevent, &mut self, &mut focused)
+                    *control_flow = process_window(
+                        window,
+                        &wevent,
+                        &mut self,
+                        &mut controls,
+                        &mut focused,
+                    )
                 }
                 glutin::event::Event::MainEventsCleared => window.request_redraw(),
+                glutin::event::Event::LoopDestroyed => {
+                    if let Some(ref path) = options.window_path {
+                        if let Some(dir) = path.parent() {
+                            let _ = std::fs::create_dir_all(dir);
+                        }
+                        let size = format!("{}x{}", windowed.width, windowed.height);
+                        let _ = std::fs::write(path, size);
+                    }
+                }
                 glutin::event::Event::RedrawRequested(_) => {
-                    self.frame();
-                    cx.draw(self.width, self.height, self.image());
+                    let pad = gamepads.as_mut().map(Gamepads::poll).unwrap_or_default();
+                    for pad in pad.into_iter().filter(|p| p.player == options.player) {
+                        match pad.pressed {
+                            true => controls.press(&mut self, pad.action),
+                            false => controls.release(&mut self, pad.action),
+                        }
+                    }
+                    if controls.shader != shader && !options.shaders.is_empty() {
+                        controls.shader %= options.shaders.len();
+                        shader = controls.shader;
+                        let preset = &options.shaders[shader];
+                        controls.status = match cx.set_preset(preset) {
+                            Ok(()) => Some(format!("Shader: {}", preset.name)),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                Some(format!("Shader {} failed to compile", preset.name))
+                            }
+                        };
+                    }
+                    if let Some(status) = controls.status.take() {
+                        window.set_title(&format!("Gameboy - {}", status));
+                    }
+                    if controls.fullscreen != window.fullscreen().is_some() {
+                        window.set_fullscreen(match controls.fullscreen {
+                            true => borderless(),
+                            false => None,
+                        });
+                    }
+                    let size = window.inner_size();
+                    if window.fullscreen().is_none() {
+                        windowed = size.to_logical(window.scale_factor());
+                    }
+                    controls.run(&mut self);
+                    cx.draw(
+                        (size.width, size.height),
+                        self.width,
+                        self.height,
+                        self.image(),
+                    );
                     gl_window.swap_buffers().unwrap();
 
                     std::thread::sleep(std::time::Duration::from_millis(5));
@@ -132,10 +369,10 @@ impl Gameboy {
     }
 
     #[cfg(not(target_arch = "wasm32"))]
-    pub fn render_terminal(mut self) {
+    pub fn render_terminal(mut self, options: RenderOptions) {
         use crate::screen::tui;
 
-        let _ = tui::run(&mut self);
+        let _ = tui::run(&mut self, &options);
     }
 
     #[inline]
@@ -145,29 +382,326 @@ impl Gameboy {
         result
     }
 
+    // Executes a single instruction and returns the elapsed ticks.
+    pub fn step(&mut self) -> u32 {
+        self.cpu.do_cycle()
+    }
+
     pub fn frame(&mut self) {
-        // let waitticks = (4194304f64 / 1000.0 * 16.0).round() as u32;
-        let waitticks = CYCLES;
-        let mut ticks = 0;
-
-        'outer: loop {
-            while ticks < waitticks {
-                ticks += self.cpu.do_cycle();
-                if self.check_and_reset_gpu_updated() {
-                    break 'outer;
-                }
+        self.frame_until(&[]);
+    }
+
+    // Like `frame`, but stops right before an instruction at one of
+    // `breakpoints` would run. The first instruction always runs, so a frame
+    // stopped at a breakpoint goes on from there. Returns true when stopped.
+    pub fn frame_until(&mut self, breakpoints: &[u16]) -> bool {
+        let mut first = true;
+        loop {
+            if !first && breakpoints.contains(&self.cpu.registers.pc) {
+                return true;
+            }
+            first = false;
+            self.cpu.do_cycle();
+            if self.check_and_reset_gpu_updated() {
+                break;
             }
+        }
+
+        let cheats = std::mem::take(&mut self.cheats);
+        for cheat in &cheats {
+            cheat.apply(self);
+        }
+        self.cheats = cheats;
+        false
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.cpu.registers
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.cpu.ime
+    }
+
+    pub fn halted(&self) -> bool {
+        self.cpu.halt != 0
+    }
 
-            ticks -= waitticks;
+    // Starts execution from a DMG (256 bytes) or CGB (2304 bytes) boot ROM
+    // instead of the post-boot state. Has to be called before running.
+    pub fn load_boot_rom(&mut self, boot: Vec<u8>) -> Result<(), &'static str> {
+        if boot.len() != 0x100 && boot.len() != 0x900 {
+            return Err("Boot ROM has to be 256 or 2304 bytes long");
         }
+        self.cpu.memory.boot_rom = Some(boot.clone());
+        self.cpu.memory.wb(0xFF40, 0);
+        let r = &mut self.cpu.registers;
+        (r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.f) = (0, 0, 0, 0, 0, 0, 0, 0);
+        r.pc = 0;
+        r.sp = 0;
+        self.boot_rom = Some(boot);
+        self.power_on = self.save_state();
+        Ok(())
+    }
+
+    // Like turning the console off and on again, the cartridge keeps the
+    // contents of its RAM. Cheats, hooks and the serial device stay in place.
+    pub fn reset(&mut self) {
+        let ram = self.cpu.memory.mbc.ram().to_vec();
+        self.cpu.memory.boot_rom = self.boot_rom.clone();
+        let power_on = std::mem::take(&mut self.power_on);
+        self.load_state(&power_on).unwrap();
+        self.power_on = power_on;
+        self.cpu.memory.mbc.ram_mut().copy_from_slice(&ram);
+    }
+
+    // Same palette for the background and both sprite palettes
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.set_palettes(DmgPalettes::new(palette));
+    }
+
+    // Takes effect from the next line drawn. Only DMG games use them, and on a
+    // CGB they replace the colorization.
+    pub fn set_palettes(&mut self, palettes: DmgPalettes) {
+        self.cpu.memory.gpu.palettes = palettes;
+        self.cpu.memory.gpu.colorization = None;
+    }
+
+    pub fn palettes(&self) -> DmgPalettes {
+        self.cpu.memory.gpu.palettes
+    }
+
+    // Colors DMG games running on a CGB the way its boot ROM would, given the
+    // buttons held at boot. Returns false for any other game.
+    pub fn set_colorization(&mut self, colorization: Colorization) -> bool {
+        if self.cpu.memory.gbmode != GbMode::ColorAsClassic {
+            return false;
+        }
+        self.cpu.memory.colorize(colorization);
+        true
+    }
+
+    // None unless a DMG game runs on a CGB without palettes of its own
+    pub fn colorization(&self) -> Option<Colorization> {
+        match self.cpu.memory.gbmode {
+            GbMode::ColorAsClassic => self.cpu.memory.gpu.colorization,
+            _ => None,
+        }
+    }
+
+    // Only CGB games and colorized DMG games use it
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.cpu.memory.gpu.set_color_correction(correction);
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.cpu.memory.gpu.color_correction()
+    }
+
+    // The pixel FIFO renderer shows changes made to the registers in the middle
+    // of a line, which the scanline one does not
+    pub fn set_ppu(&mut self, ppu: Ppu) {
+        self.cpu.memory.gpu.ppu = ppu;
+    }
+
+    pub fn ppu(&self) -> Ppu {
+        self.cpu.memory.gpu.ppu
+    }
+
+    // Whether the game runs in CGB mode, rather than on a DMG or in the CGB's
+    // compatibility mode
+    pub fn is_color(&self) -> bool {
+        self.cpu.memory.gbmode == GbMode::Color
+    }
+
+    // Header and global checksums, to tell which cartridge a state belongs to
+    fn cartridge_id(&self) -> [u8; 3] {
+        let mbc = &self.cpu.memory.mbc;
+        [mbc.readrom(0x14D), mbc.readrom(0x14E), mbc.readrom(0x14F)]
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.bytes(MAGIC);
+        w.u8(VERSION);
+        w.bytes(&self.cartridge_id());
+        self.cpu.save_state(&mut w);
+        w.into_inner()
+    }
+
+    // Nothing is changed unless the whole state could be read, a state that
+    // fails halfway leaves the emulator where it was.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), &'static str> {
+        let mut r = StateReader::new(state);
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("Not a save state");
+        }
+        if r.u8()? != VERSION {
+            return Err("Unsupported save state version");
+        }
+        let mut id = [0; 3];
+        r.bytes(&mut id)?;
+        if id != self.cartridge_id() {
+            return Err("Save state belongs to a different cartridge");
+        }
+
+        let backup = self.save_state();
+        // Loading unmaps the boot ROM as soon as it reads that the state was
+        // made after it, so it is put back for the backup to load
+        let boot_rom = self.cpu.memory.boot_rom.clone();
+        let result = self
+            .cpu
+            .load_state(&mut r)
+            .and_then(|_| match r.is_empty() {
+                true => Ok(()),
+                false => Err("Save state has trailing data"),
+            });
+        if result.is_err() {
+            self.cpu.memory.boot_rom = boot_rom;
+            let mut r = StateReader::new(&backup[8..]);
+            self.cpu.load_state(&mut r)?;
+        }
+        result
     }
 
     pub fn image(&self) -> &[u8] {
-        &*self.cpu.memory.gpu.data
+        match self.cpu.memory.sgb {
+            Some(ref sgb) => &*sgb.frame,
+            None => &*self.cpu.memory.gpu.data,
+        }
     }
 
     pub fn image_mut(&mut self) -> &mut [u8] {
-        &mut *self.cpu.memory.gpu.data
+        match self.cpu.memory.sgb {
+            Some(ref mut sgb) => &mut *sgb.frame,
+            None => &mut *self.cpu.memory.gpu.data,
+        }
+    }
+
+    // Reads never change emulator state, so tools can inspect memory at any time.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.memory.peek(address)
+    }
+
+    pub fn peek_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.peek(start.wrapping_add(i as u16)))
+            .collect()
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.cpu.memory.poke(address, value);
+    }
+
+    pub fn peek_bank(&self, bank: MemoryBank, offset: u16) -> Option<u8> {
+        self.cpu.memory.peek_bank(bank, offset)
+    }
+
+    pub fn poke_bank(&mut self, bank: MemoryBank, offset: u16, value: u8) -> bool {
+        self.cpu.memory.poke_bank(bank, offset, value)
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    pub fn remove_cheat(&mut self, address: u16) {
+        self.cheats.retain(|cheat| cheat.address != address);
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    // Hooks fire with the accessed address and value, the PC of the instruction
+    // performing the access and the CPU tick count at which it started.
+    pub fn on_read<F>(&mut self, range: RangeInclusive<u16>, callback: F) -> HookId
+    where
+        F: FnMut(&MemoryAccess) + Send + 'static,
+    {
+        self.cpu
+            .memory
+            .hooks
+            .add(AccessKind::Read, range, Box::new(callback))
+    }
+
+    pub fn on_write<F>(&mut self, range: RangeInclusive<u16>, callback: F) -> HookId
+    where
+        F: FnMut(&MemoryAccess) + Send + 'static,
+    {
+        self.cpu
+            .memory
+            .hooks
+            .add(AccessKind::Write, range, Box::new(callback))
+    }
+
+    pub fn on_execute<F>(&mut self, range: RangeInclusive<u16>, callback: F) -> HookId
+    where
+        F: FnMut(&MemoryAccess) + Send + 'static,
+    {
+        self.cpu
+            .memory
+            .hooks
+            .add(AccessKind::Execute, range, Box::new(callback))
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) {
+        self.cpu.memory.hooks.remove(id);
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.cpu.memory.hooks.clear();
+    }
+
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial_capture = None;
+        self.cpu.memory.serial.set_device(device);
+    }
+
+    pub fn unset_serial_device(&mut self) {
+        self.serial_capture = None;
+        self.cpu.memory.serial.unset_device();
+    }
+
+    // A link cable that dropped since the last call, for example
+    pub fn take_serial_error(&mut self) -> Option<String> {
+        self.cpu.memory.serial.take_device_error()
+    }
+
+    // Plugs a sink into the link port that keeps everything the game sends.
+    pub fn capture_serial(&mut self) {
+        if self.serial_capture.is_none() {
+            let capture = SerialCapture::default();
+            self.cpu.memory.serial.set_device(Box::new(capture.clone()));
+            self.serial_capture = Some(capture);
+        }
+    }
+
+    pub fn serial_output(&self) -> String {
+        match self.serial_capture {
+            Some(ref capture) => capture.output(),
+            None => String::new(),
+        }
+    }
+
+    // Runs frames until the captured serial output contains one of `patterns`,
+    // returning the one found, or None once `max_frames` have been run.
+    pub fn run_until_serial_contains<'p>(
+        &mut self,
+        patterns: &[&'p str],
+        max_frames: u32,
+    ) -> Option<&'p str> {
+        self.capture_serial();
+        for _ in 0..max_frames {
+            self.frame();
+            let output = self.serial_output();
+            if let Some(found) = patterns.iter().find(|p| output.contains(*p)) {
+                return Some(found);
+            }
+        }
+        None
     }
 
     pub fn keydown(&mut self, key: KeypadKey) {
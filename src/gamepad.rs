@@ -0,0 +1,335 @@
+use crate::input::KeypadKey;
+use crate::keys::{Action, Hotkey, KeyBindings};
+use std::collections::VecDeque;
+
+// What a gamepad library reports, with controllers identified by a number
+// and their inputs named after the standard layout, such as "South",
+// "DPadUp" or "LeftStickX".
+#[derive(PartialEq, Debug, Clone)]
+pub enum GamepadEvent {
+    Connected(usize),
+    Disconnected(usize),
+    Button(usize, String, bool),
+    // Position between -1.0 and 1.0, up and right being positive
+    Axis(usize, String, f32),
+}
+
+pub trait GamepadBackend {
+    fn next_event(&mut self) -> Option<GamepadEvent>;
+}
+
+// Controllers driven by hand, for tests and for frontends that get their
+// gamepad state from somewhere else.
+#[derive(Default)]
+pub struct VirtualGamepads {
+    events: VecDeque<GamepadEvent>,
+}
+
+impl VirtualGamepads {
+    pub fn push(&mut self, event: GamepadEvent) {
+        self.events.push_back(event);
+    }
+}
+
+impl GamepadBackend for VirtualGamepads {
+    fn next_event(&mut self) -> Option<GamepadEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct PadAction {
+    pub player: usize,
+    pub action: Action,
+    pub pressed: bool,
+}
+
+// Buttons keep their place on the pad, so A is on the right like on a Game
+// Boy. Stick directions are bound as the axis name followed by a sign.
+pub fn default_bindings() -> KeyBindings {
+    let mut pad = KeyBindings::new();
+    pad.set_keys(KeypadKey::A, &["East"]);
+    pad.set_keys(KeypadKey::B, &["South"]);
+    pad.set_keys(KeypadKey::Select, &["Select"]);
+    pad.set_keys(KeypadKey::Start, &["Start"]);
+    pad.set_keys(KeypadKey::Up, &["DPadUp", "LeftStickY+"]);
+    pad.set_keys(KeypadKey::Down, &["DPadDown", "LeftStickY-"]);
+    pad.set_keys(KeypadKey::Left, &["DPadLeft", "LeftStickX-"]);
+    pad.set_keys(KeypadKey::Right, &["DPadRight", "LeftStickX+"]);
+    pad.set_keys(Hotkey::FastForward, &["RightTrigger2"]);
+    pad
+}
+
+// Turns controller events into button and hotkey presses. Controllers are
+// given to players in the order they are plugged in, so linked instances can
+// each follow their own, and a controller plugged back in takes the first
+// free seat.
+pub struct Gamepads<B: GamepadBackend> {
+    backend: B,
+    pub bindings: KeyBindings,
+    // How far a stick has to move before it counts as a direction
+    pub deadzone: f32,
+    players: Vec<Option<usize>>,
+    // Inputs held down by each controller, as bound in `bindings`
+    held: Vec<(usize, String)>,
+    // How many held inputs press each player's action, as several inputs can
+    // be bound to the same one
+    pressed: Vec<(usize, Action, usize)>,
+}
+
+impl<B: GamepadBackend> Gamepads<B> {
+    pub fn new(backend: B, bindings: KeyBindings, deadzone: f32) -> Gamepads<B> {
+        Gamepads {
+            backend,
+            bindings,
+            deadzone,
+            players: Vec::new(),
+            held: Vec::new(),
+            pressed: Vec::new(),
+        }
+    }
+
+    pub fn player(&self, id: usize) -> Option<usize> {
+        self.players.iter().position(|&p| p == Some(id))
+    }
+
+    pub fn connected(&self) -> usize {
+        self.players.iter().flatten().count()
+    }
+
+    fn seat(&mut self, id: usize) -> usize {
+        if let Some(player) = self.player(id) {
+            return player;
+        }
+        match self.players.iter().position(Option::is_none) {
+            Some(player) => {
+                self.players[player] = Some(id);
+                player
+            }
+            None => {
+                self.players.push(Some(id));
+                self.players.len() - 1
+            }
+        }
+    }
+
+    pub fn poll(&mut self) -> Vec<PadAction> {
+        let mut actions = Vec::new();
+        while let Some(event) = self.backend.next_event() {
+            match event {
+                GamepadEvent::Connected(id) => {
+                    self.seat(id);
+                }
+                GamepadEvent::Disconnected(id) => {
+                    // Nothing stays pressed on a controller that is gone
+                    let held: Vec<String> = self
+                        .held
+                        .iter()
+                        .filter(|(pad, _)| *pad == id)
+                        .map(|(_, input)| input.clone())
+                        .collect();
+                    for input in held {
+                        self.input(&mut actions, id, &input, false);
+                    }
+                    if let Some(player) = self.player(id) {
+                        self.players[player] = None;
+                    }
+                }
+                GamepadEvent::Button(id, button, pressed) => {
+                    self.input(&mut actions, id, &button, pressed);
+                }
+                GamepadEvent::Axis(id, axis, value) => {
+                    let negative = format!("{}-", axis);
+                    let positive = format!("{}+", axis);
+                    self.input(&mut actions, id, &negative, value < -self.deadzone);
+                    self.input(&mut actions, id, &positive, value > self.deadzone);
+                }
+            }
+        }
+        actions
+    }
+
+    // Reports an input only when it changes, as sticks send a stream of
+    // positions while the direction stays the same
+    fn input(
+        &mut self,
+        actions: &mut Vec<PadAction>,
+        id: usize,
+        input: &str,
+        down: bool,
+    ) {
+        let held = self
+            .held
+            .iter()
+            .position(|(pad, i)| *pad == id && i == input);
+        match (held, down) {
+            (None, true) => self.held.push((id, input.to_string())),
+            (Some(i), false) => {
+                self.held.remove(i);
+            }
+            _ => return,
+        }
+        let Some(action) = self.bindings.action(input) else {
+            return;
+        };
+        let player = self.seat(id);
+        let i = match self
+            .pressed
+            .iter()
+            .position(|&(p, a, _)| p == player && a == action)
+        {
+            Some(i) => i,
+            None => {
+                self.pressed.push((player, action, 0));
+                self.pressed.len() - 1
+            }
+        };
+        // Only the first input down and the last one up are reported
+        let count = &mut self.pressed[i].2;
+        let changed = match down {
+            true => {
+                *count += 1;
+                *count == 1
+            }
+            false => {
+                *count = count.saturating_sub(1);
+                *count == 0
+            }
+        };
+        if *count == 0 {
+            self.pressed.remove(i);
+        }
+        if changed {
+            actions.push(PadAction {
+                player,
+                action,
+                pressed: down,
+            });
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Gilrs {
+    gilrs: gilrs::Gilrs,
+    connected: VecDeque<usize>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Gilrs {
+    pub fn new() -> Result<Gilrs, String> {
+        let gilrs = gilrs::Gilrs::new().map_err(|e| e.to_string())?;
+        // Controllers already plugged in do not get an event of their own
+        let connected = gilrs.gamepads().map(|(id, _)| usize::from(id)).collect();
+        Ok(Gilrs { gilrs, connected })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl GamepadBackend for Gilrs {
+    fn next_event(&mut self) -> Option<GamepadEvent> {
+        use gilrs::{Axis, Button, EventType};
+
+        if let Some(id) = self.connected.pop_front() {
+            return Some(GamepadEvent::Connected(id));
+        }
+        while let Some(event) = self.gilrs.next_event() {
+            let id = usize::from(event.id);
+            let event = match event.event {
+                EventType::Connected => GamepadEvent::Connected(id),
+                EventType::Disconnected => GamepadEvent::Disconnected(id),
+                EventType::ButtonPressed(button, _) if button != Button::Unknown => {
+                    GamepadEvent::Button(id, format!("{:?}", button), true)
+                }
+                EventType::ButtonReleased(button, _) if button != Button::Unknown => {
+                    GamepadEvent::Button(id, format!("{:?}", button), false)
+                }
+                EventType::AxisChanged(axis, value, _) if axis != Axis::Unknown => {
+                    GamepadEvent::Axis(id, format!("{:?}", axis), value)
+                }
+                _ => continue,
+            };
+            return Some(event);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        default_bindings, GamepadEvent::*, Gamepads, PadAction, VirtualGamepads,
+    };
+    use crate::input::KeypadKey;
+    use crate::keys::Action;
+
+    fn pressed(player: usize, key: KeypadKey, pressed: bool) -> PadAction {
+        PadAction {
+            player,
+            action: Action::Button(key),
+            pressed,
+        }
+    }
+
+    #[test]
+    fn sticks_need_to_leave_the_deadzone() {
+        let mut pads = Gamepads::new(VirtualGamepads::default(), default_bindings(), 0.5);
+        for value in [0.2, 0.7, 0.9, 0.1, -0.8] {
+            pads.backend
+                .push(Axis(3, String::from("LeftStickX"), value));
+        }
+        assert_eq!(
+            pads.poll(),
+            vec![
+                pressed(0, KeypadKey::Right, true),
+                pressed(0, KeypadKey::Right, false),
+                pressed(0, KeypadKey::Left, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn controllers_take_seats_in_order() {
+        let mut pads = Gamepads::new(VirtualGamepads::default(), default_bindings(), 0.5);
+        pads.backend.push(Connected(7));
+        pads.backend.push(Connected(2));
+        pads.backend.push(Button(2, String::from("East"), true));
+        pads.backend.push(Button(7, String::from("DPadUp"), true));
+        assert_eq!(
+            pads.poll(),
+            vec![
+                pressed(1, KeypadKey::A, true),
+                pressed(0, KeypadKey::Up, true)
+            ]
+        );
+
+        // Unplugging lets go of everything, the next controller takes the seat
+        pads.backend.push(Disconnected(7));
+        pads.backend.push(Connected(9));
+        pads.backend.push(Button(9, String::from("Start"), true));
+        assert_eq!(
+            pads.poll(),
+            vec![
+                pressed(0, KeypadKey::Up, false),
+                pressed(0, KeypadKey::Start, true)
+            ]
+        );
+        assert_eq!(pads.connected(), 2);
+        assert_eq!(pads.player(2), Some(1));
+    }
+
+    #[test]
+    fn inputs_bound_to_one_action_release_together() {
+        let mut pads = Gamepads::new(VirtualGamepads::default(), default_bindings(), 0.5);
+        pads.backend.push(Axis(0, String::from("LeftStickY"), 0.9));
+        pads.backend.push(Button(0, String::from("DPadUp"), true));
+        pads.backend.push(Button(0, String::from("DPadUp"), false));
+        assert_eq!(pads.poll(), vec![pressed(0, KeypadKey::Up, true)]);
+
+        pads.backend.push(Button(0, String::from("DPadUp"), true));
+        pads.backend.push(Axis(0, String::from("LeftStickY"), 0.0));
+        assert_eq!(pads.poll(), vec![]);
+        pads.backend.push(Button(0, String::from("DPadUp"), false));
+        assert_eq!(pads.poll(), vec![pressed(0, KeypadKey::Up, false)]);
+    }
+}
@@ -0,0 +1,231 @@
+// Colors given to DMG games by the CGB boot ROM. It looks the game up by the
+// checksum of its title, or takes the palette picked with a button combination
+// held while the logo shows. Tables are the boot ROM's own.
+
+// A palette of four 15-bit colors for each of BG, OBJ0 and OBJ1
+pub type CompatPalettes = [[[u8; 3]; 4]; 3];
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum Colorization {
+    // From the title of the game, as without any button held
+    #[default]
+    Auto,
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl Colorization {
+    pub const ALL: [Colorization; 13] = [
+        Colorization::Auto,
+        Colorization::Up,
+        Colorization::UpA,
+        Colorization::UpB,
+        Colorization::Left,
+        Colorization::LeftA,
+        Colorization::LeftB,
+        Colorization::Down,
+        Colorization::DownA,
+        Colorization::DownB,
+        Colorization::Right,
+        Colorization::RightA,
+        Colorization::RightB,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Colorization::Auto => "auto",
+            Colorization::Up => "up",
+            Colorization::UpA => "up+a",
+            Colorization::UpB => "up+b",
+            Colorization::Left => "left",
+            Colorization::LeftA => "left+a",
+            Colorization::LeftB => "left+b",
+            Colorization::Down => "down",
+            Colorization::DownA => "down+a",
+            Colorization::DownB => "down+b",
+            Colorization::Right => "right",
+            Colorization::RightA => "right+a",
+            Colorization::RightB => "right+b",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Colorization> {
+        Colorization::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(name))
+    }
+
+    // Reads the cartridge header through `rom`
+    pub fn palettes(self, rom: impl Fn(u16) -> u8) -> CompatPalettes {
+        let combination = match self {
+            Colorization::Auto => title_combination(rom),
+            Colorization::Up => 5,
+            Colorization::UpA => 43,
+            Colorization::UpB => 28,
+            Colorization::Left => 48,
+            Colorization::LeftA => 40,
+            Colorization::LeftB => 7,
+            Colorization::Down => 8,
+            Colorization::DownA => 3,
+            Colorization::DownB => 49,
+            Colorization::Right => 1,
+            Colorization::RightA => 0,
+            Colorization::RightB => 6,
+        };
+        let [obj0, obj1, bg] = COMBINATIONS[combination];
+        [bg, obj0, obj1].map(|offset| {
+            let mut palette = [[0; 3]; 4];
+            for (color, &rgb) in palette.iter_mut().zip(&COLORS[offset..offset + 4]) {
+                *color =
+                    [rgb & 0x1F, (rgb >> 5) & 0x1F, (rgb >> 10) & 0x1F].map(|c| c as u8);
+            }
+            palette
+        })
+    }
+}
+
+// Only games published by Nintendo are in the table, everything else gets the
+// first combination
+fn title_combination(rom: impl Fn(u16) -> u8) -> usize {
+    let nintendo = match rom(0x14B) {
+        0x33 => rom(0x144) == b'0' && rom(0x145) == b'1',
+        licensee => licensee == 0x01,
+    };
+    if !nintendo {
+        return 0;
+    }
+
+    let checksum = (0x134..=0x143).fold(0u8, |sum, a| sum.wrapping_add(rom(a)));
+    let fourth_letter = rom(0x137);
+    let found = CHECKSUMS.iter().enumerate().position(|(i, &c)| {
+        c == checksum
+            && (i < UNIQUE_CHECKSUMS
+                || FOURTH_LETTERS[i - UNIQUE_CHECKSUMS] == fourth_letter)
+    });
+    found.map_or(0, |i| COMBINATION_PER_CHECKSUM[i] as usize)
+}
+
+// Checksums past this index are shared by several titles and told apart by
+// their fourth letter
+const UNIQUE_CHECKSUMS: usize = 65;
+
+#[rustfmt::skip]
+const CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9,
+    0x3E, 0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34,
+    0x6F, 0x15, 0xFF, 0x97, 0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E,
+    0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE, 0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01,
+    0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F, 0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3,
+];
+
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+#[rustfmt::skip]
+const COMBINATION_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13,
+    14, 5, 29, 5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41,
+    34, 34, 5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 34, 23, 18, 29,
+    28,
+];
+
+// Offsets into `COLORS` of the OBJ0, OBJ1 and BG palettes. A few of them start
+// in the middle of a palette of `COLORS`.
+#[rustfmt::skip]
+const COMBINATIONS: [[usize; 3]; 51] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36], [0, 0, 0],
+    [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104], [64, 32, 32],
+    [16, 112, 112], [16, 8, 8], [12, 16, 16], [16, 116, 116], [112, 16, 112], [8, 68, 8],
+    [64, 64, 32], [16, 16, 28], [16, 16, 72], [16, 16, 80], [76, 76, 36], [15, 15, 44],
+    [68, 68, 8], [16, 16, 8], [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4],
+    [72, 88, 72], [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56],
+    [111, 16, 60], [76, 88, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8], [16, 0, 8],
+    [16, 112, 12], [112, 12, 0], [12, 112, 16], [84, 112, 16], [12, 112, 0], [100, 12, 112],
+    [0, 112, 32], [16, 12, 112], [112, 12, 24], [16, 112, 116],
+];
+
+// Thirty palettes of four 15-bit colors, from lightest to darkest
+#[rustfmt::skip]
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+#[cfg(test)]
+mod test {
+    use super::Colorization;
+
+    fn header(title: &[u8], licensee: u8) -> impl Fn(u16) -> u8 {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        move |a| rom[a as usize]
+    }
+
+    #[test]
+    fn picks_palettes_by_title() {
+        // White, light green, blue and black background, red sprites
+        let default = Colorization::Auto.palettes(header(b"HOMEBREW", 0x00));
+        assert_eq!(default[0][1], [15, 31, 6]);
+        assert_eq!(default[1][1], [31, 16, 16]);
+        assert_eq!(
+            Colorization::Auto.palettes(header(b"POKEMON RED", 0x33)),
+            default
+        );
+
+        // Red background, with the checksum alone
+        let red = Colorization::Auto.palettes(header(b"POKEMON RED", 0x01));
+        assert_eq!(red[0][1], [31, 16, 16]);
+        // Blue, with the fourth letter telling it from other titles
+        let blue = Colorization::Auto.palettes(header(b"POKEMON BLUE", 0x01));
+        assert_eq!(blue[0][2], [0, 0, 31]);
+
+        assert_eq!(Colorization::RightA.palettes(header(b"", 0)), default);
+        let gray = Colorization::from_name("Left+B")
+            .unwrap()
+            .palettes(header(b"", 0));
+        assert_eq!(gray[2][1], [20, 20, 20]);
+    }
+}
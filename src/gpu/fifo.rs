@@ -0,0 +1,500 @@
+// Dot by dot renderer of mode 3. A fetcher pushes background and window tiles
+// into a FIFO that shifts one pixel out per dot, while sprites are fetched into
+// a second FIFO as the pixel they start on comes up, stalling the background.
+// Registers are read as the pixels go through, so writes in the middle of a
+// line take effect there, and mode 3 lasts as long as the fetches take.
+use super::{Gpu, Layer, HEIGHT, WIDTH};
+use crate::mmu::StrResult;
+use crate::mode::GbMode;
+use crate::state::{StateReader, StateWriter};
+use std::collections::VecDeque;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum Ppu {
+    // Draws whole lines as mode 0 starts, with mode 3 always 172 dots
+    #[default]
+    Scanline,
+    // Slower, for the raster effects of demos and racing games
+    Fifo,
+}
+
+impl Ppu {
+    pub const ALL: [Ppu; 2] = [Ppu::Scanline, Ppu::Fifo];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ppu::Scanline => "scanline",
+            Ppu::Fifo => "fifo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Ppu> {
+        Ppu::ALL
+            .into_iter()
+            .find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// Dots the fetcher takes for the tile number and its two bytes, it then waits
+// for the FIFO to be empty to push the eight pixels
+const FETCH: u8 = 6;
+// Dots a sprite fetch stalls the FIFO for, once the background fetch is done
+const SPRITE_FETCH: u8 = 6;
+
+#[derive(Debug, Copy, Clone, Default)]
+struct BgPixel {
+    color: u8,
+    // CGB attributes of the tile
+    palette: u8,
+    priority: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ObjPixel {
+    color: u8,
+    // OBP0 or OBP1 on the DMG, CGB palettes 0-7 otherwise
+    palette: u8,
+    below_bg: bool,
+    index: u8,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Fifo {
+    bg: VecDeque<BgPixel>,
+    // Sprite pixels of the next 8 dots
+    obj: [Option<ObjPixel>; 8],
+    // Fetcher: dots into the current fetch, the tile it is on, and the tile
+    // number and bytes read so far
+    step: u8,
+    tile: u8,
+    tilenr: u8,
+    attributes: u8,
+    low: u8,
+    high: u8,
+    window: bool,
+    // Pixels shifted out so far, and how many more to throw away for SCX
+    x: usize,
+    discard: u8,
+    // Sprites on this line as (x, OAM index), by x, and the one being fetched
+    sprites: Vec<(u8, u8)>,
+    fetching: Option<(u8, u8)>,
+    stall: u8,
+}
+
+impl Fifo {
+    pub fn done(&self) -> bool {
+        self.x >= WIDTH
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bg.len() as u8);
+        for pixel in &self.bg {
+            w.bytes(&[pixel.color, pixel.palette, pixel.priority as u8]);
+        }
+        for pixel in &self.obj {
+            match pixel {
+                Some(p) => w.bytes(&[1, p.color, p.palette, p.below_bg as u8, p.index]),
+                None => w.bytes(&[0; 5]),
+            }
+        }
+        w.bytes(&[self.step, self.tile, self.tilenr, self.attributes]);
+        w.bytes(&[self.low, self.high, self.window as u8]);
+        w.u8(self.x as u8);
+        w.u8(self.discard);
+        w.u8(self.sprites.len() as u8);
+        for &(x, index) in &self.sprites {
+            w.bytes(&[x, index]);
+        }
+        match self.fetching {
+            Some((x, index)) => w.bytes(&[1, x, index]),
+            None => w.bytes(&[0; 3]),
+        }
+        w.u8(self.stall);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let invalid = "Save state has an invalid pixel FIFO";
+        let len = r.u8()?;
+        if len > 16 {
+            return Err(invalid);
+        }
+        self.bg.clear();
+        for _ in 0..len {
+            let mut pixel = [0; 3];
+            r.bytes(&mut pixel)?;
+            self.bg.push_back(BgPixel {
+                color: pixel[0],
+                palette: pixel[1],
+                priority: pixel[2] != 0,
+            });
+        }
+        for slot in self.obj.iter_mut() {
+            let mut pixel = [0; 5];
+            r.bytes(&mut pixel)?;
+            *slot = (pixel[0] != 0).then_some(ObjPixel {
+                color: pixel[1],
+                palette: pixel[2],
+                below_bg: pixel[3] != 0,
+                index: pixel[4],
+            });
+        }
+        let mut fetcher = [0; 7];
+        r.bytes(&mut fetcher)?;
+        let [step, tile, tilenr, attributes, low, high, window] = fetcher;
+        (self.step, self.tile, self.tilenr, self.attributes) =
+            (step, tile, tilenr, attributes);
+        (self.low, self.high, self.window) = (low, high, window != 0);
+        self.x = r.u8()? as usize;
+        self.discard = r.u8()?;
+        let len = r.u8()?;
+        if len > 10 {
+            return Err(invalid);
+        }
+        self.sprites.clear();
+        for _ in 0..len {
+            self.sprites.push((r.u8()?, r.u8()?));
+        }
+        let mut fetching = [0; 3];
+        r.bytes(&mut fetching)?;
+        self.fetching = (fetching[0] != 0).then_some((fetching[1], fetching[2]));
+        self.stall = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Gpu {
+    // Advances the LCD by one dot, see `do_cycle`
+    pub(super) fn dot(&mut self) {
+        self.modeclock += 1;
+        if self.modeclock >= 456 {
+            self.modeclock -= 456;
+            self.line = (self.line + 1) % 154;
+            self.check_interrupt_lyc();
+
+            if self.line >= HEIGHT as u8 && self.mode != 1 {
+                self.change_mode(1);
+            }
+        }
+        if self.line >= HEIGHT as u8 {
+            return;
+        }
+
+        if self.modeclock <= 80 {
+            if self.mode != 2 {
+                self.change_mode(2);
+            }
+        } else if self.mode == 2 {
+            self.change_mode(3);
+        } else if self.mode == 3 {
+            self.fifo_dot();
+            if self.fifo.done() {
+                self.change_mode(0);
+            }
+        }
+    }
+
+    // Scans OAM and starts the fetcher at the beginning of mode 3
+    pub(super) fn start_fifo(&mut self) {
+        let line = self.line as i32;
+        let mut sprites = Vec::with_capacity(10);
+        for index in 0..40 {
+            let y = self.voam[index * 4] as i32 - 16;
+            if line >= y && line < y + self.sprite_size as i32 {
+                sprites.push((self.voam[index * 4 + 1], index as u8));
+                if sprites.len() == 10 {
+                    break;
+                }
+            }
+        }
+        // The sort is stable, sprites on the same x stay in OAM order
+        sprites.sort_by_key(|&(x, _)| x);
+
+        self.fifo = Fifo {
+            sprites,
+            discard: self.scx & 0x07,
+            // The first fetch of a line is thrown away
+            step: 0u8.wrapping_sub(FETCH),
+            ..Fifo::default()
+        };
+    }
+
+    fn fifo_dot(&mut self) {
+        if self.fifo.fetching.is_none() && self.sprite_on && self.fifo.discard == 0 {
+            let x = self.fifo.x as u8 + 8;
+            if let Some(&sprite) = self.fifo.sprites.first().filter(|s| s.0 <= x) {
+                self.fifo.sprites.remove(0);
+                self.fifo.fetching = Some(sprite);
+                self.fifo.stall = 0;
+            }
+        }
+
+        // Sprites wait for the background fetch to be pushed, and hold the
+        // pixels back until they are in
+        if let Some((x, index)) = self.fifo.fetching {
+            if self.fifo.step < FETCH || self.fifo.bg.is_empty() {
+                self.fetch_dot();
+                return;
+            }
+            self.fifo.stall += 1;
+            if self.fifo.stall == SPRITE_FETCH {
+                self.fetch_sprite(x, index);
+                self.fifo.fetching = None;
+            }
+            return;
+        }
+
+        self.fetch_dot();
+        self.start_window();
+        if let Some(bg) = self.fifo.bg.pop_front() {
+            if self.fifo.discard > 0 {
+                self.fifo.discard -= 1;
+                return;
+            }
+            let obj = self.fifo.obj[0].take();
+            self.fifo.obj.rotate_left(1);
+            self.mix(bg, obj);
+            self.fifo.x += 1;
+        }
+    }
+
+    // The window starts over the fetcher when WX is reached on lines below WY
+    fn start_window(&mut self) {
+        if self.fifo.window
+            || !self.win_on
+            || !self.wy_trigger
+            || self.winx > 166
+            || (self.fifo.x as i32) < self.winx as i32 - 7
+        {
+            return;
+        }
+        self.wy_pos += 1;
+        self.fifo.window = true;
+        self.fifo.bg.clear();
+        self.fifo.tile = 0;
+        self.fifo.step = 0;
+        // Part of the window is hidden when WX is under 7
+        self.fifo.discard = 7u8.saturating_sub(self.winx);
+    }
+
+    fn fetch_dot(&mut self) {
+        let step = self.fifo.step;
+        if step < FETCH {
+            self.fifo.step += 1;
+        }
+        match step {
+            1 => self.fetch_tile(),
+            3 => self.fifo.low = self.fetch_data(0),
+            5 => self.fifo.high = self.fetch_data(1),
+            FETCH if self.fifo.bg.is_empty() => {
+                let (low, high) = (self.fifo.low, self.fifo.high);
+                let attributes = self.fifo.attributes;
+                let xflip = attributes & (1 << 5) != 0;
+                for i in 0..8 {
+                    let bit = if xflip { i } else { 7 - i };
+                    self.fifo.bg.push_back(BgPixel {
+                        color: (low >> bit) & 1 | ((high >> bit) & 1) << 1,
+                        palette: attributes & 0x07,
+                        priority: attributes & 0x80 != 0,
+                    });
+                }
+                self.fifo.tile = self.fifo.tile.wrapping_add(1);
+                self.fifo.step = 0;
+            }
+            // Counting up from the discarded first fetch
+            _ if step > FETCH => self.fifo.step = step.wrapping_add(1),
+            _ => {}
+        }
+    }
+
+    // Address of the tile in the background or window map, and the row in it
+    fn fetch_position(&self) -> (u16, u16) {
+        if self.fifo.window {
+            let y = self.wy_pos as u16;
+            let entry = ((y >> 3) & 31) * 32 + (self.fifo.tile as u16 & 31);
+            (self.win_tilemap + entry, y & 0x07)
+        } else {
+            let y = self.scy.wrapping_add(self.line) as u16;
+            let x = ((self.scx >> 3) as u16 + self.fifo.tile as u16) & 31;
+            (self.bg_tilemap + ((y >> 3) & 31) * 32 + x, y & 0x07)
+        }
+    }
+
+    fn fetch_tile(&mut self) {
+        let (entry, _) = self.fetch_position();
+        self.fifo.tilenr = self.rbvram0(entry);
+        self.fifo.attributes = match self.gbmode {
+            GbMode::Color => self.rbvram1(entry),
+            _ => 0,
+        };
+    }
+
+    fn fetch_data(&self, byte: u16) -> u8 {
+        let (_, row) = self.fetch_position();
+        let attributes = self.fifo.attributes;
+        let row = if attributes & (1 << 6) != 0 {
+            7 - row
+        } else {
+            row
+        };
+        let tilenr = self.fifo.tilenr;
+        let address = self.tilebase
+            + (if self.tilebase == 0x8000 {
+                tilenr as u16
+            } else {
+                (tilenr as i8 as i16 + 128) as u16
+            }) * 16
+            + row * 2
+            + byte;
+        match attributes & (1 << 3) != 0 {
+            false => self.rbvram0(address),
+            true => self.rbvram1(address),
+        }
+    }
+
+    fn fetch_sprite(&mut self, x: u8, index: u8) {
+        let oam = &self.voam[index as usize * 4..][..4];
+        let y = self.line as i32 - (oam[0] as i32 - 16);
+        let flags = oam[3];
+        let tilenum = match self.sprite_size {
+            16 => oam[2] & 0xFE,
+            _ => oam[2],
+        } as u16;
+        let row = match flags & (1 << 6) != 0 {
+            true => self.sprite_size as i32 - 1 - y,
+            false => y,
+        } as u16;
+        let address = 0x8000 + tilenum * 16 + row * 2;
+        let (low, high) = if flags & (1 << 3) != 0 && self.gbmode == GbMode::Color {
+            (self.rbvram1(address), self.rbvram1(address + 1))
+        } else {
+            (self.rbvram0(address), self.rbvram0(address + 1))
+        };
+
+        let palette = match self.gbmode {
+            GbMode::Color => flags & 0x07,
+            _ => (flags >> 4) & 0x01,
+        };
+        for i in 0..8 {
+            // Pixels already left of the screen are dropped
+            let Some(slot) = (x as usize + i).checked_sub(self.fifo.x + 8) else {
+                continue;
+            };
+            let bit = if flags & (1 << 5) != 0 { i } else { 7 - i };
+            let pixel = ObjPixel {
+                color: (low >> bit) & 1 | ((high >> bit) & 1) << 1,
+                palette,
+                below_bg: flags & 0x80 != 0,
+                index,
+            };
+            // On the DMG the sprite fetched first wins, as they go by x. The
+            // CGB goes by OAM index alone.
+            let replace = match self.fifo.obj[slot] {
+                None => true,
+                Some(old) if old.color == 0 => true,
+                Some(old) => self.gbmode == GbMode::Color && index < old.index,
+            };
+            if replace && pixel.color != 0 {
+                self.fifo.obj[slot] = Some(pixel);
+            }
+        }
+    }
+
+    fn mix(&mut self, bg: BgPixel, obj: Option<ObjPixel>) {
+        let x = self.fifo.x;
+        if self.gbmode == GbMode::Color {
+            // Without LCDC bit 0 sprites are always on top
+            let visible = obj.filter(|obj| {
+                !self.lcdc0 || bg.color == 0 || !(bg.priority || obj.below_bg)
+            });
+            let [r, g, b] = match visible {
+                Some(obj) => self.csprit[obj.palette as usize][obj.color as usize],
+                None => self.cbgpal[bg.palette as usize][bg.color as usize],
+            };
+            self.setrgb(x, r, g, b);
+            return;
+        }
+
+        // The background and window are blank without LCDC bit 0
+        let color = if self.lcdc0 { bg.color } else { 0 };
+        match obj.filter(|obj| !obj.below_bg || color == 0) {
+            Some(obj) if obj.palette == 1 => {
+                self.setshade(x, Layer::Obj1, self.pal1[obj.color as usize])
+            }
+            Some(obj) => self.setshade(x, Layer::Obj0, self.pal0[obj.color as usize]),
+            None if self.lcdc0 => self.setshade(x, Layer::Bg, self.palb[color as usize]),
+            None => self.setshade(x, Layer::Bg, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Ppu;
+    use crate::gpu::{Gpu, WIDTH};
+
+    // A background of color 3 everywhere
+    fn gpu(ppu: Ppu) -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.ppu = ppu;
+        for a in 0x8000..0x8010 {
+            gpu.wb(a, 0xFF);
+        }
+        gpu.wb(0xFF47, 0xE4);
+        gpu
+    }
+
+    // Dots the next mode 3 lasts
+    fn mode3(gpu: &mut Gpu) -> u32 {
+        while gpu.mode != 3 {
+            gpu.do_cycle(1);
+        }
+        let mut dots = 0;
+        while gpu.mode == 3 {
+            gpu.do_cycle(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn shows_writes_in_the_middle_of_a_line() {
+        for ppu in Ppu::ALL {
+            let mut gpu = gpu(ppu);
+            gpu.wb(0xFF40, 0x91);
+            while gpu.mode != 3 {
+                gpu.do_cycle(1);
+            }
+            gpu.do_cycle(100);
+            gpu.wb(0xFF47, 0x00);
+            mode3(&mut gpu);
+
+            let line = &gpu.shades[..WIDTH];
+            match ppu {
+                Ppu::Scanline => assert!(line.iter().all(|&s| s == 0)),
+                Ppu::Fifo => {
+                    let split = line.iter().position(|&s| s == 0).unwrap();
+                    assert!((80..100).contains(&split), "{}", split);
+                    assert!(line[split..].iter().all(|&s| s == 0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sprites_and_scrolling_lengthen_mode_3() {
+        let mut gpu = gpu(Ppu::Fifo);
+        // Ten sprites on line 1
+        for i in 0..10 {
+            gpu.wb(0xFE00 + i * 4, 17);
+            gpu.wb(0xFE01 + i * 4, 8 + i as u8 * 16);
+        }
+        gpu.wb(0xFF40, 0x93);
+        assert_eq!(mode3(&mut gpu), 172);
+        assert!(mode3(&mut gpu) >= 172 + 60);
+
+        // Without sprites, the pixels SCX throws away cost a dot each
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF43, 5);
+        assert_eq!(mode3(&mut gpu), 177);
+    }
+}
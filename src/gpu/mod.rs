@@ -1,11 +1,30 @@
+use crate::mmu::StrResult;
 use crate::mode::GbMode;
+use crate::state::{StateReader, StateWriter};
 use std::cmp::Ordering;
 
+mod compat;
+mod fifo;
+mod palette;
+
+pub use self::compat::{Colorization, CompatPalettes};
+pub use self::fifo::Ppu;
+pub use self::palette::{
+    ColorCorrection, DmgPalettes, Palette, BUILTIN, DMG, GRAYSCALE, GREEN, LIGHT, POCKET,
+};
+
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 pub const HEIGHT: usize = 144;
 pub const WIDTH: usize = 160;
 
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Layer {
+    Bg,
+    Obj0,
+    Obj1,
+}
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 enum PrioType {
     Color0,
@@ -40,9 +59,17 @@ pub struct Gpu {
     palbr: u8,
     pal0r: u8,
     pal1r: u8,
+    // Shades (0-3) picked by BGP, OBP0 and OBP1 for each color number
     palb: [u8; 4],
     pal0: [u8; 4],
     pal1: [u8; 4],
+    pub palettes: DmgPalettes,
+    // DMG games on a CGB draw through CGB palettes 0 and 1 colorized by the
+    // boot ROM, unless this is None and `palettes` are used instead
+    pub colorization: Option<Colorization>,
+    correction: ColorCorrection,
+    // RGB of every CGB color with `correction` applied, see `ColorCorrection::table`
+    colors: Vec<[u8; 3]>,
     pub vram: [u8; VRAM_SIZE],
     pub voam: [u8; VOAM_SIZE],
     cbgpal_inc: bool,
@@ -53,11 +80,16 @@ pub struct Gpu {
     csprit: [[[u8; 3]; 4]; 8],
     vrambank: usize,
     pub data: Box<[u8; WIDTH * HEIGHT * 4]>,
+    // Shade (0-3) of each pixel drawn by DMG games, the SGB colors them itself
+    shades: Box<[u8; WIDTH * HEIGHT]>,
     bgprio: [PrioType; WIDTH],
     pub updated: bool,
     pub interrupt: u8,
     pub gbmode: GbMode,
     hblanking: bool,
+    // Renderer in use, and the state of the pixel FIFO one
+    pub ppu: Ppu,
+    fifo: fifo::Fifo,
 }
 
 impl Default for Gpu {
@@ -97,9 +129,14 @@ impl Gpu {
             palb: [0; 4],
             pal0: [0; 4],
             pal1: [0; 4],
+            palettes: DmgPalettes::default(),
+            colorization: None,
+            correction: ColorCorrection::default(),
+            colors: ColorCorrection::default().table(),
             vram: [0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: Box::new([0; HEIGHT * WIDTH * 4]),
+            shades: Box::new([0; HEIGHT * WIDTH]),
             bgprio: [PrioType::Normal; WIDTH],
             updated: false,
             interrupt: 0,
@@ -112,6 +149,8 @@ impl Gpu {
             csprit: [[[0u8; 3]; 4]; 8],
             vrambank: 0,
             hblanking: false,
+            ppu: Ppu::default(),
+            fifo: fifo::Fifo::default(),
         }
     }
 
@@ -119,12 +158,133 @@ impl Gpu {
         Gpu::new()
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.mode);
+        w.u32(self.modeclock);
+        w.u8(self.line);
+        w.u8(self.lyc);
+        w.bool(self.lcd_on);
+        w.u16(self.win_tilemap);
+        w.bool(self.win_on);
+        w.u16(self.tilebase);
+        w.u16(self.bg_tilemap);
+        w.u32(self.sprite_size);
+        w.bool(self.sprite_on);
+        w.bool(self.lcdc0);
+        w.bool(self.lyc_inte);
+        w.bool(self.m0_inte);
+        w.bool(self.m1_inte);
+        w.bool(self.m2_inte);
+        w.u8(self.scy);
+        w.u8(self.scx);
+        w.u8(self.winy);
+        w.u8(self.winx);
+        w.bool(self.wy_trigger);
+        w.u32(self.wy_pos as u32);
+        w.u8(self.palbr);
+        w.u8(self.pal0r);
+        w.u8(self.pal1r);
+        w.bytes(&self.palb);
+        w.bytes(&self.pal0);
+        w.bytes(&self.pal1);
+        w.bytes(&self.vram);
+        w.bytes(&self.voam);
+        w.bool(self.cbgpal_inc);
+        w.u8(self.cbgpal_ind);
+        w.bool(self.csprit_inc);
+        w.u8(self.csprit_ind);
+        for pal in self.cbgpal.iter().chain(self.csprit.iter()) {
+            for color in pal {
+                w.bytes(color);
+            }
+        }
+        w.u8(self.vrambank as u8);
+        w.bytes(&self.data[..]);
+        w.u8(self.interrupt);
+        w.mode(self.gbmode);
+        w.bool(self.hblanking);
+        // 0 when DMG games are not colorized, otherwise one past the index
+        w.u8(match self.colorization {
+            Some(c) => Colorization::ALL.iter().position(|&a| a == c).unwrap() as u8 + 1,
+            None => 0,
+        });
+        self.fifo.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.mode = r.u8()?;
+        self.modeclock = r.u32()?;
+        self.line = r.u8()?;
+        self.lyc = r.u8()?;
+        self.lcd_on = r.bool()?;
+        self.win_tilemap = r.u16()?;
+        self.win_on = r.bool()?;
+        self.tilebase = r.u16()?;
+        self.bg_tilemap = r.u16()?;
+        self.sprite_size = r.u32()?;
+        self.sprite_on = r.bool()?;
+        self.lcdc0 = r.bool()?;
+        self.lyc_inte = r.bool()?;
+        self.m0_inte = r.bool()?;
+        self.m1_inte = r.bool()?;
+        self.m2_inte = r.bool()?;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.winy = r.u8()?;
+        self.winx = r.u8()?;
+        self.wy_trigger = r.bool()?;
+        self.wy_pos = r.u32()? as i32;
+        self.palbr = r.u8()?;
+        self.pal0r = r.u8()?;
+        self.pal1r = r.u8()?;
+        r.bytes(&mut self.palb)?;
+        r.bytes(&mut self.pal0)?;
+        r.bytes(&mut self.pal1)?;
+        r.bytes(&mut self.vram)?;
+        r.bytes(&mut self.voam)?;
+        self.cbgpal_inc = r.bool()?;
+        self.cbgpal_ind = r.u8()?;
+        self.csprit_inc = r.bool()?;
+        self.csprit_ind = r.u8()?;
+        for pal in self.cbgpal.iter_mut().chain(self.csprit.iter_mut()) {
+            for color in pal {
+                r.bytes(color)?;
+            }
+        }
+        self.vrambank = r.u8()? as usize;
+        if self.vrambank > 1 {
+            return Err("Save state is invalid");
+        }
+        r.bytes(&mut self.data[..])?;
+        self.interrupt = r.u8()?;
+        self.gbmode = r.mode()?;
+        self.hblanking = r.bool()?;
+        self.colorization = match r.u8()? {
+            0 => None,
+            n => Some(
+                *Colorization::ALL
+                    .get(n as usize - 1)
+                    .ok_or("Save state is invalid")?,
+            ),
+        };
+        self.fifo.load_state(r)?;
+        self.updated = false;
+        Ok(())
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
         if !self.lcd_on {
             return;
         }
         self.hblanking = false;
 
+        if self.ppu == Ppu::Fifo {
+            for _ in 0..ticks {
+                self.dot();
+            }
+            return;
+        }
+
         let mut ticksleft = ticks;
 
         while ticksleft > 0 {
@@ -176,7 +336,9 @@ impl Gpu {
 
         if match self.mode {
             0 => {
-                self.renderscan();
+                if self.ppu == Ppu::Scanline {
+                    self.renderscan();
+                }
                 self.hblanking = true;
                 self.m0_inte
             }
@@ -193,6 +355,9 @@ impl Gpu {
                     self.wy_trigger = true;
                     self.wy_pos = -1;
                 }
+                if self.ppu == Ppu::Fifo {
+                    self.start_fifo();
+                }
                 false
             }
             _ => false,
@@ -381,13 +546,71 @@ impl Gpu {
         }
     }
 
+    // `wb` for tooling, without what writes from the game set off: the LCD
+    // switching on or off, the VRAM bank changing and palette indexes counting
+    // up
+    pub fn poke(&mut self, a: u16, v: u8) {
+        match a {
+            0xFF40 => self.wb(a, (v & 0x7F) | (self.lcd_on as u8) << 7),
+            0xFF4F => {}
+            0xFF69 | 0xFF6B => {
+                let indexes = (self.cbgpal_ind, self.csprit_ind);
+                self.wb(a, v);
+                (self.cbgpal_ind, self.csprit_ind) = indexes;
+            }
+            _ => self.wb(a, v),
+        }
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        if correction != self.correction {
+            self.correction = correction;
+            self.colors = correction.table();
+        }
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.correction
+    }
+
+    // Sets the palettes the CGB boot ROM leaves for DMG games
+    pub fn set_compat_palettes(&mut self, [bg, obj0, obj1]: CompatPalettes) {
+        self.cbgpal[0] = bg;
+        self.csprit[0] = obj0;
+        self.csprit[1] = obj1;
+    }
+
     fn clear_screen(&mut self) {
-        for v in self.data.iter_mut() {
-            *v = 255;
+        let [r, g, b] = self.dmg_color(Layer::Bg, 0);
+        for pixel in self.data.chunks_mut(4) {
+            pixel[..3].copy_from_slice(&[r, g, b]);
         }
+        self.shades.fill(0);
         self.updated = true;
     }
 
+    pub fn shades(&self) -> &[u8] {
+        &self.shades[..]
+    }
+
+    // The 4KB the SGB copies for its VRAM transfers: the tiles shown by the
+    // first 256 entries of the 20x13 top left corner of the background map
+    pub fn screen_tiles(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(0x1000);
+        for i in 0..256 {
+            let entry = self.bg_tilemap + (i / 20) * 32 + i % 20;
+            let tilenr = self.rbvram0(entry);
+            let tileaddress = self.tilebase
+                + (if self.tilebase == 0x8000 {
+                    tilenr as u16
+                } else {
+                    (tilenr as i8 as i16 + 128) as u16
+                }) * 16;
+            data.extend((tileaddress..tileaddress + 16).map(|a| self.rbvram0(a)));
+        }
+        data
+    }
+
     fn update_pal(&mut self) {
         for i in 0..4 {
             self.palb[i] = Gpu::get_monochrome_pal_val(self.palbr, i);
@@ -397,42 +620,52 @@ impl Gpu {
     }
 
     fn get_monochrome_pal_val(value: u8, index: usize) -> u8 {
-        match (value >> (2 * index)) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0,
-        }
+        (value >> (2 * index)) & 0x03
     }
 
     fn renderscan(&mut self) {
         for x in 0..WIDTH {
-            self.setcolor(x, 255);
+            self.setshade(x, Layer::Bg, 0);
             self.bgprio[x] = PrioType::Normal;
         }
         self.draw_bg();
         self.draw_sprites();
     }
 
-    fn setcolor(&mut self, x: usize, color: u8) {
-        self.data[self.line as usize * WIDTH * 4 + x * 4] = color;
-        self.data[self.line as usize * WIDTH * 4 + x * 4 + 1] = color;
-        self.data[self.line as usize * WIDTH * 4 + x * 4 + 2] = color;
+    fn setcolor(&mut self, x: usize, color: [u8; 3]) {
+        let baseidx = self.line as usize * WIDTH * 4 + x * 4;
+        self.data[baseidx..baseidx + 3].copy_from_slice(&color);
+    }
+
+    fn setshade(&mut self, x: usize, layer: Layer, shade: u8) {
+        self.setcolor(x, self.dmg_color(layer, shade));
+        self.shades[self.line as usize * WIDTH + x] = shade;
     }
 
+    // r, g and b are between 0 and 1F
     fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        // Gameboy Color RGB correction
-        // Taken from the Gambatte emulator
-        // assume r, g and b are between 0 and 1F
-        let baseidx = self.line as usize * WIDTH * 4 + x * 4;
+        self.setcolor(x, self.cgb_color([r, g, b]));
+    }
 
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
+    fn cgb_color(&self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        self.colors[r as usize | (g as usize) << 5 | (b as usize) << 10]
+    }
 
-        self.data[baseidx] = ((r * 13 + g * 2 + b) >> 1) as u8;
-        self.data[baseidx + 1] = ((g * 3 + b) << 1) as u8;
-        self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+    // Color of a shade picked by BGP, OBP0 or OBP1
+    fn dmg_color(&self, layer: Layer, shade: u8) -> [u8; 3] {
+        let shade = shade as usize;
+        if self.gbmode == GbMode::ColorAsClassic && self.colorization.is_some() {
+            return self.cgb_color(match layer {
+                Layer::Bg => self.cbgpal[0][shade],
+                Layer::Obj0 => self.csprit[0][shade],
+                Layer::Obj1 => self.csprit[1][shade],
+            });
+        }
+        match layer {
+            Layer::Bg => self.palettes.bg[shade],
+            Layer::Obj0 => self.palettes.obj0[shade],
+            Layer::Obj1 => self.palettes.obj1[shade],
+        }
     }
 
     fn draw_bg(&mut self) {
@@ -531,8 +764,7 @@ impl Gpu {
                 let b = self.cbgpal[palnr][colnr][2];
                 self.setrgb(x, r, g, b);
             } else {
-                let color = self.palb[colnr];
-                self.setcolor(x, color);
+                self.setshade(x, Layer::Bg, self.palb[colnr]);
             }
         }
     }
@@ -626,12 +858,19 @@ impl Gpu {
                     {
                         continue 'xloop;
                     }
-                    let color = if usepal1 {
-                        self.pal1[colnr]
+                    if usepal1 {
+                        self.setshade(
+                            (spritex + x) as usize,
+                            Layer::Obj1,
+                            self.pal1[colnr],
+                        );
                     } else {
-                        self.pal0[colnr]
-                    };
-                    self.setcolor((spritex + x) as usize, color);
+                        self.setshade(
+                            (spritex + x) as usize,
+                            Layer::Obj0,
+                            self.pal0[colnr],
+                        );
+                    }
                 }
             }
         }
@@ -0,0 +1,199 @@
+// Colors of the four DMG shades, from lightest to darkest
+pub type Palette = [[u8; 3]; 4];
+pub const GRAYSCALE: Palette =
+    [[255, 255, 255], [192, 192, 192], [96, 96, 96], [0, 0, 0]];
+pub const GREEN: Palette = [[224, 248, 208], [136, 192, 112], [52, 104, 86], [8, 24, 32]];
+// The pea soup of the original screen
+pub const DMG: Palette = [[155, 188, 15], [139, 172, 15], [48, 98, 48], [15, 56, 15]];
+pub const POCKET: Palette =
+    [[196, 207, 161], [139, 149, 109], [77, 83, 60], [31, 31, 31]];
+// The blue green backlight of the Game Boy Light
+pub const LIGHT: Palette = [[0, 198, 156], [0, 154, 118], [0, 94, 72], [0, 44, 36]];
+
+pub const BUILTIN: [(&str, Palette); 5] = [
+    ("gray", GRAYSCALE),
+    ("green", GREEN),
+    ("dmg", DMG),
+    ("pocket", POCKET),
+    ("light", LIGHT),
+];
+
+// Palettes of the background and window, and of the sprites using OBP0 and
+// OBP1. Only used by DMG games.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct DmgPalettes {
+    pub bg: Palette,
+    pub obj0: Palette,
+    pub obj1: Palette,
+}
+
+impl DmgPalettes {
+    // The same palette for everything
+    pub fn new(palette: Palette) -> DmgPalettes {
+        DmgPalettes {
+            bg: palette,
+            obj0: palette,
+            obj1: palette,
+        }
+    }
+
+    // Takes up to three palettes separated by `;`, for the background, OBJ0
+    // and OBJ1, each one also used by the ones after it when they are left
+    // out. A palette is the name of a built-in one or four colors from
+    // lightest to darkest, as in `#e0f8d0,#88c070,#346856,#081820`.
+    pub fn parse(value: &str) -> Result<DmgPalettes, String> {
+        let parts: Vec<&str> = value.split(';').map(str::trim).collect();
+        if parts.len() > 3 {
+            return Err(format!("more than three palettes in {:?}", value));
+        }
+        let mut palettes = [GRAYSCALE; 3];
+        for (i, part) in parts.iter().enumerate() {
+            let palette = parse_palette(part)?;
+            palettes[i..].fill(palette);
+        }
+        let [bg, obj0, obj1] = palettes;
+        Ok(DmgPalettes { bg, obj0, obj1 })
+    }
+}
+
+impl Default for DmgPalettes {
+    fn default() -> Self {
+        DmgPalettes::new(GRAYSCALE)
+    }
+}
+
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    let name = if value.eq_ignore_ascii_case("grey") {
+        "gray"
+    } else {
+        value
+    };
+    if let Some(&(_, palette)) =
+        BUILTIN.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        return Ok(palette);
+    }
+
+    let invalid = || format!("invalid palette {:?}", value);
+    let colors: Vec<&str> = value.split(',').map(str::trim).collect();
+    if colors.len() != 4 {
+        return Err(invalid());
+    }
+    let mut palette = [[0; 3]; 4];
+    for (shade, color) in palette.iter_mut().zip(colors) {
+        let hex = color.trim_start_matches('#');
+        let rgb = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        if hex.len() != 6 {
+            return Err(invalid());
+        }
+        *shade = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+    Ok(palette)
+}
+
+// How the 15-bit colors of CGB games are turned into RGB. The CGB screen is
+// darker and less saturated than a computer monitor, games were made to look
+// right on it.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum ColorCorrection {
+    // Each channel scaled up to 8 bits as is
+    None,
+    // The cheap approximation of the Gambatte emulator
+    #[default]
+    Gambatte,
+    // Mixes the channels in linear light and applies the screen's gamma
+    Accurate,
+}
+
+impl ColorCorrection {
+    pub const ALL: [ColorCorrection; 3] = [
+        ColorCorrection::None,
+        ColorCorrection::Gambatte,
+        ColorCorrection::Accurate,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorCorrection::None => "none",
+            ColorCorrection::Gambatte => "gambatte",
+            ColorCorrection::Accurate => "accurate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ColorCorrection> {
+        ColorCorrection::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(name))
+    }
+
+    // Channels are 0 to 31
+    pub fn apply(self, r: u8, g: u8, b: u8) -> [u8; 3] {
+        match self {
+            ColorCorrection::None => [r, g, b].map(|c| (c << 3) | (c >> 2)),
+            ColorCorrection::Gambatte => {
+                let (r, g, b) = (r as u32, g as u32, b as u32);
+                [
+                    ((r * 13 + g * 2 + b) >> 1) as u8,
+                    ((g * 3 + b) << 1) as u8,
+                    ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+                ]
+            }
+            ColorCorrection::Accurate => {
+                let [r, g, b] = [r, g, b].map(|c| (c as f32 / 31.0).powf(2.2));
+                // Each row adds up to one, so white stays white
+                let mixed = [
+                    0.82 * r + 0.24 * g - 0.06 * b,
+                    0.125 * r + 0.665 * g + 0.21 * b,
+                    0.195 * r + 0.075 * g + 0.73 * b,
+                ];
+                mixed.map(|c| (c.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8)
+            }
+        }
+    }
+
+    // Every 15-bit color, indexed by `r | g << 5 | b << 10`
+    pub fn table(self) -> Vec<[u8; 3]> {
+        (0..0x8000u16)
+            .map(|c| {
+                let channel = |shift: u16| ((c >> shift) & 0x1F) as u8;
+                self.apply(channel(0), channel(5), channel(10))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColorCorrection, DmgPalettes, GRAYSCALE, GREEN, POCKET};
+
+    #[test]
+    fn parses_one_to_three_palettes() {
+        assert_eq!(
+            DmgPalettes::parse("green").unwrap(),
+            DmgPalettes::new(GREEN)
+        );
+        let palettes =
+            DmgPalettes::parse("pocket; #ffffff,#aaaaaa,#555555,#000000").unwrap();
+        assert_eq!(palettes.bg, POCKET);
+        assert_eq!(palettes.obj0[1], [0xAA, 0xAA, 0xAA]);
+        assert_eq!(palettes.obj1, palettes.obj0);
+        assert_eq!(
+            DmgPalettes::parse("Grey;gray;green").unwrap().obj0,
+            GRAYSCALE
+        );
+
+        assert!(DmgPalettes::parse("sepia").is_err());
+        assert!(DmgPalettes::parse("#fff,#aaa,#555,#000").is_err());
+        assert!(DmgPalettes::parse("gray;gray;gray;gray").is_err());
+    }
+
+    #[test]
+    fn corrections_keep_black_and_white() {
+        for correction in ColorCorrection::ALL {
+            let table = correction.table();
+            assert_eq!(table[0], [0, 0, 0], "{:?}", correction);
+            assert!(table[0x7FFF].iter().all(|&c| c >= 248), "{:?}", correction);
+        }
+        assert_eq!(ColorCorrection::None.apply(31, 16, 0), [255, 132, 0]);
+    }
+}
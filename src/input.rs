@@ -1,11 +1,17 @@
+use crate::mmu::StrResult;
+use crate::sgb::Packets;
+use crate::state::{StateReader, StateWriter};
+
 pub struct Keypad {
     row0: u8,
     row1: u8,
     data: u8,
     pub interrupt: u8,
+    // Commands sent to the Super Game Boy, only for games that support it
+    pub sgb: Option<Packets>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum KeypadKey {
     Right,
     Left,
@@ -17,6 +23,38 @@ pub enum KeypadKey {
     Start,
 }
 
+impl KeypadKey {
+    pub const ALL: [KeypadKey; 8] = [
+        KeypadKey::Right,
+        KeypadKey::Left,
+        KeypadKey::Up,
+        KeypadKey::Down,
+        KeypadKey::A,
+        KeypadKey::B,
+        KeypadKey::Select,
+        KeypadKey::Start,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeypadKey::Right => "right",
+            KeypadKey::Left => "left",
+            KeypadKey::Up => "up",
+            KeypadKey::Down => "down",
+            KeypadKey::A => "a",
+            KeypadKey::B => "b",
+            KeypadKey::Select => "select",
+            KeypadKey::Start => "start",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<KeypadKey> {
+        KeypadKey::ALL
+            .into_iter()
+            .find(|key| key.name().eq_ignore_ascii_case(name))
+    }
+}
+
 impl Default for Keypad {
     fn default() -> Self {
         Keypad {
@@ -24,6 +62,7 @@ impl Default for Keypad {
             row1: 0x0F,
             data: 0xFF,
             interrupt: 0,
+            sgb: None,
         }
     }
 }
@@ -35,12 +74,27 @@ impl Keypad {
 
     pub fn wb(&mut self, value: u8) {
         self.data = (self.data & 0xCF) | (value & 0x30);
+        if let Some(ref mut packets) = self.sgb {
+            packets.write(value & 0x30);
+        }
         self.update();
     }
 
     fn update(&mut self) {
         let old_values = self.data & 0xF;
         let mut new_values = 0xF;
+        let player = self.sgb.as_ref().map_or(0, |packets| packets.player);
+
+        // With both rows deselected, the SGB tells which joypad is read
+        if self.data & 0x30 == 0x30 && self.sgb.is_some() {
+            self.data = (self.data & 0xF0) | (0xF - player);
+            return;
+        }
+        // Only the first player has buttons here
+        if player != 0 {
+            self.data |= 0x0F;
+            return;
+        }
 
         if self.data & 0x10 == 0x00 {
             new_values &= self.row0;
@@ -56,6 +110,27 @@ impl Keypad {
         self.data = (self.data & 0xF0) | new_values;
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.row0);
+        w.u8(self.row1);
+        w.u8(self.data);
+        w.u8(self.interrupt);
+        if let Some(ref packets) = self.sgb {
+            packets.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.row0 = r.u8()?;
+        self.row1 = r.u8()?;
+        self.data = r.u8()?;
+        self.interrupt = r.u8()?;
+        match self.sgb {
+            Some(ref mut packets) => packets.load_state(r),
+            None => Ok(()),
+        }
+    }
+
     pub fn keydown(&mut self, key: KeypadKey) {
         match key {
             KeypadKey::Right => self.row0 &= !(1 << 0),
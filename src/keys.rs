@@ -0,0 +1,217 @@
+use crate::input::KeypadKey;
+
+// Things the player can ask of the emulator itself rather than of the game.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    // Held down, runs several frames per frame
+    FastForward,
+    Pause,
+    Reset,
+    Screenshot,
+    // Only the desktop frontend has a window to make fullscreen
+    Fullscreen,
+    // Switches to the next shader preset of the desktop frontend
+    NextShader,
+    // Switches DMG palettes, or the color correction of CGB games
+    NextPalette,
+}
+
+impl Hotkey {
+    pub const ALL: [Hotkey; 9] = [
+        Hotkey::SaveState,
+        Hotkey::LoadState,
+        Hotkey::FastForward,
+        Hotkey::Pause,
+        Hotkey::Reset,
+        Hotkey::Screenshot,
+        Hotkey::Fullscreen,
+        Hotkey::NextShader,
+        Hotkey::NextPalette,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hotkey::SaveState => "save_state",
+            Hotkey::LoadState => "load_state",
+            Hotkey::FastForward => "fast_forward",
+            Hotkey::Pause => "pause",
+            Hotkey::Reset => "reset",
+            Hotkey::Screenshot => "screenshot",
+            Hotkey::Fullscreen => "fullscreen",
+            Hotkey::NextShader => "next_shader",
+            Hotkey::NextPalette => "next_palette",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        Hotkey::ALL
+            .into_iter()
+            .find(|key| key.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Action {
+    Button(KeypadKey),
+    Hotkey(Hotkey),
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Button(button) => button.name(),
+            Action::Hotkey(hotkey) => hotkey.name(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        KeypadKey::from_name(name)
+            .map(Action::Button)
+            .or_else(|| Hotkey::from_name(name).map(Action::Hotkey))
+    }
+}
+
+impl From<KeypadKey> for Action {
+    fn from(button: KeypadKey) -> Self {
+        Action::Button(button)
+    }
+}
+
+impl From<Hotkey> for Action {
+    fn from(hotkey: Hotkey) -> Self {
+        Action::Hotkey(hotkey)
+    }
+}
+
+// Maps host keys to Game Boy buttons and hotkeys. Frontends name their keys
+// the same way so one set of bindings works everywhere: letters and digits as
+// "A" or "Key1", everything else with names such as "Left", "Return" or "F5".
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct KeyBindings {
+    bindings: Vec<(String, Action)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let mut keys = KeyBindings::new();
+        keys.bind("A", KeypadKey::A);
+        keys.bind("B", KeypadKey::B);
+        keys.bind("Z", KeypadKey::Select);
+        keys.bind("X", KeypadKey::Start);
+        keys.bind("Left", KeypadKey::Left);
+        keys.bind("Right", KeypadKey::Right);
+        keys.bind("Up", KeypadKey::Up);
+        keys.bind("Down", KeypadKey::Down);
+        keys.bind("F5", Hotkey::SaveState);
+        keys.bind("F9", Hotkey::LoadState);
+        keys.bind("Tab", Hotkey::FastForward);
+        keys.bind("P", Hotkey::Pause);
+        keys.bind("R", Hotkey::Reset);
+        keys.bind("F12", Hotkey::Screenshot);
+        keys.bind("F11", Hotkey::Fullscreen);
+        keys.bind("F8", Hotkey::NextShader);
+        keys.bind("F7", Hotkey::NextPalette);
+        keys
+    }
+}
+
+impl KeyBindings {
+    // No keys bound at all
+    pub fn new() -> KeyBindings {
+        KeyBindings {
+            bindings: Vec::new(),
+        }
+    }
+
+    pub fn bind(&mut self, key: &str, action: impl Into<Action>) {
+        self.bindings.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self.bindings.push((key.to_string(), action.into()));
+    }
+
+    // Replaces every key bound to `action`
+    pub fn set_keys(&mut self, action: impl Into<Action>, keys: &[&str]) {
+        let action = action.into();
+        self.bindings.retain(|&(_, a)| a != action);
+        for key in keys {
+            self.bind(key, action);
+        }
+    }
+
+    // Applies a binding written as `action=key,key`, e.g. `start=Return,Space`
+    pub fn apply(&mut self, binding: &str) -> Result<(), String> {
+        let (action, keys) = binding
+            .split_once('=')
+            .ok_or_else(|| format!("invalid key binding {:?}", binding))?;
+        let action = Action::from_name(action.trim())
+            .ok_or_else(|| format!("unknown button or hotkey {:?}", action.trim()))?;
+        let keys: Vec<&str> = keys
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .collect();
+        self.set_keys(action, &keys);
+        Ok(())
+    }
+
+    pub fn action(&self, key: &str) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, a)| a)
+    }
+
+    pub fn button(&self, key: &str) -> Option<KeypadKey> {
+        match self.action(key)? {
+            Action::Button(button) => Some(button),
+            Action::Hotkey(_) => None,
+        }
+    }
+
+    pub fn keys(&self, action: impl Into<Action>) -> impl Iterator<Item = &str> {
+        let action = action.into();
+        self.bindings
+            .iter()
+            .filter(move |&&(_, a)| a == action)
+            .map(|(k, _)| k.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, Hotkey, KeyBindings};
+    use crate::input::KeypadKey;
+
+    #[test]
+    fn rebinding_replaces_keys() {
+        let mut keys = KeyBindings::default();
+        assert_eq!(keys.button("x"), Some(KeypadKey::Start));
+
+        keys.set_keys(KeypadKey::Start, &["Return", "Space"]);
+        assert_eq!(keys.button("X"), None);
+        assert_eq!(keys.button("return"), Some(KeypadKey::Start));
+        assert_eq!(keys.keys(KeypadKey::Start).count(), 2);
+
+        keys.bind("Space", KeypadKey::A);
+        assert_eq!(keys.button("Space"), Some(KeypadKey::A));
+        assert_eq!(KeypadKey::from_name("Select"), Some(KeypadKey::Select));
+    }
+
+    #[test]
+    fn binds_hotkeys_from_text() {
+        let mut keys = KeyBindings::default();
+        assert_eq!(keys.action("f5"), Some(Action::Hotkey(Hotkey::SaveState)));
+        assert_eq!(keys.button("F5"), None);
+
+        keys.apply("save_state = S, F2").unwrap();
+        keys.apply("b=F5").unwrap();
+        assert_eq!(keys.action("s"), Some(Action::Hotkey(Hotkey::SaveState)));
+        assert_eq!(keys.action("F2"), Some(Action::Hotkey(Hotkey::SaveState)));
+        assert_eq!(keys.button("F5"), Some(KeypadKey::B));
+        assert_eq!(keys.button("B"), None);
+
+        assert!(keys.apply("jump=Space").is_err());
+        assert!(keys.apply("Space").is_err());
+    }
+}
@@ -3,12 +3,22 @@ use wasm_bindgen::prelude::*;
 
 pub mod cpu;
 pub mod gameboy;
+pub mod gamepad;
 mod gpu;
 mod input;
+pub mod keys;
+pub mod link;
 mod mbc;
 mod mmu;
 mod mode;
+pub mod patch;
+pub mod printer;
+pub mod scale;
 mod screen;
+pub mod search;
+mod sgb;
+pub mod shaders;
+mod state;
 
 #[cfg(target_arch = "wasm32")]
 #[wasm_bindgen(start)]
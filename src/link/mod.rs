@@ -0,0 +1,118 @@
+use crate::gameboy::{Gameboy, SerialDevice, CYCLES};
+use std::sync::{Arc, Mutex};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp;
+
+#[derive(Default)]
+struct Port {
+    // SB of a side waiting for the external clock
+    waiting: Option<u8>,
+    // Byte clocked in by the other side, not yet picked up
+    received: Option<u8>,
+}
+
+// One end of an in-process link cable. Whichever side writes SC with the
+// internal clock becomes the master for that transfer.
+pub struct LinkPort {
+    side: usize,
+    ports: Arc<Mutex<[Port; 2]>>,
+}
+
+impl LinkPort {
+    pub fn pair() -> (LinkPort, LinkPort) {
+        let ports = Arc::new(Mutex::new([Port::default(), Port::default()]));
+        (
+            LinkPort {
+                side: 0,
+                ports: ports.clone(),
+            },
+            LinkPort { side: 1, ports },
+        )
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn transfer(&mut self, out: u8) -> u8 {
+        let mut ports = self.ports.lock().unwrap();
+        let peer = &mut ports[1 - self.side];
+        match peer.waiting.take() {
+            Some(v) => {
+                peer.received = Some(out);
+                v
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn external(&mut self, out: u8) -> Option<u8> {
+        let mut ports = self.ports.lock().unwrap();
+        let port = &mut ports[self.side];
+        match port.received.take() {
+            Some(v) => {
+                port.waiting = None;
+                Some(v)
+            }
+            None => {
+                port.waiting = Some(out);
+                None
+            }
+        }
+    }
+}
+
+pub fn connect(a: &mut Gameboy, b: &mut Gameboy) {
+    let (port_a, port_b) = LinkPort::pair();
+    a.set_serial_device(Box::new(port_a));
+    b.set_serial_device(Box::new(port_b));
+}
+
+// Runs both machines until each has produced a frame, never letting one get
+// more than a single instruction ahead of the other.
+pub fn frame(a: &mut Gameboy, b: &mut Gameboy) {
+    let (mut ticks_a, mut ticks_b) = (0, 0);
+    let (mut done_a, mut done_b) = (false, false);
+
+    while !(done_a && done_b) {
+        if !done_a && (done_b || ticks_a <= ticks_b) {
+            ticks_a += a.step();
+            done_a = a.check_and_reset_gpu_updated() || ticks_a >= CYCLES;
+        } else {
+            ticks_b += b.step();
+            done_b = b.check_and_reset_gpu_updated() || ticks_b >= CYCLES;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gameboy::Gameboy;
+
+    fn run(code: &[u8]) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        Gameboy::new(rom, None)
+    }
+
+    #[test]
+    fn exchanges_bytes_between_machines() {
+        // ld a, 24; ldh (01), a; ld a, 81; ldh (02), a; jr -2, after a short
+        // delay so the slave is ready first
+        let mut master = run(&[
+            0x06, 0x00, 0x05, 0x20, 0xFD, 0x3E, 0x24, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0x18, 0xFE,
+        ]);
+        // ld a, 42; ldh (01), a; ld a, 80; ldh (02), a; jr -2
+        let mut slave =
+            run(&[0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18, 0xFE]);
+        super::connect(&mut master, &mut slave);
+
+        super::frame(&mut master, &mut slave);
+        super::frame(&mut master, &mut slave);
+
+        assert_eq!(master.peek(0xFF01), 0x42);
+        assert_eq!(slave.peek(0xFF01), 0x24);
+        assert_eq!(master.peek(0xFF0F) & 0x08, 0x08);
+        assert_eq!(slave.peek(0xFF0F) & 0x08, 0x08);
+    }
+}
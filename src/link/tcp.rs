@@ -0,0 +1,333 @@
+use crate::gameboy::{SerialDevice, CYCLES};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Every message is a tag followed by a big endian payload. Transfers and
+// replies carry a sequence number above the data byte.
+const MSG_CLOCK: u8 = b'C';
+const MSG_TRANSFER: u8 = b'T';
+const MSG_REPLY: u8 = b'R';
+const MSG_LEN: usize = 9;
+
+// Each side reports its clock every SYNC_INTERVAL ticks and stops once it
+// gets SYNC_WINDOW ticks ahead of the last clock reported by the other side.
+const SYNC_INTERVAL: u64 = 4096;
+const SYNC_WINDOW: u64 = CYCLES as u64;
+
+// Clocks are kept within SYNC_WINDOW of each other, so a reply only takes
+// this long when the peer or the network is gone. The link is then dropped on
+// both sides, reading 0xFF alike, rather than letting one side carry on with a
+// byte the other already sent.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct State {
+    waiting: Option<u8>,
+    received: Option<u8>,
+    // Sequence number of the transfer waiting for its reply
+    pending: Option<u64>,
+    reply: Option<u8>,
+    peer_clock: u64,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+// Link cable tunnelled over TCP. A background thread answers the other side's
+// transfers, so a master never waits on this side's emulation loop.
+pub struct TcpLink {
+    stream: Arc<Mutex<TcpStream>>,
+    shared: Arc<Shared>,
+    clock: u64,
+    last_sync: u64,
+    sequence: u64,
+    reply_timeout: Duration,
+    // Why the link was dropped, once it is
+    error: Option<&'static str>,
+    reported: bool,
+}
+
+fn send(stream: &Mutex<TcpStream>, tag: u8, payload: u64) -> io::Result<()> {
+    let mut msg = [0; MSG_LEN];
+    msg[0] = tag;
+    msg[1..].copy_from_slice(&payload.to_be_bytes());
+    stream.lock().unwrap().write_all(&msg)
+}
+
+fn receive(
+    mut reader: TcpStream,
+    stream: Arc<Mutex<TcpStream>>,
+    shared: Arc<Shared>,
+) -> io::Result<()> {
+    let mut msg = [0; MSG_LEN];
+    loop {
+        reader.read_exact(&mut msg)?;
+        let mut payload = [0; 8];
+        payload.copy_from_slice(&msg[1..]);
+        let payload = u64::from_be_bytes(payload);
+
+        let mut state = shared.state.lock().unwrap();
+        match msg[0] {
+            MSG_CLOCK => state.peer_clock = payload,
+            MSG_TRANSFER => {
+                let answer = match state.waiting.take() {
+                    Some(v) => {
+                        state.received = Some(payload as u8);
+                        v
+                    }
+                    None => 0xFF,
+                };
+                drop(state);
+                send(&stream, MSG_REPLY, payload & !0xFF | answer as u64)?;
+                continue;
+            }
+            // Replies that arrive after their transfer timed out are dropped
+            MSG_REPLY if state.pending == Some(payload >> 8) => {
+                state.reply = Some(payload as u8)
+            }
+            MSG_REPLY => continue,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad message")),
+        }
+        shared.changed.notify_all();
+    }
+}
+
+impl TcpLink {
+    // Waits for a single peer to join on `addr`.
+    pub fn host<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn join<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        TcpLink::from_stream(TcpStream::connect(addr)?)
+    }
+
+    pub fn from_stream(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let stream = Arc::new(Mutex::new(stream));
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+
+        {
+            let stream = stream.clone();
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let _ = receive(reader, stream, shared.clone());
+                shared.state.lock().unwrap().closed = true;
+                shared.changed.notify_all();
+            });
+        }
+
+        Ok(TcpLink {
+            stream,
+            shared,
+            clock: 0,
+            last_sync: 0,
+            sequence: 0,
+            reply_timeout: REPLY_TIMEOUT,
+            error: None,
+            reported: false,
+        })
+    }
+
+    fn disconnect(&mut self, error: &'static str) {
+        self.error.get_or_insert(error);
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        let _ = self
+            .stream
+            .lock()
+            .unwrap()
+            .shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.sequence += 1;
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.pending = Some(self.sequence);
+            state.reply = None;
+        }
+        let payload = self.sequence << 8 | out as u64;
+        if send(&self.stream, MSG_TRANSFER, payload).is_err() {
+            self.disconnect("Link cable disconnected");
+            return 0xFF;
+        }
+
+        let state = self.shared.state.lock().unwrap();
+        let (mut state, timeout) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, self.reply_timeout, |s| {
+                s.reply.is_none() && !s.closed
+            })
+            .unwrap();
+        state.pending = None;
+        let reply = state.reply.take();
+        drop(state);
+        if timeout.timed_out() {
+            self.disconnect("Link cable dropped, the other side stopped answering");
+        }
+        reply.unwrap_or(0xFF)
+    }
+
+    fn external(&mut self, out: u8) -> Option<u8> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.received.take() {
+            Some(v) => Some(v),
+            None => {
+                state.waiting = Some(out);
+                None
+            }
+        }
+    }
+
+    fn tick(&mut self, ticks: u32) {
+        self.clock += ticks as u64;
+        if self.clock - self.last_sync < SYNC_INTERVAL {
+            return;
+        }
+
+        self.last_sync = self.clock;
+        if send(&self.stream, MSG_CLOCK, self.clock).is_err() {
+            self.disconnect("Link cable disconnected");
+            return;
+        }
+
+        let clock = self.clock;
+        let state = self.shared.state.lock().unwrap();
+        let _state = self
+            .shared
+            .changed
+            .wait_while(state, |s| clock > s.peer_clock + SYNC_WINDOW && !s.closed)
+            .unwrap();
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        if self.reported || !self.shared.state.lock().unwrap().closed {
+            return None;
+        }
+        self.reported = true;
+        Some(String::from(
+            self.error.unwrap_or("Link cable disconnected"),
+        ))
+    }
+}
+
+impl Drop for TcpLink {
+    fn drop(&mut self) {
+        let _ = self
+            .stream
+            .lock()
+            .unwrap()
+            .shutdown(std::net::Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TcpLink, MSG_LEN, MSG_REPLY, MSG_TRANSFER};
+    use crate::gameboy::SerialDevice;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let joiner = thread::spawn(move || TcpStream::connect(addr).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        (stream, joiner.join().unwrap())
+    }
+
+    fn read(stream: &mut TcpStream) -> (u8, u64) {
+        let mut msg = [0; MSG_LEN];
+        stream.read_exact(&mut msg).unwrap();
+        let mut payload = [0; 8];
+        payload.copy_from_slice(&msg[1..]);
+        (msg[0], u64::from_be_bytes(payload))
+    }
+
+    fn write(stream: &mut TcpStream, tag: u8, payload: u64) {
+        let mut msg = [0; MSG_LEN];
+        msg[0] = tag;
+        msg[1..].copy_from_slice(&payload.to_be_bytes());
+        stream.write_all(&msg).unwrap();
+    }
+
+    #[test]
+    fn transfers_over_localhost() {
+        let (a, b) = pair();
+        let mut master = TcpLink::from_stream(a).unwrap();
+        let mut slave = TcpLink::from_stream(b).unwrap();
+
+        assert_eq!(slave.external(0x42), None);
+        assert_eq!(master.transfer(0x24), 0x42);
+        assert_eq!(slave.external(0x42), Some(0x24));
+
+        // A byte sent while the other side is not listening is lost
+        assert_eq!(master.transfer(0x25), 0xFF);
+        assert_eq!(slave.external(0x43), None);
+
+        // Both sides take turns driving the clock
+        assert_eq!(master.external(0x10), None);
+        assert_eq!(slave.transfer(0x20), 0x10);
+        assert_eq!(master.external(0x10), Some(0x20));
+    }
+
+    #[test]
+    fn silent_peer_drops_the_link() {
+        let (a, mut peer) = pair();
+        let mut master = TcpLink::from_stream(a).unwrap();
+        master.reply_timeout = Duration::from_millis(50);
+        assert_eq!(master.take_error(), None);
+
+        // The peer takes the byte but never answers
+        let start = Instant::now();
+        assert_eq!(master.transfer(0x01), 0xFF);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(read(&mut peer), (MSG_TRANSFER, 1 << 8 | 0x01));
+
+        // Both sides see the link go down instead of drifting apart
+        let mut rest = Vec::new();
+        assert_eq!(peer.read_to_end(&mut rest).unwrap(), 0);
+        assert!(master.take_error().unwrap().contains("stopped answering"));
+        assert_eq!(master.take_error(), None);
+        let start = Instant::now();
+        assert_eq!(master.transfer(0x02), 0xFF);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn late_replies_are_not_taken_for_the_next_transfer() {
+        let (a, mut peer) = pair();
+        let mut master = TcpLink::from_stream(a).unwrap();
+
+        // A reply for a transfer that is not the one waiting, as a buggy or
+        // restarted peer could send, is ignored
+        let answer = thread::spawn(move || {
+            let (tag, transfer) = read(&mut peer);
+            assert_eq!((tag, transfer & 0xFF), (MSG_TRANSFER, 0x02));
+            let other = (transfer & !0xFF) + (1 << 8);
+            write(&mut peer, MSG_REPLY, other | 0x11);
+            write(&mut peer, MSG_REPLY, transfer & !0xFF | 0x22);
+            peer
+        });
+        assert_eq!(master.transfer(0x02), 0x22);
+        answer.join().unwrap();
+    }
+}
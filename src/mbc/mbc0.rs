@@ -1,5 +1,6 @@
 pub type StrResult<T> = Result<T, &'static str>;
 use crate::mbc::MemoryBankController;
+use crate::state::{StateReader, StateWriter};
 
 pub struct MBC0 {
     rom: Vec<u8>,
@@ -20,4 +21,17 @@ impl MemoryBankController for MBC0 {
     }
     fn writerom(&mut self, _a: u16, _v: u8) {}
     fn writeram(&mut self, _a: u16, _v: u8) {}
+    fn save_state(&self, _w: &mut StateWriter) {}
+    fn load_state(&mut self, _r: &mut StateReader) -> StrResult<()> {
+        Ok(())
+    }
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+    fn rom_offset(&self, a: u16) -> usize {
+        a as usize
+    }
 }
@@ -2,6 +2,7 @@ use std::io::prelude::*;
 use std::{fs, io, path};
 
 use crate::mbc::{ram_banks, rom_banks, MemoryBankController};
+use crate::state::{StateReader, StateWriter};
 pub type StrResult<T> = Result<T, &'static str>;
 
 pub struct MBC1 {
@@ -17,10 +18,13 @@ pub struct MBC1 {
 }
 
 impl MBC1 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC1> {
+    pub fn new(data: Vec<u8>, file: Option<path::PathBuf>) -> StrResult<MBC1> {
         let (svpath, rambanks) = match data[0x147] {
             0x02 => (None, ram_banks(data[0x149])),
-            0x03 => (Some(file.with_extension("gbsave")), ram_banks(data[0x149])),
+            0x03 => (
+                file.map(|f| f.with_extension("gbsave")),
+                ram_banks(data[0x149]),
+            ),
             _ => (None, 0),
         };
         let rombanks = rom_banks(data[0x148]);
@@ -40,31 +44,6 @@ impl MBC1 {
         res.loadram().map(|_| res)
     }
 
-    #[allow(dead_code)]
-    pub fn new_without_save(data: Vec<u8>) -> StrResult<MBC1> {
-        let (svpath, rambanks) = match data[0x147] {
-            0x02 => (None, ram_banks(data[0x149])),
-            0x03 => (None, ram_banks(data[0x149])),
-            _ => (None, 0),
-        };
-        let rombanks = rom_banks(data[0x148]);
-        let ramsize = rambanks * 0x2000;
-
-        let res = MBC1 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
-            ram_on: false,
-            banking_mode: 0,
-            rombank: 1,
-            rambank: 0,
-            savepath: svpath,
-            rombanks,
-            rambanks,
-        };
-        // res.loadram().map(|_| res)
-        Ok(res)
-    }
-
     fn loadram(&mut self) -> StrResult<()> {
         match self.savepath {
             None => Ok(()),
@@ -163,4 +142,61 @@ impl MemoryBankController for MBC1 {
             self.ram[address] = v;
         }
     }
+    fn save_state(&self, w: &mut StateWriter) {
+        w.buffer(&self.ram);
+        w.bool(self.ram_on);
+        w.u8(self.banking_mode);
+        w.u32(self.rombank as u32);
+        w.u32(self.rambank as u32);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        r.buffer(&mut self.ram)?;
+        self.ram_on = r.bool()?;
+        self.banking_mode = r.u8()?;
+        self.rombank = r.u32()? as usize;
+        self.rambank = r.u32()? as usize;
+        if self.rombank >= self.rombanks
+            || self.rambank > 0 && self.rambank >= self.rambanks
+        {
+            return Err("Save state is invalid");
+        }
+        Ok(())
+    }
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+    fn rom_offset(&self, a: u16) -> usize {
+        let bank = if a < 0x4000 {
+            if self.banking_mode == 0 {
+                0
+            } else {
+                self.rombank & 0xE0
+            }
+        } else {
+            self.rombank
+        };
+        (bank * 0x4000) | ((a as usize) & 0x3FFF)
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_offset(&self, a: u16) -> Option<usize> {
+        let rambank = if self.banking_mode == 1 {
+            self.rambank
+        } else {
+            0
+        };
+        let address = (rambank * 0x2000) | ((a & 0x1FFF) as usize);
+        if address < self.ram.len() {
+            Some(address)
+        } else {
+            None
+        }
+    }
 }
@@ -2,6 +2,7 @@ use std::io::prelude::*;
 use std::{fs, io, path};
 
 use crate::mbc::{rom_banks, MemoryBankController};
+use crate::state::{StateReader, StateWriter};
 pub type StrResult<T> = Result<T, &'static str>;
 
 pub struct MBC2 {
@@ -14,10 +15,10 @@ pub struct MBC2 {
 }
 
 impl MBC2 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC2> {
+    pub fn new(data: Vec<u8>, file: Option<path::PathBuf>) -> StrResult<MBC2> {
         let svpath = match data[0x147] {
             0x05 => None,
-            0x06 => Some(file.with_extension("gbsave")),
+            0x06 => file.map(|f| f.with_extension("gbsave")),
             _ => None,
         };
         let rombanks = rom_banks(data[0x148]);
@@ -95,4 +96,37 @@ impl MemoryBankController for MBC2 {
         }
         self.ram[(a as usize) & 0x1FF] = v | 0xF0;
     }
+    fn save_state(&self, w: &mut StateWriter) {
+        w.buffer(&self.ram);
+        w.bool(self.ram_on);
+        w.u32(self.rombank as u32);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        r.buffer(&mut self.ram)?;
+        self.ram_on = r.bool()?;
+        self.rombank = r.u32()? as usize;
+        if self.rombank >= self.rombanks {
+            return Err("Save state is invalid");
+        }
+        Ok(())
+    }
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+    fn rom_offset(&self, a: u16) -> usize {
+        let bank = if a < 0x4000 { 0 } else { self.rombank };
+        (bank * 0x4000) | ((a as usize) & 0x3FFF)
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_offset(&self, a: u16) -> Option<usize> {
+        Some((a as usize) & 0x1FF)
+    }
 }
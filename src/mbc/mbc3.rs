@@ -1,4 +1,5 @@
 use crate::mbc::{ram_banks, MemoryBankController};
+use crate::state::{StateReader, StateWriter};
 pub type StrResult<T> = Result<T, &'static str>;
 
 use std::io::prelude::*;
@@ -20,10 +21,10 @@ pub struct MBC3 {
 }
 
 impl MBC3 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC3> {
+    pub fn new(data: Vec<u8>, file: Option<path::PathBuf>) -> StrResult<MBC3> {
         let subtype = data[0x147];
         let svpath = match subtype {
-            0x0F | 0x10 | 0x13 => Some(file.with_extension("gbsave")),
+            0x0F | 0x10 | 0x13 => file.map(|f| f.with_extension("gbsave")),
             _ => None,
         };
         let rambanks = match subtype {
@@ -52,36 +53,6 @@ impl MBC3 {
         res.loadram().map(|_| res)
     }
 
-    pub fn new_without_save(data: Vec<u8>) -> StrResult<MBC3> {
-        let subtype = data[0x147];
-        let svpath = None;
-
-        let rambanks = match subtype {
-            0x10 | 0x12 | 0x13 => ram_banks(data[0x149]),
-            _ => 0,
-        };
-        let ramsize = rambanks * 0x2000;
-        let rtc = match subtype {
-            0x0F | 0x10 => Some(0),
-            _ => None,
-        };
-
-        let mut res = MBC3 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
-            rombank: 1,
-            rambank: 0,
-            rambanks,
-            selectrtc: false,
-            ram_on: false,
-            savepath: svpath,
-            rtc_ram: [0u8; 5],
-            rtc_ram_latch: [0u8; 5],
-            rtc_zero: rtc,
-        };
-        res.loadram().map(|_| res)
-    }
-
     fn loadram(&mut self) -> StrResult<()> {
         match self.savepath {
             None => Ok(()),
@@ -248,4 +219,58 @@ impl MemoryBankController for MBC3 {
             self.calc_rtc_zero();
         }
     }
+    fn save_state(&self, w: &mut StateWriter) {
+        w.buffer(&self.ram);
+        w.u32(self.rombank as u32);
+        w.u32(self.rambank as u32);
+        w.bool(self.selectrtc);
+        w.bool(self.ram_on);
+        w.bytes(&self.rtc_ram);
+        w.bytes(&self.rtc_ram_latch);
+        w.bool(self.rtc_zero.is_some());
+        w.u64(self.rtc_zero.unwrap_or(0));
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        r.buffer(&mut self.ram)?;
+        self.rombank = r.u32()? as usize;
+        self.rambank = r.u32()? as usize;
+        // The bank registers are 7 and 3 bits wide
+        if !(1..0x80).contains(&self.rombank) || self.rambank > 7 {
+            return Err("Save state is invalid");
+        }
+        self.selectrtc = r.bool()?;
+        self.ram_on = r.bool()?;
+        r.bytes(&mut self.rtc_ram)?;
+        r.bytes(&mut self.rtc_ram_latch)?;
+        let has_rtc = r.bool()?;
+        let rtc_zero = r.u64()?;
+        self.rtc_zero = if has_rtc { Some(rtc_zero) } else { None };
+        Ok(())
+    }
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+    fn rom_offset(&self, a: u16) -> usize {
+        if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_offset(&self, a: u16) -> Option<usize> {
+        if !self.selectrtc && self.rambank < self.rambanks {
+            Some((self.rambank * 0x2000) | ((a as usize) & 0x1FFF))
+        } else {
+            None
+        }
+    }
 }
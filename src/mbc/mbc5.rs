@@ -1,4 +1,5 @@
 use crate::mbc::{ram_banks, rom_banks, MemoryBankController};
+use crate::state::{StateReader, StateWriter};
 pub type StrResult<T> = Result<T, &'static str>;
 
 use std::fs::File;
@@ -17,10 +18,10 @@ pub struct MBC5 {
 }
 
 impl MBC5 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC5> {
+    pub fn new(data: Vec<u8>, file: Option<path::PathBuf>) -> StrResult<MBC5> {
         let subtype = data[0x147];
         let svpath = match subtype {
-            0x1B | 0x1E => Some(file.with_extension("gbsave")),
+            0x1B | 0x1E => file.map(|f| f.with_extension("gbsave")),
             _ => None,
         };
         let rambanks = match subtype {
@@ -108,4 +109,47 @@ impl MemoryBankController for MBC5 {
         }
         self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)] = v;
     }
+    fn save_state(&self, w: &mut StateWriter) {
+        w.buffer(&self.ram);
+        w.u32(self.rombank as u32);
+        w.u32(self.rambank as u32);
+        w.bool(self.ram_on);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        r.buffer(&mut self.ram)?;
+        self.rombank = r.u32()? as usize;
+        self.rambank = r.u32()? as usize;
+        if self.rombank >= self.rombanks
+            || self.rambank > 0 && self.rambank >= self.rambanks
+        {
+            return Err("Save state is invalid");
+        }
+        self.ram_on = r.bool()?;
+        Ok(())
+    }
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+    fn rom_offset(&self, a: u16) -> usize {
+        if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_offset(&self, a: u16) -> Option<usize> {
+        if self.rambanks == 0 {
+            return None;
+        }
+        Some((self.rambank * 0x2000) | ((a as usize) & 0x1FFF))
+    }
 }
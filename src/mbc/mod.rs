@@ -1,5 +1,6 @@
 pub type StrResult<T> = Result<T, &'static str>;
 
+use crate::state::{StateReader, StateWriter};
 use std::path;
 
 mod mbc0;
@@ -14,6 +15,25 @@ pub trait MemoryBankController: Send {
     fn writerom(&mut self, a: u16, v: u8);
     fn writeram(&mut self, a: u16, v: u8);
 
+    // Banking registers and cartridge RAM, the ROM is never part of the state.
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()>;
+
+    // Raw cartridge contents and the offsets the CPU address currently maps to,
+    // used to inspect or patch memory without touching the MBC registers.
+    fn rom(&self) -> &[u8];
+    fn rom_mut(&mut self) -> &mut [u8];
+    fn rom_offset(&self, a: u16) -> usize;
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+    fn ram_offset(&self, _a: u16) -> Option<usize> {
+        None
+    }
+
     fn romname(&self) -> String {
         const TITLE_START: u16 = 0x134;
         const CGB_FLAG: u16 = 0x143;
@@ -36,17 +56,12 @@ pub trait MemoryBankController: Send {
     }
 }
 
+// The controller is picked from the cartridge header. Without a path, battery
+// backed RAM starts empty and is not saved.
 pub fn get_mbc(
     data: Vec<u8>,
-    filepath: Option<path::PathBuf>,
+    file: Option<path::PathBuf>,
 ) -> StrResult<Box<dyn MemoryBankController + 'static>> {
-    if filepath.is_none() {
-        return mbc3::MBC3::new_without_save(data)
-            .map(|v| Box::new(v) as Box<dyn MemoryBankController>);
-    }
-
-    let file = filepath.unwrap();
-
     match data[0x147] {
         0x00 => {
             mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MemoryBankController>)
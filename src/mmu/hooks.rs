@@ -0,0 +1,176 @@
+use std::ops::RangeInclusive;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u8,
+    pub pc: u16,
+    pub cycle: u64,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct HookId(usize);
+
+pub type HookCallback<'a> = Box<dyn FnMut(&MemoryAccess) + Send + 'a>;
+
+struct Hook<'a> {
+    id: HookId,
+    kind: AccessKind,
+    range: RangeInclusive<u16>,
+    callback: HookCallback<'a>,
+}
+
+// Callbacks are kept outside of the `rb`/`wb` address decoding, the MMU only
+// calls in here when at least one hook is registered.
+#[derive(Default)]
+pub struct Hooks<'a> {
+    hooks: Vec<Hook<'a>>,
+    next_id: usize,
+    active: bool,
+    pc: u16,
+}
+
+impl<'a> Hooks<'a> {
+    #[inline]
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn add(
+        &mut self,
+        kind: AccessKind,
+        range: RangeInclusive<u16>,
+        callback: HookCallback<'a>,
+    ) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.push(Hook {
+            id,
+            kind,
+            range,
+            callback,
+        });
+        self.active = true;
+        id
+    }
+
+    pub fn remove(&mut self, id: HookId) {
+        self.hooks.retain(|hook| hook.id != id);
+        self.active = !self.hooks.is_empty();
+    }
+
+    pub fn clear(&mut self) {
+        self.hooks.clear();
+        self.active = false;
+    }
+
+    pub fn execute(&mut self, pc: u16, opcode: u8, cycle: u64) {
+        self.pc = pc;
+        self.fire(AccessKind::Execute, pc, opcode, cycle);
+    }
+
+    pub fn read(&mut self, address: u16, value: u8, cycle: u64) {
+        self.fire(AccessKind::Read, address, value, cycle);
+    }
+
+    pub fn write(&mut self, address: u16, value: u8, cycle: u64) {
+        self.fire(AccessKind::Write, address, value, cycle);
+    }
+
+    fn fire(&mut self, kind: AccessKind, address: u16, value: u8, cycle: u64) {
+        let access = MemoryAccess {
+            kind,
+            address,
+            value,
+            pc: self.pc,
+            cycle,
+        };
+        for hook in self.hooks.iter_mut() {
+            if hook.kind == kind && hook.range.contains(&address) {
+                (hook.callback)(&access);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccessKind;
+    use crate::gameboy::Gameboy;
+    use std::sync::{Arc, Mutex};
+
+    // A ROM that runs `code` from the entry point
+    fn run(code: &[u8]) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        Gameboy::new(rom, None)
+    }
+
+    #[test]
+    fn fires_only_inside_range() {
+        // ld a, 2; ldh (80), a; ldh (91), a
+        let mut gb = run(&[0x3E, 0x02, 0xE0, 0x80, 0xE0, 0x91]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = seen.clone();
+        gb.on_write(0xFF90..=0xFF9F, move |access| {
+            log.lock().unwrap().push(*access)
+        });
+        for _ in 0..3 {
+            gb.step();
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].kind, AccessKind::Write);
+        assert_eq!((seen[0].address, seen[0].value), (0xFF91, 2));
+        assert_eq!(seen[0].pc, 0x0104);
+    }
+
+    #[test]
+    fn ignores_dma_and_poke() {
+        // ld a, c0; ldh (46), a
+        let mut gb = run(&[0x3E, 0xC0, 0xE0, 0x46]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = seen.clone();
+        gb.on_read(0xC000..=0xC0FF, move |access| {
+            log.lock().unwrap().push(*access)
+        });
+        let log = seen.clone();
+        gb.on_write(0xFE00..=0xFE9F, move |access| {
+            log.lock().unwrap().push(*access)
+        });
+        gb.poke(0xC000, 0x42);
+        gb.poke(0xFE00, 1);
+        gb.step();
+        gb.step();
+
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(gb.peek(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn execute_reports_pc() {
+        let mut gb = Gameboy::new(vec![0; 0x8000], None);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = seen.clone();
+        let id = gb.on_execute(0x0100..=0x0101, move |access| {
+            log.lock().unwrap().push(access.pc)
+        });
+        gb.frame();
+        gb.remove_hook(id);
+        gb.frame();
+
+        assert_eq!(*seen.lock().unwrap(), vec![0x0100, 0x0101]);
+    }
+}
@@ -1,13 +1,18 @@
-mod serial;
+pub mod hooks;
+pub mod serial;
 mod timer;
 
-use crate::gpu::Gpu;
+use crate::gameboy::MemoryBank;
+use crate::gpu::{Colorization, Gpu};
 use crate::input::Keypad;
+use crate::mmu::hooks::Hooks;
 use crate::mmu::serial::Serial;
 use crate::mmu::timer::Timer;
 // use crate::sound::Sound;
 use crate::mbc;
 use crate::mode::{GbMode, GbSpeed};
+use crate::sgb::{self, Packets, Sgb};
+use crate::state::{StateReader, StateWriter};
 use std::path;
 
 pub type StrResult<T> = Result<T, &'static str>;
@@ -32,6 +37,11 @@ pub struct MemoryManagementUnit<'a> {
     pub timer: Timer,
     pub keypad: Keypad,
     pub gpu: Gpu,
+    // Super Game Boy, which colors the screen and draws a border around it
+    pub sgb: Option<Box<Sgb>>,
+    pub hooks: Hooks<'a>,
+    // Total CPU ticks elapsed, used to timestamp hooked accesses
+    pub clock: u64,
     // pub sound: Option<Sound>,
     hdma_status: DMAType,
     hdma_src: u16,
@@ -39,6 +49,8 @@ pub struct MemoryManagementUnit<'a> {
     hdma_len: u8,
     wrambank: usize,
     pub mbc: Box<dyn mbc::MemoryBankController + 'static>,
+    // Mapped over the cartridge until the boot ROM writes to FF50
+    pub boot_rom: Option<Vec<u8>>,
     pub gbmode: GbMode,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
@@ -77,8 +89,12 @@ impl<'a> MemoryManagementUnit<'a> {
             timer: Timer::default(),
             keypad: Keypad::default(),
             gpu: Gpu::new(),
+            sgb: None,
+            hooks: Hooks::default(),
+            clock: 0,
             // sound: None,
             mbc: mmu_mbc,
+            boot_rom: None,
             gbmode: GbMode::Classic,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
@@ -113,8 +129,12 @@ impl<'a> MemoryManagementUnit<'a> {
             timer: Timer::default(),
             keypad: Keypad::default(),
             gpu: Gpu::new_cgb(),
+            sgb: None,
+            hooks: Hooks::default(),
+            clock: 0,
             // sound: None,
             mbc: mmu_mbc,
+            boot_rom: None,
             gbmode: GbMode::Color,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
@@ -130,6 +150,18 @@ impl<'a> MemoryManagementUnit<'a> {
         Ok(res)
     }
 
+    pub fn new_sgb(
+        data: Vec<u8>,
+        file: Option<path::PathBuf>,
+    ) -> StrResult<MemoryManagementUnit<'a>> {
+        let mut res = MemoryManagementUnit::new(data, file)?;
+        res.sgb = Some(Box::default());
+        if sgb::supported(|a| res.mbc.readrom(a)) {
+            res.keypad.sgb = Some(Packets::default());
+        }
+        Ok(res)
+    }
+
     fn set_initial(&mut self) {
         self.wb(0xFF05, 0);
         self.wb(0xFF06, 0);
@@ -164,6 +196,90 @@ impl<'a> MemoryManagementUnit<'a> {
         self.wb(0xFF4B, 0);
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram);
+        w.bytes(&self.zram);
+        w.bytes(&self.hdma);
+        w.u8(self.inte);
+        w.u8(self.intf);
+        w.u64(self.clock);
+        w.u8(match self.hdma_status {
+            DMAType::NoDma => 0,
+            DMAType::Gdma => 1,
+            DMAType::Hdma => 2,
+        });
+        w.u16(self.hdma_src);
+        w.u16(self.hdma_dst);
+        w.u8(self.hdma_len);
+        w.u8(self.wrambank as u8);
+        w.mode(self.gbmode);
+        w.bool(self.gbspeed == GbSpeed::Double);
+        w.bool(self.speed_switch_req);
+        w.bytes(&self.undocumented_cgb_regs);
+        w.bool(self.boot_rom.is_some());
+        w.bool(self.sgb.is_some());
+        self.timer.save_state(w);
+        self.serial.save_state(w);
+        self.keypad.save_state(w);
+        self.gpu.save_state(w);
+        if let Some(ref sgb) = self.sgb {
+            sgb.save_state(w);
+        }
+        self.mbc.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        r.bytes(&mut self.wram)?;
+        r.bytes(&mut self.zram)?;
+        r.bytes(&mut self.hdma)?;
+        self.inte = r.u8()?;
+        self.intf = r.u8()?;
+        self.clock = r.u64()?;
+        self.hdma_status = match r.u8()? {
+            1 => DMAType::Gdma,
+            2 => DMAType::Hdma,
+            _ => DMAType::NoDma,
+        };
+        self.hdma_src = r.u16()?;
+        self.hdma_dst = r.u16()?;
+        self.hdma_len = r.u8()?;
+        self.wrambank = r.u8()? as usize;
+        if !(1..8).contains(&self.wrambank) {
+            return Err("Save state is invalid");
+        }
+        self.gbmode = r.mode()?;
+        self.gbspeed = match r.bool()? {
+            true => GbSpeed::Double,
+            false => GbSpeed::Single,
+        };
+        self.speed_switch_req = r.bool()?;
+        r.bytes(&mut self.undocumented_cgb_regs)?;
+        if r.bool()? {
+            if self.boot_rom.is_none() {
+                return Err("Save state was made while running the boot ROM");
+            }
+        } else {
+            self.boot_rom = None;
+        }
+        if r.bool()? != self.sgb.is_some() {
+            return Err("Save state was made for another model");
+        }
+        self.timer.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.serial.gbmode = self.gbmode;
+        self.keypad.load_state(r)?;
+        self.gpu.load_state(r)?;
+        // States bring back the colorization they were made with, its palettes
+        // are worked out again from the cartridge
+        if let Some(colorization) = self.gpu.colorization {
+            self.colorize(colorization);
+        }
+        if let Some(ref mut sgb) = self.sgb {
+            sgb.load_state(r)?;
+        }
+        self.mbc.load_state(r)
+    }
+
     fn determine_mode(&mut self) {
         let mode = match self.rb(0x0143) & 0x80 {
             0x80 => GbMode::Color,
@@ -171,6 +287,17 @@ impl<'a> MemoryManagementUnit<'a> {
         };
         self.gbmode = mode;
         self.gpu.gbmode = mode;
+        self.serial.gbmode = mode;
+        if mode == GbMode::ColorAsClassic {
+            self.colorize(Colorization::Auto);
+        }
+    }
+
+    // Does what the CGB boot ROM does for DMG games
+    pub fn colorize(&mut self, colorization: Colorization) {
+        let palettes = colorization.palettes(|a| self.mbc.readrom(a));
+        self.gpu.set_compat_palettes(palettes);
+        self.gpu.colorization = Some(colorization);
     }
 
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
@@ -181,6 +308,7 @@ impl<'a> MemoryManagementUnit<'a> {
         let vramticks = self.perform_vramdma();
         let gputicks = ticks / cpudivider + vramticks;
         let cputicks = ticks + vramticks * cpudivider;
+        self.clock += cputicks as u64;
 
         self.timer.do_cycle(cputicks);
         self.intf |= self.timer.interrupt;
@@ -190,19 +318,58 @@ impl<'a> MemoryManagementUnit<'a> {
         self.keypad.interrupt = 0;
 
         self.gpu.do_cycle(gputicks);
+        let vblank = self.gpu.interrupt & 0x01 != 0;
         self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
+        if let Some(ref mut sgb) = self.sgb {
+            if let Some(ref mut packets) = self.keypad.sgb {
+                for command in packets.received.drain(..) {
+                    sgb.command(&command);
+                }
+            }
+            if vblank {
+                sgb.vblank(&self.gpu);
+            }
+        }
+
         // self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
 
+        self.serial.do_cycle(cputicks);
         self.intf |= self.serial.interrupt;
         self.serial.interrupt = 0;
 
         gputicks
     }
 
+    // `rb` and `wb` are what the CPU uses. DMA and tooling do not go through
+    // them, so hooks only see accesses made by the game's code.
     pub fn rb(&mut self, address: u16) -> u8 {
+        let value = self.peek(address);
+        if self.hooks.active() {
+            self.hooks.read(address, value, self.clock);
+        }
+        value
+    }
+
+    // Whether `address` reads from the boot ROM rather than the cartridge
+    fn in_boot_rom(&self, address: u16) -> bool {
+        match self.boot_rom {
+            // CGB boot ROMs leave the cartridge header at 0x100-0x1FF visible
+            Some(ref boot) => {
+                address < 0x100 || (0x200..0x900).contains(&address) && boot.len() > 0x100
+            }
+            None => false,
+        }
+    }
+
+    // Same mapping as `rb` but without requiring mutable access, so tooling can
+    // inspect memory without disturbing the emulated machine.
+    pub fn peek(&self, address: u16) -> u8 {
         match address {
+            _ if self.in_boot_rom(address) => {
+                self.boot_rom.as_ref().unwrap()[address as usize]
+            }
             0x0000..=0x7FFF => self.mbc.readrom(address),
             0x8000..=0x9FFF => self.gpu.rb(address),
             0xA000..=0xBFFF => self.mbc.readram(address),
@@ -250,6 +417,9 @@ impl<'a> MemoryManagementUnit<'a> {
     }
 
     pub fn wb(&mut self, address: u16, value: u8) {
+        if self.hooks.active() {
+            self.hooks.write(address, value, self.clock);
+        }
         match address {
             0x0000..=0x7FFF => self.mbc.writerom(address, value),
             0x8000..=0x9FFF => self.gpu.wb(address, value),
@@ -267,6 +437,7 @@ impl<'a> MemoryManagementUnit<'a> {
             0xFF10..=0xFF3F => {}
             // self.sound.as_mut().map_or((), |s| s.wb(address, value)),
             0xFF46 => self.oamdma(value),
+            0xFF50 if value != 0 => self.boot_rom = None,
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 | 0xFF76..=0xFF77
                 if self.gbmode != GbMode::Color => {}
             0xFF72..=0xFF73 | 0xFF75..=0xFF77 if self.gbmode == GbMode::Classic => {}
@@ -295,6 +466,90 @@ impl<'a> MemoryManagementUnit<'a> {
         };
     }
 
+    // Writes memory for tooling. Cartridge ROM and RAM are patched directly
+    // instead of being interpreted as MBC register writes or gated by the RAM
+    // enable flag, registers are set without what writing them sets off, and
+    // hooks do not see it.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF => {
+                let offset = self.mbc.rom_offset(address);
+                if let Some(b) = self.mbc.rom_mut().get_mut(offset) {
+                    *b = value;
+                }
+            }
+            0xA000..=0xBFFF => {
+                if let Some(offset) = self.mbc.ram_offset(address) {
+                    if let Some(b) = self.mbc.ram_mut().get_mut(offset) {
+                        *b = value;
+                    }
+                }
+            }
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.gpu.wb(address, value),
+            0xC000..=0xCFFF | 0xE000..=0xEFFF => {
+                self.wram[address as usize & 0x0FFF] = value
+            }
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => {
+                self.wram[(self.wrambank * 0x1000) | (address as usize & 0x0FFF)] = value
+            }
+            0xFF01..=0xFF02 => self.serial.poke(address, value),
+            0xFF04..=0xFF07 => self.timer.poke(address, value),
+            0xFF0F => self.intf = value,
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.gpu.poke(address, value),
+            0xFF68..=0xFF6B if self.gbmode == GbMode::Color => {
+                self.gpu.poke(address, value)
+            }
+            0xFF80..=0xFFFE => self.zram[address as usize & 0x007F] = value,
+            0xFFFF => self.inte = value,
+            // The joypad, DMA, bank switches, the speed switch and the boot
+            // ROM mapping only change through the game's own writes
+            _ => {}
+        }
+    }
+
+    fn bank_offset(bank: MemoryBank, offset: u16) -> Option<usize> {
+        let (n, size) = match bank {
+            MemoryBank::Rom(n) => (n, 0x4000),
+            MemoryBank::Vram(n) => (n, 0x2000),
+            MemoryBank::Wram(n) => (n, 0x1000),
+            MemoryBank::CartRam(n) => (n, 0x2000),
+        };
+        if offset as usize >= size {
+            return None;
+        }
+        Some(n * size + offset as usize)
+    }
+
+    pub fn peek_bank(&self, bank: MemoryBank, offset: u16) -> Option<u8> {
+        let idx = MemoryManagementUnit::bank_offset(bank, offset)?;
+        match bank {
+            MemoryBank::Rom(_) => self.mbc.rom().get(idx).copied(),
+            MemoryBank::Vram(_) => self.gpu.vram.get(idx).copied(),
+            MemoryBank::Wram(_) => self.wram.get(idx).copied(),
+            MemoryBank::CartRam(_) => self.mbc.ram().get(idx).copied(),
+        }
+    }
+
+    pub fn poke_bank(&mut self, bank: MemoryBank, offset: u16, value: u8) -> bool {
+        let idx = match MemoryManagementUnit::bank_offset(bank, offset) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let b = match bank {
+            MemoryBank::Rom(_) => self.mbc.rom_mut().get_mut(idx),
+            MemoryBank::Vram(_) => self.gpu.vram.get_mut(idx),
+            MemoryBank::Wram(_) => self.wram.get_mut(idx),
+            MemoryBank::CartRam(_) => self.mbc.ram_mut().get_mut(idx),
+        };
+        match b {
+            Some(b) => {
+                *b = value;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn ww(&mut self, address: u16, value: u16) {
         self.wb(address, (value & 0xFF) as u8);
         self.wb(address + 1, (value >> 8) as u8);
@@ -314,8 +569,8 @@ impl<'a> MemoryManagementUnit<'a> {
     fn oamdma(&mut self, value: u8) {
         let base = (value as u16) << 8;
         for i in 0..0xA0 {
-            let b = self.rb(base + i);
-            self.wb(0xFE00 + i, b);
+            let b = self.peek(base + i);
+            self.gpu.wb(0xFE00 + i, b);
         }
     }
 
@@ -401,7 +656,7 @@ impl<'a> MemoryManagementUnit<'a> {
     fn perform_vramdma_row(&mut self) {
         let mmu_src = self.hdma_src;
         for j in 0..0x10 {
-            let b: u8 = self.rb(mmu_src + j);
+            let b: u8 = self.peek(mmu_src + j);
             self.gpu.wb(self.hdma_dst + j, b);
         }
         self.hdma_src += 0x10;
@@ -414,3 +669,120 @@ impl<'a> MemoryManagementUnit<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MemoryManagementUnit;
+    use crate::gameboy::{Gameboy, MemoryBank, Target};
+
+    // MBC3 with an RTC, 4 RAM banks and the bank number at the start of each
+    // of its 8 ROM banks
+    fn mbc3() -> MemoryManagementUnit<'static> {
+        let mut rom = vec![0; 0x20000];
+        for bank in 0..8 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x03;
+        MemoryManagementUnit::new_cgb(rom, None).unwrap()
+    }
+
+    #[test]
+    fn poke_bypasses_the_mbc() {
+        let mut mmu = mbc3();
+        mmu.poke(0x2000, 5);
+        assert_eq!(mmu.peek(0x2000), 5);
+        assert_eq!(mmu.peek(0x4000), 1);
+        mmu.poke(0x4001, 0xAA);
+        assert_eq!(mmu.peek_bank(MemoryBank::Rom(1), 1), Some(0xAA));
+
+        assert_eq!(mmu.peek_bank(MemoryBank::Rom(5), 0), Some(5));
+        assert!(mmu.poke_bank(MemoryBank::Rom(5), 0x123, 7));
+        assert_eq!(mmu.peek_bank(MemoryBank::Rom(5), 0x123), Some(7));
+        assert_eq!(mmu.peek_bank(MemoryBank::Rom(8), 0), None);
+        assert_eq!(mmu.peek_bank(MemoryBank::Rom(0), 0x4000), None);
+
+        // Cartridge RAM the game switched to bank 2, poked while disabled
+        mmu.wb(0x4000, 0x02);
+        mmu.poke(0xA010, 0x42);
+        assert_eq!(mmu.peek_bank(MemoryBank::CartRam(2), 0x10), Some(0x42));
+        assert!(mmu.poke_bank(MemoryBank::CartRam(3), 0x1FFF, 9));
+        assert_eq!(mmu.peek_bank(MemoryBank::CartRam(3), 0x1FFF), Some(9));
+        assert!(!mmu.poke_bank(MemoryBank::CartRam(4), 0, 9));
+    }
+
+    #[test]
+    fn rtc_registers_are_left_alone() {
+        let mut mmu = mbc3();
+        mmu.wb(0x0000, 0x0A);
+        mmu.wb(0x4000, 0x08);
+        mmu.wb(0x6000, 0x01);
+        let seconds = mmu.peek(0xA000);
+        let ram = mmu.mbc.ram().to_vec();
+
+        // Neither latched again by a poke of the latch register, nor written
+        mmu.poke(0x6000, 0x01);
+        mmu.poke(0xA000, seconds.wrapping_add(1));
+        assert_eq!(mmu.peek(0xA000), seconds);
+        assert_eq!(mmu.peek(0x6000), 0x01);
+        assert_eq!(mmu.mbc.ram(), &ram[..]);
+    }
+
+    #[test]
+    fn poke_sets_registers_without_side_effects() {
+        let mut mmu = mbc3();
+        mmu.wb(0xC000, 0x12);
+        mmu.poke(0xFF46, 0xC0);
+        assert_eq!(mmu.peek(0xFE00), 0);
+
+        mmu.poke(0xFF70, 3);
+        mmu.poke(0xD000, 1);
+        assert_eq!(mmu.peek_bank(MemoryBank::Wram(1), 0), Some(1));
+        mmu.poke(0xFF4F, 1);
+        mmu.poke(0x8000, 9);
+        assert_eq!(mmu.peek_bank(MemoryBank::Vram(0), 0), Some(9));
+
+        mmu.poke(0xFF04, 0x40);
+        assert_eq!(mmu.peek(0xFF04), 0x40);
+        mmu.poke(0xFF05, 0x80);
+        assert_eq!(mmu.peek(0xFF05), 0x80);
+        mmu.poke(0xFF02, 0x81);
+        assert_eq!(mmu.peek(0xFF02) & 0x80, 0);
+        mmu.poke(0xFF80, 0x34);
+        assert_eq!(mmu.peek(0xFF80), 0x34);
+    }
+
+    #[test]
+    fn boot_rom_unmaps_on_ff50() {
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0x12;
+        let mut gb = Gameboy::new(rom, None);
+        let mut boot = vec![0; 0x100];
+        // ld a, 1; ldh (50), a
+        boot[..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        gb.load_boot_rom(boot).unwrap();
+
+        assert_eq!(gb.registers().pc, 0);
+        assert_eq!(gb.peek(0x0000), 0x3E);
+        assert_eq!(gb.peek(0x0100), 0x00);
+        gb.step();
+        gb.step();
+        assert_eq!(gb.peek(0x0000), 0x12);
+        assert!(gb.load_boot_rom(vec![0; 10]).is_err());
+    }
+
+    #[test]
+    fn unsupported_cartridges_are_errors() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0xC0;
+        assert!(
+            Gameboy::try_with_target(rom.clone(), None, Target::GameBoyColor).is_ok()
+        );
+        assert_eq!(
+            Gameboy::try_with_target(rom.clone(), None, Target::GameBoy).err(),
+            Some("This game does not work in Classic mode")
+        );
+        rom[0x147] = 0xFC;
+        assert!(Gameboy::try_with_target(rom, None, Target::GameBoyColor).is_err());
+    }
+}
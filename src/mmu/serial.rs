@@ -1,22 +1,85 @@
-pub type SerialCallback<'a> = Box<dyn FnMut(u8) -> Option<u8> + Send + 'a>;
+use crate::mmu::StrResult;
+use crate::mode::GbMode;
+use crate::state::{StateReader, StateWriter};
+use std::sync::{Arc, Mutex};
 
-fn noop(_: u8) -> Option<u8> {
-    None
+// Something plugged into the link port. Transfers are exchanged a byte at a
+// time, the bits are then shifted into SB at the speed of the clock.
+pub trait SerialDevice: Send {
+    // This side drives the clock: `out` is sent and the byte shifted in from
+    // the other end is returned. 0xFF is what an unconnected port reads.
+    fn transfer(&mut self, out: u8) -> u8;
+
+    // This side waits for an external clock with `out` loaded in SB. Returns
+    // the received byte once the other end has clocked a transfer.
+    fn external(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+
+    // Called with the CPU ticks elapsed on every step, transfer or not, so
+    // devices can keep their own notion of time in sync with the emulator.
+    fn tick(&mut self, _ticks: u32) {}
+
+    // Why the device stopped working, reported once for frontends to show.
+    fn take_error(&mut self) -> Option<String> {
+        None
+    }
+}
+
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _out: u8) -> u8 {
+        0xFF
+    }
+}
+
+// Records every byte sent while this side drives the clock, which is how test
+// ROMs report their results.
+#[derive(Default, Clone)]
+pub struct SerialCapture {
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SerialCapture {
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.lock().unwrap()).into_owned()
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.output.lock().unwrap().push(out);
+        0xFF
+    }
 }
 
+// CPU ticks per bit: 8192 Hz with the normal clock, 262144 Hz with the CGB
+// fast clock. Both double along with the CPU in double speed mode.
+const SLOW_BIT_TICKS: u32 = 512;
+const FAST_BIT_TICKS: u32 = 16;
+
 pub struct Serial<'a> {
     data: u8,
     control: u8,
-    callback: SerialCallback<'a>,
+    device: Box<dyn SerialDevice + 'a>,
+    incoming: u8,
+    bits: u8,
+    clock: u32,
+    pub gbmode: GbMode,
     pub interrupt: u8,
 }
 
 impl<'a> Serial<'a> {
-    pub fn new_with_callback(cb: SerialCallback<'a>) -> Serial<'a> {
+    pub fn new_with_device(device: Box<dyn SerialDevice + 'a>) -> Serial<'a> {
         Serial {
             data: 0,
             control: 0,
-            callback: cb,
+            device,
+            incoming: 0,
+            bits: 0,
+            clock: 0,
+            gbmode: GbMode::Classic,
             interrupt: 0,
         }
     }
@@ -26,41 +89,175 @@ impl<'a> Serial<'a> {
             0xFF01 => self.data = v,
             0xFF02 => {
                 self.control = v;
+                self.bits = 0;
+                self.clock = 0;
                 if v & 0x81 == 0x81 {
-                    if let Some(v) = (self.callback)(self.data) {
-                        self.data = v;
-                        self.interrupt = 0x8
-                    }
+                    self.incoming = self.device.transfer(self.data);
+                    self.bits = 8;
                 }
             }
             _ => panic!("Serial does not handle address {:4X} (write)", a),
         };
     }
 
+    // Sets a register for tooling, without starting or stopping a transfer
+    pub fn poke(&mut self, a: u16, v: u8) {
+        match a {
+            0xFF01 => self.data = v,
+            0xFF02 => self.control = (v & 0x7F) | (self.control & 0x80),
+            _ => panic!("Serial does not handle address {:4X} (poke)", a),
+        }
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF01 => self.data,
-            0xFF02 => self.control | 0b01111110,
+            0xFF02 => match self.gbmode {
+                GbMode::Color => self.control | 0b01111100,
+                _ => self.control | 0b01111110,
+            },
             _ => panic!("Serial does not handle address {:4X} (read)", a),
         }
     }
 
-    pub fn set_callback(&mut self, cb: SerialCallback<'static>) {
-        self.callback = cb;
+    pub fn do_cycle(&mut self, ticks: u32) {
+        self.device.tick(ticks);
+        if self.control & 0x80 == 0 {
+            return;
+        }
+
+        // The external clock is assumed to run at the normal 8192 Hz, which is
+        // what every master drives it with unless both ends are in CGB fast mode.
+        let internal = self.control & 0x01 != 0;
+        if !internal && self.bits == 0 {
+            match self.device.external(self.data) {
+                Some(v) => {
+                    self.incoming = v;
+                    self.bits = 8;
+                    self.clock = 0;
+                }
+                None => return,
+            }
+        }
+
+        let period =
+            if internal && self.gbmode == GbMode::Color && self.control & 0x02 != 0 {
+                FAST_BIT_TICKS
+            } else {
+                SLOW_BIT_TICKS
+            };
+        self.clock += ticks;
+        while self.clock >= period && self.bits > 0 {
+            self.clock -= period;
+            self.bits -= 1;
+            self.data = (self.data << 1) | ((self.incoming >> self.bits) & 1);
+            if self.bits == 0 {
+                self.finish();
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.control &= 0x7F;
+        self.bits = 0;
+        self.clock = 0;
+        self.interrupt = 0x8;
+    }
+
+    // The device itself is not part of the state, only the transfer in flight.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u8(self.incoming);
+        w.u8(self.bits);
+        w.u32(self.clock);
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.data = r.u8()?;
+        self.control = r.u8()?;
+        self.incoming = r.u8()?;
+        self.bits = r.u8()?;
+        self.clock = r.u32()?;
+        self.interrupt = r.u8()?;
+        Ok(())
     }
 
-    pub fn unset_callback(&mut self) {
-        self.callback = Box::new(noop);
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice + 'a>) {
+        self.device = device;
+    }
+
+    pub fn unset_device(&mut self) {
+        self.device = Box::new(Disconnected);
+    }
+
+    pub fn take_device_error(&mut self) -> Option<String> {
+        self.device.take_error()
     }
 }
 
 impl Default for Serial<'static> {
     fn default() -> Serial<'static> {
-        Serial {
-            data: 0,
-            control: 0,
-            callback: Box::new(noop),
-            interrupt: 0,
+        Serial::new_with_device(Box::new(Disconnected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Serial, SerialDevice};
+    use crate::gameboy::Gameboy;
+
+    struct Echo;
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, out: u8) -> u8 {
+            !out
         }
     }
+
+    #[test]
+    fn internal_clock_shifts_bits() {
+        let mut serial = Serial::new_with_device(Box::new(Echo));
+        serial.wb(0xFF01, 0b1010_0000);
+        serial.wb(0xFF02, 0x81);
+
+        serial.do_cycle(512 * 4);
+        assert_eq!(serial.rb(0xFF01), 0b0000_0101);
+        assert_eq!(serial.interrupt, 0);
+
+        serial.do_cycle(512 * 4);
+        assert_eq!(serial.rb(0xFF01), 0b0101_1111);
+        assert_eq!(serial.interrupt, 0x8);
+        assert_eq!(serial.rb(0xFF02) & 0x80, 0);
+    }
+
+    #[test]
+    fn external_clock_waits_for_peer() {
+        let mut serial = Serial::default();
+        serial.wb(0xFF01, 0x12);
+        serial.wb(0xFF02, 0x80);
+        serial.do_cycle(512 * 16);
+        assert_eq!(serial.interrupt, 0);
+        assert_eq!(serial.rb(0xFF02) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn captures_test_rom_output() {
+        let mut rom = vec![0; 0x8000];
+        let mut pc = 0x100;
+        for &c in b"Passed" {
+            // ld a, c; ldh (01), a; ld a, 81; ldh (02), a
+            let code = [0x3E, c, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02];
+            rom[pc..pc + code.len()].copy_from_slice(&code);
+            pc += code.len();
+        }
+        // jr -2
+        rom[pc..pc + 2].copy_from_slice(&[0x18, 0xFE]);
+
+        let mut gb = Gameboy::new(rom, None);
+        let found = gb.run_until_serial_contains(&["Passed", "Failed"], 10);
+        assert_eq!(found, Some("Passed"));
+        assert_eq!(gb.serial_output(), "Passed");
+    }
 }
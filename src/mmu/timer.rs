@@ -1,44 +1,30 @@
+use crate::mmu::StrResult;
+use crate::state::{StateReader, StateWriter};
+
+// DIV and TIMA are both driven by one 16-bit system counter. TIMA increments
+// on the falling edge of the counter bit selected by TAC (ANDed with the
+// enable bit), so writes to DIV or TAC can also produce an increment.
+#[derive(Default)]
 pub struct Timer {
-    divider: u8,
-    counter: u8,
-    modulo: u8,
-    enabled: bool,
-    step: u32,
-    internalcnt: u32,
-    internaldiv: u32,
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed and reads as 0 until it is reloaded on the next M-cycle
+    overflow: bool,
+    // M-cycle in which TMA is copied into TIMA, writes to TIMA are ignored here
+    reloading: bool,
+    leftover: u32,
     pub interrupt: u8,
 }
 
-impl Default for Timer {
-    fn default() -> Self {
-        Timer {
-            divider: 0,
-            counter: 0,
-            modulo: 0,
-            enabled: false,
-            step: 256,
-            internalcnt: 0,
-            internaldiv: 0,
-            interrupt: 0,
-        }
-    }
-}
-
 impl Timer {
     pub fn rb(&self, a: u16) -> u8 {
         match a {
-            0xFF04 => self.divider,
-            0xFF05 => self.counter,
-            0xFF06 => self.modulo,
-            0xFF07 => {
-                0xF8 | (if self.enabled { 0x4 } else { 0 })
-                    | (match self.step {
-                        16 => 1,
-                        64 => 2,
-                        256 => 3,
-                        _ => 0,
-                    })
-            }
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
             _ => panic!("Timer does not handler read {:4X}", a),
         }
     }
@@ -46,45 +32,177 @@ impl Timer {
     pub fn wb(&mut self, a: u16, v: u8) {
         match a {
             0xFF04 => {
-                self.divider = 0;
+                let old = self.signal();
+                self.counter = 0;
+                self.detect_edge(old);
             }
             0xFF05 => {
-                self.counter = v;
+                if !self.reloading {
+                    self.tima = v;
+                    self.overflow = false;
+                }
             }
             0xFF06 => {
-                self.modulo = v;
+                self.tma = v;
+                if self.reloading {
+                    self.tima = v;
+                }
             }
             0xFF07 => {
-                self.enabled = v & 0x4 != 0;
-                self.step = match v & 0x3 {
-                    1 => 16,
-                    2 => 64,
-                    3 => 256,
-                    _ => 1024,
-                };
+                let old = self.signal();
+                self.tac = v & 0x07;
+                self.detect_edge(old);
             }
             _ => panic!("Timer does not handler write {:4X}", a),
         };
     }
 
+    // Sets a register for tooling, without resetting the counter or producing
+    // the increments that writes from the game can
+    pub fn poke(&mut self, a: u16, v: u8) {
+        match a {
+            0xFF04 => self.counter = (v as u16) << 8 | (self.counter & 0xFF),
+            0xFF05 => self.tima = v,
+            0xFF06 => self.tma = v,
+            0xFF07 => self.tac = v & 0x07,
+            _ => panic!("Timer does not handle poke {:4X}", a),
+        }
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
-        self.internaldiv += ticks;
-        while self.internaldiv >= 256 {
-            self.divider = self.divider.wrapping_add(1);
-            self.internaldiv -= 256;
+        self.leftover += ticks;
+        while self.leftover >= 4 {
+            self.leftover -= 4;
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            self.interrupt |= 0x04;
         }
 
-        if self.enabled {
-            self.internalcnt += ticks;
+        let old = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_edge(old);
+    }
 
-            while self.internalcnt >= self.step {
-                self.counter = self.counter.wrapping_add(1);
-                if self.counter == 0 {
-                    self.counter = self.modulo;
-                    self.interrupt |= 0x04;
-                }
-                self.internalcnt -= self.step;
-            }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.bool(self.overflow);
+        w.bool(self.reloading);
+        w.u32(self.leftover);
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.counter = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.overflow = r.bool()?;
+        self.reloading = r.bool()?;
+        self.leftover = r.u32()?;
+        self.interrupt = r.u8()?;
+        Ok(())
+    }
+
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            1 => 3,
+            2 => 5,
+            3 => 7,
+            _ => 9,
+        };
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn detect_edge(&mut self, old: bool) {
+        if old && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow |= overflow;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Timer;
+
+    #[test]
+    fn tima_follows_selected_bit() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05); // enabled, every 16 clocks
+        timer.do_cycle(16 * 10);
+        assert_eq!(timer.rb(0xFF05), 10);
+        assert_eq!(timer.rb(0xFF04), 0);
+        timer.do_cycle(256 - 16 * 10);
+        assert_eq!(timer.rb(0xFF04), 1);
+    }
+
+    #[test]
+    fn div_reset_causes_increment() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(8); // bit 3 is now set
+        timer.wb(0xFF04, 0);
+        assert_eq!(timer.rb(0xFF05), 1);
+    }
+
+    #[test]
+    fn tac_disable_causes_increment() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(8);
+        timer.wb(0xFF07, 0x01);
+        assert_eq!(timer.rb(0xFF05), 1);
+    }
+
+    #[test]
+    fn overflow_reloads_after_delay() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0xAB);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(16);
+        assert_eq!(timer.rb(0xFF05), 0);
+        assert_eq!(timer.interrupt, 0);
+        timer.do_cycle(4);
+        assert_eq!(timer.rb(0xFF05), 0xAB);
+        assert_eq!(timer.interrupt, 0x04);
+    }
+
+    #[test]
+    fn tima_write_cancels_pending_reload() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0xAB);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(16);
+        timer.wb(0xFF05, 0x42);
+        timer.do_cycle(4);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+        assert_eq!(timer.interrupt, 0);
+    }
+
+    #[test]
+    fn tima_write_ignored_while_reloading() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0xAB);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(20);
+        timer.wb(0xFF05, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0xAB);
+        timer.wb(0xFF06, 0x10);
+        assert_eq!(timer.rb(0xFF05), 0x10);
+    }
+}
@@ -0,0 +1,278 @@
+// ROM patches in the IPS and BPS formats, as used for translations and hacks.
+
+pub type PatchResult = Result<Vec<u8>, &'static str>;
+
+// 512 banks of 16KB, the most an MBC5 can address
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> PatchResult {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err("Unknown patch format")
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> PatchResult {
+    const TRUNCATED: &str = "IPS patch is truncated";
+
+    let mut out = rom.to_vec();
+    let mut i = 5;
+    loop {
+        let record = patch.get(i..i + 3).ok_or(TRUNCATED)?;
+        if record == b"EOF" {
+            // Some patchers append the size the ROM has to be truncated to
+            if let Some(size) = patch.get(i + 3..i + 6) {
+                let size =
+                    (size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize;
+                out.truncate(size);
+            }
+            return Ok(out);
+        }
+
+        let offset =
+            (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+        let size = patch.get(i + 3..i + 5).ok_or(TRUNCATED)?;
+        let size = (size[0] as usize) << 8 | size[1] as usize;
+        i += 5;
+
+        // A zero size introduces a run of a single repeated byte
+        let data = if size == 0 {
+            let run = patch.get(i..i + 3).ok_or(TRUNCATED)?;
+            i += 3;
+            vec![run[2]; (run[0] as usize) << 8 | run[1] as usize]
+        } else {
+            let data = patch.get(i..i + size).ok_or(TRUNCATED)?.to_vec();
+            i += size;
+            data
+        };
+
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+}
+
+struct BpsReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BpsReader<'_> {
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        let v = *self.data.get(self.pos).ok_or("BPS patch is truncated")?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn number(&mut self) -> Result<usize, &'static str> {
+        let mut data = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let x = self.byte()? as usize;
+            data = data
+                .checked_add(
+                    (x & 0x7F)
+                        .checked_mul(shift)
+                        .ok_or("BPS number overflows")?,
+                )
+                .ok_or("BPS number overflows")?;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_shl(7).ok_or("BPS number overflows")?;
+            data = data.checked_add(shift).ok_or("BPS number overflows")?;
+        }
+    }
+
+    fn offset(&mut self, base: &mut usize) -> Result<(), &'static str> {
+        let v = self.number()?;
+        let delta = v >> 1;
+        *base = if v & 1 != 0 {
+            base.checked_sub(delta)
+        } else {
+            base.checked_add(delta)
+        }
+        .ok_or("BPS copy offset is out of range")?;
+        Ok(())
+    }
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> PatchResult {
+    if patch.len() < 4 + 12 {
+        return Err("BPS patch is truncated");
+    }
+    let footer = patch.len() - 12;
+    let crc = |i: usize| u32::from_le_bytes(patch[i..i + 4].try_into().unwrap());
+    if crc32(&patch[..footer + 8]) != crc(footer + 8) {
+        return Err("BPS patch is corrupted");
+    }
+    if crc32(rom) != crc(footer) {
+        return Err("BPS patch was made for a different ROM");
+    }
+
+    let mut r = BpsReader {
+        data: &patch[..footer],
+        pos: 4,
+    };
+    let source_size = r.number()?;
+    let target_size = r.number()?;
+    let metadata = r.number()?;
+    r.pos = r
+        .pos
+        .checked_add(metadata)
+        .filter(|&end| end <= footer)
+        .ok_or("BPS patch is truncated")?;
+    if source_size != rom.len() {
+        return Err("BPS patch was made for a different ROM");
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err("BPS patch makes the ROM too large");
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while r.pos < footer {
+        let action = r.number()?;
+        let len = (action >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err("BPS patch produced an invalid ROM");
+        }
+        match action & 3 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(
+                    rom.get(start..start + len).ok_or("BPS read past ROM")?,
+                )
+            }
+            // TargetRead
+            1 => {
+                for _ in 0..len {
+                    out.push(r.byte()?);
+                }
+            }
+            // SourceCopy
+            2 => {
+                r.offset(&mut source_offset)?;
+                let data = rom
+                    .get(source_offset..source_offset + len)
+                    .ok_or("BPS copy past ROM")?;
+                out.extend_from_slice(data);
+                source_offset += len;
+            }
+            // TargetCopy, byte by byte as the ranges may overlap
+            _ => {
+                r.offset(&mut target_offset)?;
+                for _ in 0..len {
+                    let v = *out.get(target_offset).ok_or("BPS copy past output")?;
+                    out.push(v);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size || crc32(&out) != crc(footer + 4) {
+        return Err("BPS patch produced an invalid ROM");
+    }
+    Ok(out)
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, crc32};
+
+    #[test]
+    fn applies_ips_records_and_runs() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+
+        let rom = apply(&[0; 6], &patch).unwrap();
+        assert_eq!(rom, vec![0x00, 0xAA, 0xBB, 0x00, 0x00, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn applies_bps() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let source = b"hello";
+        let target = b"hello world";
+        // Sizes 5 and 11, no metadata, SourceRead 5 then TargetRead 6
+        let mut patch = b"BPS1".to_vec();
+        patch.extend_from_slice(&[0x85, 0x8B, 0x80, 0x90, 0x95]);
+        patch.extend_from_slice(b" world");
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(apply(source, &patch).unwrap(), target.to_vec());
+        assert!(apply(b"jello", &patch).is_err());
+    }
+
+    fn number(mut v: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (v & 0x7F) as u8;
+            v >>= 7;
+            if v == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            v -= 1;
+        }
+    }
+
+    fn bps(source: &[u8], numbers: &[usize]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        for &n in numbers {
+            patch.extend_from_slice(&number(n));
+        }
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&[0; 4]);
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn rejects_oversized_bps() {
+        let source = b"hello";
+        assert_eq!(
+            apply(source, &bps(source, &[5, 1 << 40, 0])),
+            Err("BPS patch makes the ROM too large")
+        );
+        assert_eq!(
+            apply(source, &bps(source, &[5, 5, usize::MAX >> 8])),
+            Err("BPS patch is truncated")
+        );
+        // SourceRead 1, then a TargetCopy far longer than the target
+        assert_eq!(
+            apply(source, &bps(source, &[5, 5, 0, 0, (1 << 40) << 2 | 3, 0])),
+            Err("BPS patch produced an invalid ROM")
+        );
+    }
+}
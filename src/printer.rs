@@ -0,0 +1,348 @@
+use crate::gameboy::SerialDevice;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_BREAK: u8 = 0x08;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// Number of status inquiries answered with "busy" after a print command
+const PRINT_POLLS: u8 = 8;
+
+pub const WIDTH: usize = 160;
+// The printer RAM holds 9 bands of 2 tile rows each
+const MAX_BYTES: usize = 640 * 9;
+
+// Default shades, from white to black
+pub const GRAYSCALE: [[u8; 4]; 4] = [
+    [255, 255, 255, 255],
+    [192, 192, 192, 255],
+    [96, 96, 96, 255],
+    [0, 0, 0, 255],
+];
+
+#[derive(Debug, Clone)]
+pub struct PrintedImage {
+    pub width: u32,
+    pub height: u32,
+    // RGBA pixels
+    pub data: Vec<u8>,
+}
+
+impl PrintedImage {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> image::ImageResult<()> {
+        image::save_buffer(
+            path,
+            &self.data,
+            self.width,
+            self.height,
+            image::ExtendedColorType::Rgba8,
+        )
+    }
+}
+
+pub type PrintCallback<'a> = Box<dyn FnMut(PrintedImage) + Send + 'a>;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    Alive,
+    Status,
+}
+
+// Game Boy Printer attached to the link port. Pages are accumulated until a
+// print command feeds paper after them, then delivered to the callback.
+pub struct Printer<'a> {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy: u8,
+    buffer: Vec<u8>,
+    page: Vec<u8>,
+    palette: [[u8; 4]; 4],
+    callback: PrintCallback<'a>,
+}
+
+impl<'a> Printer<'a> {
+    pub fn new(callback: PrintCallback<'a>) -> Printer<'a> {
+        Printer::with_palette(GRAYSCALE, callback)
+    }
+
+    pub fn with_palette(
+        palette: [[u8; 4]; 4],
+        callback: PrintCallback<'a>,
+    ) -> Printer<'a> {
+        Printer {
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            palette,
+            callback,
+        }
+    }
+
+    fn receive(&mut self, v: u8) -> u8 {
+        match self.state {
+            State::Magic(i) => {
+                self.state = if v == MAGIC[i] {
+                    if i == 0 {
+                        State::Magic(1)
+                    } else {
+                        State::Command
+                    }
+                } else {
+                    State::Magic(0)
+                };
+            }
+            State::Command => {
+                self.command = v;
+                self.checksum = v as u16;
+                self.state = State::Compression;
+            }
+            State::Compression => {
+                self.compressed = v & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                self.state = State::Length(0);
+            }
+            State::Length(i) => {
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                if i == 0 {
+                    self.length = v as u16;
+                    self.state = State::Length(1);
+                } else {
+                    self.length |= (v as u16) << 8;
+                    self.packet.clear();
+                    self.state = if self.length == 0 {
+                        State::Checksum(0)
+                    } else {
+                        State::Data
+                    };
+                }
+            }
+            State::Data => {
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                self.packet.push(v);
+                if self.packet.len() == self.length as usize {
+                    self.state = State::Checksum(0);
+                }
+            }
+            State::Checksum(i) => {
+                if i == 0 {
+                    self.received_checksum = v as u16;
+                    self.state = State::Checksum(1);
+                } else {
+                    self.received_checksum |= (v as u16) << 8;
+                    self.state = State::Alive;
+                }
+            }
+            State::Alive => {
+                self.state = State::Status;
+                return 0x81;
+            }
+            State::Status => {
+                self.state = State::Magic(0);
+                self.process();
+                return self.status;
+            }
+        }
+        0x00
+    }
+
+    fn process(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM;
+
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.busy = 0;
+                self.status = 0;
+            }
+            CMD_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    std::mem::take(&mut self.packet)
+                };
+                let room = MAX_BYTES - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(room));
+                if self.buffer.len() == MAX_BYTES {
+                    self.status |= STATUS_FULL;
+                }
+                self.status |= STATUS_UNPROCESSED;
+            }
+            CMD_PRINT if self.packet.len() >= 4 => {
+                let margins = self.packet[1];
+                let palette = match self.packet[2] {
+                    0 => 0xE4,
+                    p => p,
+                };
+                self.print(palette);
+                if margins & 0x0F != 0 {
+                    self.feed();
+                }
+                self.busy = PRINT_POLLS;
+                self.status =
+                    (self.status & !STATUS_UNPROCESSED) | STATUS_BUSY | STATUS_FULL;
+            }
+            CMD_BREAK => {
+                self.buffer.clear();
+                self.busy = 0;
+                self.status = 0;
+            }
+            CMD_STATUS if self.busy > 0 => {
+                self.busy -= 1;
+                if self.busy == 0 {
+                    self.status &= !(STATUS_BUSY | STATUS_FULL);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        let rows = self.buffer.len() / (WIDTH / 8 * 16);
+        for tiley in 0..rows {
+            for y in 0..8 {
+                for tilex in 0..WIDTH / 8 {
+                    let tile = (tiley * (WIDTH / 8) + tilex) * 16;
+                    let b1 = self.buffer[tile + y * 2];
+                    let b2 = self.buffer[tile + y * 2 + 1];
+                    for x in 0..8 {
+                        let bit = 7 - x;
+                        let colnr = ((b1 >> bit) & 1) | (((b2 >> bit) & 1) << 1);
+                        let shade = (palette >> (colnr * 2)) & 0x03;
+                        self.page.extend_from_slice(&self.palette[shade as usize]);
+                    }
+                }
+            }
+        }
+        self.buffer.clear();
+    }
+
+    fn feed(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        let data = std::mem::take(&mut self.page);
+        let height = (data.len() / 4 / WIDTH) as u32;
+        (self.callback)(PrintedImage {
+            width: WIDTH as u32,
+            height,
+            data,
+        });
+    }
+}
+
+impl SerialDevice for Printer<'_> {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.receive(out)
+    }
+}
+
+// A control byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+// otherwise the next n + 1 bytes are copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let len = (control & 0x7F) as usize + 2;
+            if let Some(&v) = data.get(i) {
+                out.extend(std::iter::repeat(v).take(len));
+            }
+            i += 1;
+        } else {
+            let len = control as usize + 1;
+            let end = (i + len).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PrintedImage, Printer};
+    use std::sync::{Arc, Mutex};
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x88, 0x33, command, compressed as u8];
+        bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        let checksum = bytes[2..]
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes
+    }
+
+    fn send(printer: &mut Printer, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|&b| printer.receive(b)).collect()
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        let data = [0x81, 0xAA, 0x01, 0x10, 0x20];
+        assert_eq!(super::decompress(&data), vec![0xAA, 0xAA, 0xAA, 0x10, 0x20]);
+    }
+
+    #[test]
+    fn prints_black_band() {
+        let pages: Arc<Mutex<Vec<PrintedImage>>> = Arc::new(Mutex::new(Vec::new()));
+        let output = pages.clone();
+        let mut printer =
+            Printer::new(Box::new(move |image| output.lock().unwrap().push(image)));
+
+        let reply = send(&mut printer, &packet(0x01, false, &[]));
+        assert_eq!(&reply[reply.len() - 2..], &[0x81, 0x00]);
+
+        // One band (640 bytes) of color 3 pixels as RLE runs of 129 + 124 bytes
+        let band = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF];
+        let reply = send(&mut printer, &packet(0x04, true, &band));
+        assert_eq!(reply.last(), Some(&0x08));
+        send(&mut printer, &packet(0x04, false, &[]));
+
+        let reply = send(&mut printer, &packet(0x02, false, &[1, 0x13, 0xE4, 0x40]));
+        assert_eq!(reply.last().unwrap() & 0x02, 0x02);
+
+        let pages = pages.lock().unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].width, 160);
+        assert_eq!(pages[0].height, 16);
+        assert_eq!(&pages[0].data[..4], &[0, 0, 0, 255]);
+    }
+}
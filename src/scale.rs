@@ -0,0 +1,492 @@
+// Pixel art upscalers working on RGBA images, for the frontends that cannot
+// run shaders and for screenshots. Each one multiplies both dimensions by
+// `Scaler::factor`, pixels past the borders repeat the edge.
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum Scaler {
+    // Every pixel copied as is
+    #[default]
+    Nearest,
+    // Also known as EPX
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Hq3x,
+    Xbr2x,
+}
+
+type Pixel = [u8; 4];
+
+impl Scaler {
+    pub const ALL: [Scaler; 6] = [
+        Scaler::Nearest,
+        Scaler::Scale2x,
+        Scaler::Scale3x,
+        Scaler::Hq2x,
+        Scaler::Hq3x,
+        Scaler::Xbr2x,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scaler::Nearest => "nearest",
+            Scaler::Scale2x => "scale2x",
+            Scaler::Scale3x => "scale3x",
+            Scaler::Hq2x => "hq2x",
+            Scaler::Hq3x => "hq3x",
+            Scaler::Xbr2x => "xbr2x",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scaler> {
+        if name.eq_ignore_ascii_case("epx") {
+            return Some(Scaler::Scale2x);
+        }
+        Scaler::ALL
+            .into_iter()
+            .find(|s| s.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn factor(self) -> u32 {
+        match self {
+            Scaler::Nearest => 1,
+            Scaler::Scale2x | Scaler::Hq2x | Scaler::Xbr2x => 2,
+            Scaler::Scale3x | Scaler::Hq3x => 3,
+        }
+    }
+
+    // Returns an image `factor` times wider and taller than `image`
+    pub fn upscale(self, image: &[u8], width: u32, height: u32) -> Vec<u8> {
+        let factor = self.factor() as usize;
+        let (width, height) = (width as usize, height as usize);
+        let out_width = width * factor;
+        let mut out = vec![0; out_width * height * factor * 4];
+
+        let get = |x: isize, y: isize| -> Pixel {
+            let x = x.clamp(0, width as isize - 1) as usize;
+            let y = y.clamp(0, height as isize - 1) as usize;
+            let i = (y * width + x) * 4;
+            [image[i], image[i + 1], image[i + 2], image[i + 3]]
+        };
+
+        let mut block = vec![[0; 4]; factor * factor];
+        for y in 0..height {
+            for x in 0..width {
+                let (x, y) = (x as isize, y as isize);
+                let at = |dx: isize, dy: isize| get(x + dx, y + dy);
+                match self {
+                    Scaler::Nearest => block[0] = at(0, 0),
+                    Scaler::Scale2x => scale2x(&at, &mut block),
+                    Scaler::Scale3x => scale3x(&at, &mut block),
+                    Scaler::Hq2x | Scaler::Hq3x => hqx(&at, factor, &mut block),
+                    Scaler::Xbr2x => xbr2x(&at, &mut block),
+                }
+                for (i, pixel) in block.iter().enumerate() {
+                    let (ox, oy) = (
+                        x as usize * factor + i % factor,
+                        y as usize * factor + i / factor,
+                    );
+                    let o = (oy * out_width + ox) * 4;
+                    out[o..o + 4].copy_from_slice(pixel);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn scale2x(at: &impl Fn(isize, isize) -> Pixel, out: &mut [Pixel]) {
+    let p = at(0, 0);
+    let (a, b, c, d) = (at(0, -1), at(1, 0), at(-1, 0), at(0, 1));
+    out[0] = if c == a && c != d && a != b { a } else { p };
+    out[1] = if a == b && a != c && b != d { b } else { p };
+    out[2] = if d == c && d != b && c != a { c } else { p };
+    out[3] = if b == d && b != a && d != c { d } else { p };
+}
+
+fn scale3x(at: &impl Fn(isize, isize) -> Pixel, out: &mut [Pixel]) {
+    let [a, b, c, d, e, f, g, h, i] = neighbours(at);
+    out.fill(e);
+    if b == h || d == f {
+        return;
+    }
+    out[0] = if d == b { d } else { e };
+    out[1] = if (d == b && e != c) || (b == f && e != a) {
+        b
+    } else {
+        e
+    };
+    out[2] = if b == f { f } else { e };
+    out[3] = if (d == b && e != g) || (d == h && e != a) {
+        d
+    } else {
+        e
+    };
+    out[5] = if (b == f && e != i) || (h == f && e != c) {
+        f
+    } else {
+        e
+    };
+    out[6] = if d == h { d } else { e };
+    out[7] = if (d == h && e != i) || (h == f && e != g) {
+        h
+    } else {
+        e
+    };
+    out[8] = if h == f { f } else { e };
+}
+
+// The 3x3 block around a pixel, row by row
+fn neighbours(at: &impl Fn(isize, isize) -> Pixel) -> [Pixel; 9] {
+    let mut n = [[0; 4]; 9];
+    for (i, pixel) in n.iter_mut().enumerate() {
+        *pixel = at(i as isize % 3 - 1, i as isize / 3 - 1);
+    }
+    n
+}
+
+fn yuv([r, g, b, _]: Pixel) -> [i32; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    [
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    ]
+}
+
+// Colors far enough apart to count as an edge, with the thresholds of hqx
+fn differ(a: Pixel, b: Pixel) -> bool {
+    let (a, b) = (yuv(a), yuv(b));
+    (a[0] - b[0]).abs() > 48 || (a[1] - b[1]).abs() > 7 || (a[2] - b[2]).abs() > 6
+}
+
+// Weighted average of pixels
+fn mix(pixels: &[(Pixel, u32)]) -> Pixel {
+    let total: u32 = pixels.iter().map(|&(_, w)| w).sum();
+    let mut out = [0; 4];
+    for (c, channel) in out.iter_mut().enumerate() {
+        let sum: u32 = pixels.iter().map(|&(p, w)| p[c] as u32 * w).sum();
+        *channel = ((sum + total / 2) / total) as u8;
+    }
+    out
+}
+
+// hq2x and hq3x by Maxim Stepin. Which of the eight neighbours differ from
+// the center makes a pattern, and the reference implementation picks how to
+// interpolate each output pixel with a switch over its 256 values. Those cases
+// are folded here into (mask, value) pairs of the neighbours that matter, as
+// in FFmpeg's hqx filter, and written for the top left corner: the other
+// corners rotate the neighbourhood first. Neighbours are numbered row by row,
+// 4 being the center, and bit n of a pattern is the nth of them besides it.
+fn hqx(at: &impl Fn(isize, isize) -> Pixel, factor: usize, out: &mut [Pixel]) {
+    let mut w = neighbours(at);
+    let center = w[4];
+    for corner in 0..4 {
+        let pattern = [0, 1, 2, 3, 5, 6, 7, 8]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| differ(center, w[n]))
+            .fold(0, |pattern, (bit, _)| pattern | 1 << bit);
+        let is = |patterns: &[(u8, u8)]| patterns.iter().any(|&(m, v)| pattern & m == v);
+        if factor == 2 {
+            out[[0, 1, 3, 2][corner]] = hq2x_corner(&w, is);
+        } else {
+            let (pixel, edge) = hq3x_corner(&w, is);
+            out[[0, 2, 8, 6][corner]] = pixel;
+            // The side clockwise from the corner
+            out[[1, 5, 7, 3][corner]] = edge;
+        }
+        // Counterclockwise, bringing the next corner clockwise to the top left
+        w = [2, 5, 8, 1, 4, 7, 0, 3, 6].map(|n| w[n]);
+    }
+    if factor == 3 {
+        out[4] = center;
+    }
+}
+
+// Patterns both scalers test, named after the edge they find near the corner.
+// Some of them only count when two of the neighbours differ as well.
+const ALONG_TOP: [(u8, u8); 2] = [(0xbf, 0x37), (0xdb, 0x13)];
+const ALONG_LEFT: [(u8, u8); 2] = [(0xdb, 0x49), (0xef, 0x6d)];
+const CUT: [(u8, u8); 3] = [(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)];
+const CUT_NEAR: [(u8, u8); 13] = [
+    (0x6f, 0x2a),
+    (0x5b, 0x0a),
+    (0xbf, 0x3a),
+    (0xdf, 0x5a),
+    (0x9f, 0x8a),
+    (0xcf, 0x8a),
+    (0xef, 0x4e),
+    (0x3f, 0x0e),
+    (0xfb, 0x5a),
+    (0xbb, 0x8a),
+    (0x7f, 0x5a),
+    (0xaf, 0x8a),
+    (0xeb, 0x8a),
+];
+const TOWARDS_LEFT: [(u8, u8); 4] =
+    [(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)];
+const TOWARDS_TOP: [(u8, u8); 4] =
+    [(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)];
+const STEEP: [(u8, u8); 4] = [(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)];
+const DIAGONAL: [(u8, u8); 8] = [
+    (0x4f, 0x4b),
+    (0x9f, 0x1b),
+    (0x2f, 0x0b),
+    (0xbe, 0x0a),
+    (0xee, 0x0a),
+    (0x7e, 0x0a),
+    (0xeb, 0x4b),
+    (0x3b, 0x1b),
+];
+
+fn hq2x_corner(w: &[Pixel; 9], is: impl Fn(&[(u8, u8)]) -> bool) -> Pixel {
+    let [w0, w1, _, w3, w4, w5, _, w7, _] = *w;
+    if is(&ALONG_TOP) && differ(w1, w5) {
+        mix(&[(w4, 3), (w3, 1)])
+    } else if is(&ALONG_LEFT) && differ(w7, w3) {
+        mix(&[(w4, 3), (w1, 1)])
+    } else if is(&CUT) && differ(w3, w1) {
+        w4
+    } else if is(&CUT_NEAR) && differ(w3, w1) {
+        mix(&[(w4, 3), (w0, 1)])
+    } else if is(&[(0x0b, 0x08)]) {
+        mix(&[(w4, 2), (w0, 1), (w1, 1)])
+    } else if is(&[(0x0b, 0x02)]) {
+        mix(&[(w4, 2), (w0, 1), (w3, 1)])
+    } else if is(&[(0x2f, 0x2f)]) {
+        mix(&[(w4, 14), (w3, 1), (w1, 1)])
+    } else if is(&ALONG_TOP) {
+        mix(&[(w4, 5), (w1, 2), (w3, 1)])
+    } else if is(&ALONG_LEFT) {
+        mix(&[(w4, 5), (w3, 2), (w1, 1)])
+    } else if is(&TOWARDS_LEFT) {
+        mix(&[(w4, 3), (w3, 1)])
+    } else if is(&TOWARDS_TOP) {
+        mix(&[(w4, 3), (w1, 1)])
+    } else if is(&STEEP) {
+        mix(&[(w4, 2), (w3, 3), (w1, 3)])
+    } else if is(&[
+        (0xfb, 0x6a),
+        (0x6f, 0x6e),
+        (0x3f, 0x3e),
+        (0xfb, 0xfa),
+        (0xdf, 0xde),
+        (0xdf, 0x1e),
+    ]) {
+        mix(&[(w4, 3), (w0, 1)])
+    } else if is(&[(0x0a, 0x00)]) || is(&DIAGONAL) {
+        mix(&[(w4, 2), (w3, 1), (w1, 1)])
+    } else {
+        mix(&[(w4, 6), (w3, 1), (w1, 1)])
+    }
+}
+
+// The corner and the pixel right of it
+fn hq3x_corner(w: &[Pixel; 9], is: impl Fn(&[(u8, u8)]) -> bool) -> (Pixel, Pixel) {
+    let [w0, w1, _, w3, w4, w5, _, w7, _] = *w;
+    let corner = if is(&ALONG_LEFT) && differ(w7, w3) {
+        mix(&[(w4, 3), (w1, 1)])
+    } else if is(&ALONG_TOP) && differ(w1, w5) {
+        mix(&[(w4, 3), (w3, 1)])
+    } else if is(&CUT) && differ(w3, w1) {
+        w4
+    } else if is(&CUT_NEAR) && differ(w3, w1) {
+        mix(&[(w4, 3), (w0, 1)])
+    } else if is(&TOWARDS_TOP) {
+        mix(&[(w4, 3), (w1, 1)])
+    } else if is(&TOWARDS_LEFT) {
+        mix(&[(w4, 3), (w3, 1)])
+    } else if is(&STEEP) {
+        mix(&[(w3, 1), (w1, 1)])
+    } else if is(&DIAGONAL) {
+        mix(&[(w4, 2), (w3, 7), (w1, 7)])
+    } else if is(&[
+        (0x0b, 0x08),
+        (0xf9, 0x68),
+        (0xf3, 0x62),
+        (0x6d, 0x6c),
+        (0x67, 0x66),
+        (0x3d, 0x3c),
+        (0x37, 0x36),
+        (0xf9, 0xf8),
+        (0xdd, 0xdc),
+        (0xf3, 0xf2),
+        (0xd7, 0xd6),
+        (0xdd, 0x1c),
+        (0xd7, 0x16),
+        (0x0b, 0x02),
+    ]) {
+        mix(&[(w4, 3), (w0, 1)])
+    } else {
+        mix(&[(w4, 2), (w3, 1), (w1, 1)])
+    };
+
+    let edge = if is(&[
+        (0xfe, 0xde),
+        (0x9e, 0x16),
+        (0xda, 0x12),
+        (0x17, 0x16),
+        (0x5b, 0x12),
+        (0xbb, 0x12),
+    ]) && differ(w1, w5)
+        || is(&[
+            (0x0f, 0x0b),
+            (0x5e, 0x0a),
+            (0xfb, 0x7b),
+            (0x3b, 0x0b),
+            (0xbe, 0x0a),
+            (0x7a, 0x0a),
+        ]) && differ(w3, w1)
+    {
+        w4
+    } else if is(&[(0xbf, 0x8f), (0x7e, 0x0e), (0xbf, 0x37), (0xdb, 0x13)]) {
+        mix(&[(w1, 3), (w4, 1)])
+    } else if is(&[
+        (0x02, 0x00),
+        (0x7c, 0x28),
+        (0xed, 0xa9),
+        (0xf5, 0xb4),
+        (0xd9, 0x90),
+    ]) {
+        mix(&[(w4, 3), (w1, 1)])
+    } else if is(&[
+        (0x4f, 0x4b),
+        (0xfb, 0x7b),
+        (0xfe, 0x7e),
+        (0x9f, 0x1b),
+        (0x2f, 0x0b),
+        (0xbe, 0x0a),
+        (0x7e, 0x0a),
+        (0xfb, 0x4b),
+        (0xfb, 0xdb),
+        (0xfe, 0xde),
+        (0xfe, 0x56),
+        (0x57, 0x56),
+        (0x97, 0x16),
+        (0x3f, 0x1e),
+        (0xdb, 0x12),
+        (0xbb, 0x12),
+    ]) {
+        mix(&[(w4, 7), (w1, 1)])
+    } else {
+        w4
+    };
+    (corner, edge)
+}
+
+fn distance(a: Pixel, b: Pixel) -> i32 {
+    let (a, b) = (yuv(a), yuv(b));
+    48 * (a[0] - b[0]).abs() + 7 * (a[1] - b[1]).abs() + 6 * (a[2] - b[2]).abs()
+}
+
+// xBR level 1. Each corner of the block compares the edges running along and
+// across it, and when the one across wins, blends in the closer of the two
+// pixels it runs through. Corners are worked out by mirroring the
+// neighbourhood, the rule being symmetric along the diagonal.
+fn xbr2x(at: &impl Fn(isize, isize) -> Pixel, out: &mut [Pixel]) {
+    let e = at(0, 0);
+    for (n, (sx, sy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].into_iter().enumerate() {
+        // Named for the bottom right corner, E being the center
+        let p = |dx: isize, dy: isize| at(dx * sx, dy * sy);
+        let (b, c, d, f) = (p(0, -1), p(1, -1), p(-1, 0), p(1, 0));
+        let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+        let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+
+        let along = distance(e, c)
+            + distance(e, g)
+            + distance(i, f4)
+            + distance(i, h5)
+            + 4 * distance(h, f);
+        let across = distance(h, d)
+            + distance(h, i5)
+            + distance(f, i4)
+            + distance(f, b)
+            + 4 * distance(e, i);
+        out[n] = match along < across {
+            true => {
+                let pixel = if distance(e, f) <= distance(e, h) {
+                    f
+                } else {
+                    h
+                };
+                mix(&[(e, 1), (pixel, 1)])
+            }
+            false => e,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Scaler;
+
+    const W: [u8; 4] = [255, 255, 255, 255];
+    const K: [u8; 4] = [0, 0, 0, 255];
+
+    fn image(pixels: &[[u8; 4]]) -> Vec<u8> {
+        pixels.iter().flatten().copied().collect()
+    }
+
+    fn pixel(image: &[u8], width: usize, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * width + x) * 4;
+        image[i..i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        // Black staircase on white
+        let input = image(&[K, W, W, K]);
+        let out = Scaler::Scale2x.upscale(&input, 2, 2);
+        assert_eq!(out.len(), 4 * 4 * 4);
+        // The white corners next to the diagonal turn black
+        assert_eq!(pixel(&out, 4, 2, 1), K);
+        assert_eq!(pixel(&out, 4, 1, 2), K);
+        assert_eq!(pixel(&out, 4, 1, 1), W);
+        assert_eq!(pixel(&out, 4, 3, 0), W);
+        assert_eq!(Scaler::from_name("EPX"), Some(Scaler::Scale2x));
+    }
+
+    #[test]
+    fn flat_areas_stay_flat() {
+        let input = image(&[W; 9]);
+        for scaler in Scaler::ALL {
+            let f = scaler.factor() as usize;
+            let out = scaler.upscale(&input, 3, 3);
+            assert_eq!(out.len(), 9 * f * f * 4, "{:?}", scaler);
+            assert!(out.chunks(4).all(|p| p == W), "{:?}", scaler);
+        }
+    }
+
+    #[test]
+    fn hqx_keeps_the_sides_of_a_dot() {
+        // A black dot on white, each corner rounded off by blending
+        let mut input = image(&[W; 9]);
+        input[16..20].copy_from_slice(&K);
+        let out = Scaler::Hq3x.upscale(&input, 3, 3);
+        for (x, y) in [(3, 3), (5, 3), (3, 5), (5, 5)] {
+            assert_eq!(pixel(&out, 9, x, y), [128, 128, 128, 255]);
+        }
+        for (x, y) in [(4, 3), (3, 4), (4, 4), (5, 4), (4, 5)] {
+            assert_eq!(pixel(&out, 9, x, y), K);
+        }
+
+        // hq2x only takes a little off its corners
+        let out = Scaler::Hq2x.upscale(&input, 3, 3);
+        assert_eq!(pixel(&out, 6, 2, 2), [32, 32, 32, 255]);
+    }
+
+    #[test]
+    fn scalers_blend_edges() {
+        // A diagonal edge, white above and black below
+        let input = image(&[W, W, W, W, W, K, W, K, K]);
+        for scaler in [Scaler::Hq2x, Scaler::Hq3x, Scaler::Xbr2x] {
+            let f = scaler.factor() as usize;
+            let out = scaler.upscale(&input, 3, 3);
+            // The white center gets some black towards the bottom right only
+            let corner = pixel(&out, 3 * f, 2 * f - 1, 2 * f - 1);
+            assert!(corner != W && corner != K, "{:?} {:?}", scaler, corner);
+            assert_eq!(pixel(&out, 3 * f, f, f), W, "{:?}", scaler);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use crate::gameboy::{
+    ColorCorrection, Colorization, DmgPalettes, Gameboy, RenderOptions,
+};
+use crate::keys::{Action, Hotkey, KeyBindings};
+use crate::scale::Scaler;
+use std::path::PathBuf;
+
+// Frames run per displayed frame while fast forwarding
+const FAST_FORWARD: u32 = 4;
+
+// Turns host key presses into Game Boy buttons and hotkeys, the same way for
+// every frontend. Frontends only have to name their keys, see `KeyBindings`.
+pub struct Controls {
+    pub keys: KeyBindings,
+    pub paused: bool,
+    pub fast_forward: bool,
+    // Frontends with a window follow it
+    pub fullscreen: bool,
+    // Shader preset in use, counting up as the hotkey is pressed
+    pub shader: usize,
+    palettes: Vec<(String, DmgPalettes)>,
+    palette: usize,
+    // Outcome of the last hotkey, for frontends to show
+    pub status: Option<String>,
+    // Quick save slot, used when states are not saved to a file
+    state: Option<Vec<u8>>,
+    state_path: Option<PathBuf>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    screenshot_dir: Option<PathBuf>,
+    // Screenshots are upscaled with it
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub scaler: Scaler,
+    // Bound keys that are down. Key repeat presses them again, which must not
+    // toggle hotkeys over and over.
+    held: Vec<String>,
+}
+
+impl Controls {
+    pub fn new(options: &RenderOptions) -> Controls {
+        Controls {
+            keys: options.keys.clone(),
+            paused: false,
+            fast_forward: false,
+            fullscreen: options.fullscreen,
+            shader: options.shader,
+            palettes: options.palettes.clone(),
+            palette: options.palette,
+            status: None,
+            state: None,
+            state_path: options.state_path.clone(),
+            screenshot_dir: options.screenshot_dir.clone(),
+            scaler: options.scaler,
+            held: Vec::new(),
+        }
+    }
+
+    // Returns false when `key` is not bound to anything
+    pub fn key_down(&mut self, gameboy: &mut Gameboy, key: &str) -> bool {
+        let Some(action) = self.keys.action(key) else {
+            return false;
+        };
+        if !self.held.iter().any(|k| k == key) {
+            self.held.push(key.to_string());
+            self.press(gameboy, action);
+        }
+        true
+    }
+
+    pub fn key_up(&mut self, gameboy: &mut Gameboy, key: &str) -> bool {
+        self.held.retain(|k| k != key);
+        match self.keys.action(key) {
+            Some(action) => self.release(gameboy, action),
+            None => return false,
+        }
+        true
+    }
+
+    pub fn press(&mut self, gameboy: &mut Gameboy, action: Action) {
+        match action {
+            Action::Button(button) => gameboy.keydown(button),
+            Action::Hotkey(hotkey) => self.hotkey(gameboy, hotkey),
+        }
+    }
+
+    pub fn release(&mut self, gameboy: &mut Gameboy, action: Action) {
+        match action {
+            Action::Button(button) => gameboy.keyup(button),
+            Action::Hotkey(Hotkey::FastForward) => self.fast_forward = false,
+            Action::Hotkey(_) => {}
+        }
+    }
+
+    // How many frames to emulate before the next one is displayed
+    pub fn frames(&self) -> u32 {
+        match (self.paused, self.fast_forward) {
+            (true, _) => 0,
+            (false, true) => FAST_FORWARD,
+            (false, false) => 1,
+        }
+    }
+
+    pub fn run(&mut self, gameboy: &mut Gameboy) {
+        for _ in 0..self.frames() {
+            gameboy.frame();
+        }
+        self.check_serial(gameboy);
+    }
+
+    pub fn check_serial(&mut self, gameboy: &mut Gameboy) {
+        if let Some(error) = gameboy.take_serial_error() {
+            self.status = Some(error);
+        }
+    }
+
+    fn hotkey(&mut self, gameboy: &mut Gameboy, hotkey: Hotkey) {
+        let status = match hotkey {
+            Hotkey::SaveState => self.save_state(gameboy),
+            Hotkey::LoadState => self.load_state(gameboy),
+            Hotkey::FastForward => {
+                self.fast_forward = true;
+                return;
+            }
+            Hotkey::Pause => {
+                self.paused = !self.paused;
+                Ok(String::from(if self.paused { "Paused" } else { "Resumed" }))
+            }
+            Hotkey::Reset => {
+                gameboy.reset();
+                Ok(String::from("Reset"))
+            }
+            Hotkey::Screenshot => self.screenshot(gameboy),
+            Hotkey::Fullscreen => {
+                self.fullscreen = !self.fullscreen;
+                return;
+            }
+            Hotkey::NextShader => {
+                self.shader = self.shader.wrapping_add(1);
+                return;
+            }
+            Hotkey::NextPalette => self.next_palette(gameboy),
+        };
+        self.status = Some(status.unwrap_or_else(|e| e));
+    }
+
+    fn next_palette(&mut self, gameboy: &mut Gameboy) -> Result<String, String> {
+        if gameboy.is_color() {
+            let all = ColorCorrection::ALL;
+            let next = all.iter().position(|&c| c == gameboy.color_correction());
+            let correction = all[(next.unwrap() + 1) % all.len()];
+            gameboy.set_color_correction(correction);
+            return Ok(format!("Color correction: {}", correction.name()));
+        }
+        // DMG games on a CGB go through the colorizations of its boot ROM,
+        // starting over from the automatic one after a DMG palette was set
+        let all = Colorization::ALL;
+        let next = gameboy
+            .colorization()
+            .map_or(0, |c| all.iter().position(|&a| a == c).unwrap() + 1);
+        let colorization = all[next % all.len()];
+        if gameboy.set_colorization(colorization) {
+            return Ok(format!("Colorization: {}", colorization.name()));
+        }
+        if self.palettes.is_empty() {
+            return Err(String::from("No palettes"));
+        }
+        self.palette = (self.palette + 1) % self.palettes.len();
+        let (ref name, palettes) = self.palettes[self.palette];
+        gameboy.set_palettes(palettes);
+        Ok(format!("Palette: {}", name))
+    }
+
+    fn save_state(&mut self, gameboy: &Gameboy) -> Result<String, String> {
+        let state = gameboy.save_state();
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(ref path) = self.state_path {
+            std::fs::write(path, &state)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            return Ok(format!("Saved state to {}", path.display()));
+        }
+        self.state = Some(state);
+        Ok(String::from("Saved state"))
+    }
+
+    fn load_state(&mut self, gameboy: &mut Gameboy) -> Result<String, String> {
+        let state = match self.state_path {
+            #[cfg(not(target_arch = "wasm32"))]
+            Some(ref path) => {
+                std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            _ => self.state.clone().ok_or("No state saved yet")?,
+        };
+        gameboy.load_state(&state)?;
+        Ok(String::from("Loaded state"))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn screenshot(&self, gameboy: &Gameboy) -> Result<String, String> {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!("gameboy-{}.png", time.as_millis());
+        let path = match self.screenshot_dir {
+            Some(ref dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+
+        let factor = self.scaler.factor();
+        let rgb: Vec<u8> = self
+            .scaler
+            .upscale(gameboy.image(), gameboy.width, gameboy.height)
+            .chunks(4)
+            .flat_map(|p| [p[0], p[1], p[2]])
+            .collect();
+        image::save_buffer(
+            &path,
+            &rgb,
+            gameboy.width * factor,
+            gameboy.height * factor,
+            image::ExtendedColorType::Rgb8,
+        )
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(format!("Saved {}", path.display()))
+    }
+
+    // Browsers can already save the canvas
+    #[cfg(target_arch = "wasm32")]
+    fn screenshot(&self, _gameboy: &Gameboy) -> Result<String, String> {
+        Err(String::from("Screenshots are not supported here"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Controls;
+    use crate::gameboy::{
+        ColorCorrection, Colorization, DmgPalettes, Gameboy, RenderOptions, Target, GREEN,
+    };
+
+    #[test]
+    fn hotkeys_act_on_the_emulator() {
+        let mut rom = vec![0; 0x8000];
+        // ld hl, C000; inc (hl); jr -3
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        let mut gb = Gameboy::new(rom, None);
+        let mut controls = Controls::new(&RenderOptions::default());
+
+        assert!(!controls.key_down(&mut gb, "F1"));
+        controls.key_down(&mut gb, "Tab");
+        assert_eq!(controls.frames(), 4);
+        controls.key_up(&mut gb, "Tab");
+        controls.key_down(&mut gb, "P");
+        assert_eq!(controls.frames(), 0);
+
+        gb.frame();
+        controls.key_down(&mut gb, "F9");
+        controls.key_up(&mut gb, "F9");
+        assert_eq!(controls.status.as_deref(), Some("No state saved yet"));
+        controls.key_down(&mut gb, "F5");
+        let saved = gb.peek(0xC000);
+        gb.frame();
+        assert_ne!(gb.peek(0xC000), saved);
+        controls.key_down(&mut gb, "F9");
+        assert_eq!(gb.peek(0xC000), saved);
+    }
+
+    #[test]
+    fn palette_hotkey_depends_on_the_game() {
+        let mut controls = Controls::new(&RenderOptions::default());
+        let mut dmg = Gameboy::with_target(vec![0; 0x8000], None, Target::GameBoy);
+        controls.key_down(&mut dmg, "F7");
+        assert_eq!(dmg.palettes(), DmgPalettes::new(GREEN));
+        assert_eq!(controls.status.as_deref(), Some("Palette: green"));
+
+        // A DMG game on a CGB
+        let mut compat = Gameboy::new(vec![0; 0x8000], None);
+        assert_eq!(compat.colorization(), Some(Colorization::Auto));
+        controls.key_up(&mut dmg, "F7");
+        controls.key_down(&mut compat, "F7");
+        controls.key_up(&mut compat, "F7");
+        assert_eq!(compat.colorization(), Some(Colorization::Up));
+        compat.set_palettes(DmgPalettes::new(GREEN));
+        assert_eq!(compat.colorization(), None);
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut cgb = Gameboy::new(rom, None);
+        controls.key_down(&mut cgb, "F7");
+        assert_eq!(cgb.color_correction(), ColorCorrection::Accurate);
+    }
+
+    #[test]
+    fn key_repeat_does_not_retrigger_hotkeys() {
+        let mut gb = Gameboy::new(vec![0; 0x8000], None);
+        let mut controls = Controls::new(&RenderOptions::default());
+
+        for _ in 0..3 {
+            controls.key_down(&mut gb, "P");
+        }
+        assert!(controls.paused);
+        controls.key_up(&mut gb, "P");
+        controls.key_down(&mut gb, "P");
+        assert!(!controls.paused);
+
+        controls.key_down(&mut gb, "Tab");
+        controls.key_down(&mut gb, "Tab");
+        assert!(controls.fast_forward);
+        controls.key_up(&mut gb, "Tab");
+        assert!(!controls.fast_forward);
+    }
+}
@@ -0,0 +1,494 @@
+use crate::cpu::disasm::disassemble;
+use crate::cpu::registers::CpuFlag;
+use crate::gameboy::Gameboy;
+use crate::screen::controls::Controls;
+use ratatui::{
+    crossterm::event::KeyCode,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+// Memory view bytes per line
+const ROW: u16 = 8;
+// Lines moved by J and K
+const PAGE: usize = 16;
+
+// I/O registers worth watching, shown two per line
+const IO: [(&str, u16); 14] = [
+    ("LCDC", 0xFF40),
+    ("STAT", 0xFF41),
+    ("SCY", 0xFF42),
+    ("SCX", 0xFF43),
+    ("LY", 0xFF44),
+    ("LYC", 0xFF45),
+    ("WY", 0xFF4A),
+    ("WX", 0xFF4B),
+    ("DIV", 0xFF04),
+    ("TIMA", 0xFF05),
+    ("TMA", 0xFF06),
+    ("TAC", 0xFF07),
+    ("IE", 0xFFFF),
+    ("IF", 0xFF0F),
+];
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Panel {
+    Disassembly,
+    Memory,
+    Breakpoints,
+}
+
+// Registers, disassembly, memory and breakpoint panels for the terminal
+// frontend. Everything is driven from the keyboard, as it is meant to be used
+// over SSH.
+pub struct Debugger {
+    pub visible: bool,
+    pub breakpoints: Vec<u16>,
+    focus: Panel,
+    // Disassembly cursor, following PC when unset
+    cursor: Option<u16>,
+    // First address of the memory view
+    memory: u16,
+    // Selected breakpoint
+    selected: usize,
+    // Hex address being typed after `g`
+    input: Option<String>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger {
+            visible: false,
+            breakpoints: Vec::new(),
+            focus: Panel::Disassembly,
+            cursor: None,
+            memory: 0xC000,
+            selected: 0,
+            input: None,
+        }
+    }
+}
+
+// Instructions starting at `address`, and as many before it. Code cannot be
+// decoded backwards, so the lines before come from the furthest starting point
+// that decodes into `address`.
+fn listing(
+    gameboy: &Gameboy,
+    address: u16,
+    before: usize,
+    after: usize,
+) -> Vec<(u16, String)> {
+    let decode = |a: u16| disassemble(&gameboy.peek_range(a, 3), a);
+    let mut lines = Vec::new();
+    for back in (1..=before as u16 * 3).rev().filter(|&b| b <= address) {
+        let mut a = (address - back) as u32;
+        let mut decoded = Vec::new();
+        while a < address as u32 {
+            let (text, len) = decode(a as u16);
+            decoded.push((a as u16, text));
+            a += len as u32;
+        }
+        if a == address as u32 {
+            let skip = decoded.len().saturating_sub(before);
+            lines.extend(decoded.drain(skip..));
+            break;
+        }
+    }
+
+    let mut a = address;
+    for _ in 0..after {
+        let (text, len) = decode(a);
+        lines.push((a, text));
+        a = a.wrapping_add(len);
+    }
+    lines
+}
+
+impl Debugger {
+    // Returns false for keys the debugger has no use for
+    pub fn on_key(
+        &mut self,
+        gameboy: &mut Gameboy,
+        controls: &mut Controls,
+        key: KeyCode,
+    ) -> bool {
+        if let Some(ref mut input) = self.input {
+            match key {
+                KeyCode::Char(c) if c.is_ascii_hexdigit() && input.len() < 4 => {
+                    input.push(c.to_ascii_uppercase())
+                }
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Enter => {
+                    if let Ok(address) = u16::from_str_radix(input, 16) {
+                        self.go_to(address);
+                    }
+                    self.input = None;
+                }
+                KeyCode::Esc => self.input = None,
+                _ => {}
+            }
+            return true;
+        }
+
+        let KeyCode::Char(c) = key else {
+            return false;
+        };
+        match c {
+            'n' => {
+                controls.paused = true;
+                gameboy.step();
+                self.cursor = None;
+            }
+            'c' => controls.paused = false,
+            'f' => self.cursor = None,
+            'g' => self.input = Some(String::new()),
+            't' => self.toggle_breakpoint(gameboy),
+            ']' => {
+                self.focus = match self.focus {
+                    Panel::Disassembly => Panel::Memory,
+                    Panel::Memory => Panel::Breakpoints,
+                    Panel::Breakpoints => Panel::Disassembly,
+                }
+            }
+            '[' => {
+                self.focus = match self.focus {
+                    Panel::Disassembly => Panel::Breakpoints,
+                    Panel::Memory => Panel::Disassembly,
+                    Panel::Breakpoints => Panel::Memory,
+                }
+            }
+            'j' => self.scroll(gameboy, 1, true),
+            'k' => self.scroll(gameboy, 1, false),
+            'J' => self.scroll(gameboy, PAGE, true),
+            'K' => self.scroll(gameboy, PAGE, false),
+            _ => return false,
+        }
+        true
+    }
+
+    fn go_to(&mut self, address: u16) {
+        match self.focus {
+            Panel::Disassembly => self.cursor = Some(address),
+            Panel::Memory => self.memory = address & !(ROW - 1),
+            Panel::Breakpoints => {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                    self.breakpoints.sort_unstable();
+                }
+            }
+        }
+    }
+
+    fn toggle_breakpoint(&mut self, gameboy: &Gameboy) {
+        let address = match self.focus {
+            Panel::Disassembly => self.cursor.unwrap_or(gameboy.registers().pc),
+            Panel::Memory => return,
+            Panel::Breakpoints => match self.breakpoints.get(self.selected) {
+                Some(&address) => address,
+                None => return,
+            },
+        };
+        match self.breakpoints.iter().position(|&b| b == address) {
+            Some(i) => {
+                self.breakpoints.remove(i);
+            }
+            None => {
+                self.breakpoints.push(address);
+                self.breakpoints.sort_unstable();
+            }
+        }
+        self.selected = self.selected.min(self.breakpoints.len().saturating_sub(1));
+    }
+
+    fn scroll(&mut self, gameboy: &Gameboy, lines: usize, down: bool) {
+        match self.focus {
+            Panel::Disassembly => {
+                let mut cursor = self.cursor.unwrap_or(gameboy.registers().pc);
+                for _ in 0..lines {
+                    cursor = match down {
+                        true => listing(gameboy, cursor, 0, 2)[1].0,
+                        false => match listing(gameboy, cursor, 1, 0).first() {
+                            Some(&(address, _)) => address,
+                            None => cursor.saturating_sub(1),
+                        },
+                    };
+                }
+                self.cursor = Some(cursor);
+            }
+            Panel::Memory => {
+                let offset = (lines as u16).wrapping_mul(ROW);
+                self.memory = match down {
+                    true => self.memory.wrapping_add(offset),
+                    false => self.memory.wrapping_sub(offset),
+                };
+            }
+            Panel::Breakpoints => {
+                let last = self.breakpoints.len().saturating_sub(1);
+                self.selected = match down {
+                    true => (self.selected + lines).min(last),
+                    false => self.selected.saturating_sub(lines),
+                };
+            }
+        }
+    }
+
+    // Runs the frames `controls` asks for, pausing on breakpoints
+    pub fn run(&mut self, gameboy: &mut Gameboy, controls: &mut Controls) {
+        for _ in 0..controls.frames() {
+            if gameboy.frame_until(&self.breakpoints) {
+                controls.paused = true;
+                controls.status =
+                    Some(format!("Breakpoint at ${:04X}", gameboy.registers().pc));
+                self.cursor = None;
+                return;
+            }
+        }
+        controls.check_serial(gameboy);
+    }
+
+    pub fn render(&self, f: &mut Frame<'_>, area: Rect, gameboy: &Gameboy) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(9),
+                Constraint::Min(6),
+                Constraint::Length(7),
+            ])
+            .split(area);
+        let top = halves(rows[0]);
+        // The memory view keeps the width of a full line of bytes
+        let middle = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(20), Constraint::Length(41)])
+            .split(rows[1]);
+        let bottom = halves(rows[2]);
+
+        f.render_widget(
+            Paragraph::new(registers(gameboy)).block(block("Registers", false)),
+            top[0],
+        );
+        let io: Vec<Line> = IO
+            .chunks(2)
+            .map(|pair| {
+                let text: Vec<String> = pair
+                    .iter()
+                    .map(|&(name, address)| {
+                        format!("{:<4} {:02X}", name, gameboy.peek(address))
+                    })
+                    .collect();
+                Line::from(text.join("   "))
+            })
+            .collect();
+        f.render_widget(Paragraph::new(io).block(block("I/O", false)), top[1]);
+
+        self.render_disassembly(f, middle[0], gameboy);
+        self.render_memory(f, middle[1], gameboy);
+        self.render_breakpoints(f, bottom[0]);
+
+        let mut help = vec![
+            Line::from("n: step  c: continue"),
+            Line::from("[/]: panel  j/k J/K: move"),
+            Line::from("g: go to  t: breakpoint"),
+            Line::from("f: follow PC  d: close"),
+        ];
+        if let Some(ref input) = self.input {
+            help.push(Line::from(format!("Address: ${}_", input)));
+        }
+        f.render_widget(Paragraph::new(help).block(block("Keys", false)), bottom[1]);
+    }
+
+    fn render_disassembly(&self, f: &mut Frame<'_>, area: Rect, gameboy: &Gameboy) {
+        let pc = gameboy.registers().pc;
+        let cursor = self.cursor.unwrap_or(pc);
+        let height = area.height.saturating_sub(2) as usize;
+        let before = height / 3;
+        let lines: Vec<Line> = listing(gameboy, cursor, before, height - before)
+            .into_iter()
+            .take(height)
+            .map(|(address, text)| {
+                let mark = match (self.breakpoints.contains(&address), address == pc) {
+                    (true, true) => "*>",
+                    (true, false) => "* ",
+                    (false, true) => " >",
+                    (false, false) => "  ",
+                };
+                let line = Line::from(format!("{} {:04X}  {}", mark, address, text));
+                match address == cursor && self.focus == Panel::Disassembly {
+                    true => line.style(Style::default().add_modifier(Modifier::REVERSED)),
+                    false => line,
+                }
+            })
+            .collect();
+        let focused = self.focus == Panel::Disassembly;
+        f.render_widget(
+            Paragraph::new(lines).block(block("Disassembly", focused)),
+            area,
+        );
+    }
+
+    fn render_memory(&self, f: &mut Frame<'_>, area: Rect, gameboy: &Gameboy) {
+        let height = area.height.saturating_sub(2);
+        let lines: Vec<Line> = (0..height)
+            .map(|row| {
+                let address = self.memory.wrapping_add(row.wrapping_mul(ROW));
+                let bytes = gameboy.peek_range(address, ROW as usize);
+                let hex: Vec<String> =
+                    bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                let ascii: String = bytes
+                    .iter()
+                    .map(|&b| match b {
+                        0x20..=0x7E => b as char,
+                        _ => '.',
+                    })
+                    .collect();
+                Line::from(format!("{:04X}  {}  {}", address, hex.join(" "), ascii))
+            })
+            .collect();
+        f.render_widget(
+            Paragraph::new(lines).block(block("Memory", self.focus == Panel::Memory)),
+            area,
+        );
+    }
+
+    fn render_breakpoints(&self, f: &mut Frame<'_>, area: Rect) {
+        let focused = self.focus == Panel::Breakpoints;
+        let lines: Vec<Line> = match self.breakpoints.is_empty() {
+            true => vec![Line::from("None, t sets one")],
+            false => {
+                self.breakpoints
+                    .iter()
+                    .enumerate()
+                    .map(|(i, address)| {
+                        let line = Line::from(format!("${:04X}", address));
+                        match i == self.selected && focused {
+                            true => line
+                                .style(Style::default().add_modifier(Modifier::REVERSED)),
+                            false => line,
+                        }
+                    })
+                    .collect()
+            }
+        };
+        // Keeps the selection in view
+        let height = area.height.saturating_sub(2) as usize;
+        let scroll = (self.selected + 1).saturating_sub(height) as u16;
+        f.render_widget(
+            Paragraph::new(lines)
+                .scroll((scroll, 0))
+                .block(block("Breakpoints", focused)),
+            area,
+        );
+    }
+}
+
+fn registers(gameboy: &Gameboy) -> Vec<Line<'static>> {
+    let r = gameboy.registers();
+    let flag = |flag: CpuFlag, name: char| match r.f & flag as u8 {
+        0 => '-',
+        _ => name,
+    };
+    let pair = |high: u8, low: u8| u16::from_be_bytes([high, low]);
+    vec![
+        Line::from(format!(
+            "AF {:04X}  BC {:04X}",
+            pair(r.a, r.f),
+            pair(r.b, r.c)
+        )),
+        Line::from(format!(
+            "DE {:04X}  HL {:04X}",
+            pair(r.d, r.e),
+            pair(r.h, r.l)
+        )),
+        Line::from(format!("SP {:04X}  PC {:04X}", r.sp, r.pc)),
+        Line::from(format!(
+            "Flags {}{}{}{}",
+            flag(CpuFlag::Z, 'Z'),
+            flag(CpuFlag::N, 'N'),
+            flag(CpuFlag::H, 'H'),
+            flag(CpuFlag::C, 'C')
+        )),
+        Line::from(format!(
+            "IME {}  {}",
+            gameboy.interrupts_enabled() as u8,
+            if gameboy.halted() { "HALT" } else { "" }
+        )),
+    ]
+}
+
+fn halves(area: Rect) -> std::rc::Rc<[Rect]> {
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(area)
+}
+
+fn block(name: &str, focused: bool) -> Block<'_> {
+    let block = Block::default().borders(Borders::ALL).title(name);
+    match focused {
+        true => block.border_style(Style::default().add_modifier(Modifier::BOLD)),
+        false => block,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{listing, Debugger};
+    use crate::gameboy::{Gameboy, RenderOptions};
+    use crate::screen::controls::Controls;
+    use ratatui::crossterm::event::KeyCode;
+
+    fn gameboy() -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        // ld hl, C000; inc (hl); jr -3
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        Gameboy::new(rom, None)
+    }
+
+    #[test]
+    fn lists_whole_instructions_around_an_address() {
+        let gb = gameboy();
+        let lines = listing(&gb, 0x104, 2, 2);
+        let addresses: Vec<u16> = lines.iter().map(|&(a, _)| a).collect();
+        assert_eq!(addresses, vec![0x100, 0x103, 0x104, 0x106]);
+        assert_eq!(lines[0].1, "LD HL,$C000");
+        assert_eq!(lines[2].1, "JR $0103");
+    }
+
+    #[test]
+    fn breakpoints_pause_the_emulator() {
+        let mut gb = gameboy();
+        let mut controls = Controls::new(&RenderOptions::default());
+        let mut debugger = Debugger::default();
+
+        // A breakpoint on the increment, set from the disassembly cursor
+        debugger.on_key(&mut gb, &mut controls, KeyCode::Char('j'));
+        debugger.on_key(&mut gb, &mut controls, KeyCode::Char('t'));
+        assert_eq!(debugger.breakpoints, vec![0x103]);
+
+        let count = gb.peek(0xC000);
+        debugger.run(&mut gb, &mut controls);
+        assert!(controls.paused);
+        assert_eq!((gb.registers().pc, gb.peek(0xC000)), (0x103, count));
+
+        // Stepping goes past it, continuing stops on it again
+        debugger.on_key(&mut gb, &mut controls, KeyCode::Char('n'));
+        let count = count.wrapping_add(1);
+        assert_eq!(gb.peek(0xC000), count);
+        debugger.on_key(&mut gb, &mut controls, KeyCode::Char('c'));
+        debugger.run(&mut gb, &mut controls);
+        assert_eq!((gb.registers().pc, gb.peek(0xC000)), (0x103, count));
+        assert_eq!(controls.status.as_deref(), Some("Breakpoint at $0103"));
+
+        // Addresses can be typed in, hex digits win over bound keys
+        for key in [']', ']', 'g', 'b', '0', '0', '0'] {
+            debugger.on_key(&mut gb, &mut controls, KeyCode::Char(key));
+        }
+        debugger.on_key(&mut gb, &mut controls, KeyCode::Enter);
+        assert_eq!(debugger.breakpoints, vec![0x103, 0xB000]);
+    }
+}
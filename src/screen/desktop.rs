@@ -2,26 +2,31 @@ extern crate gl;
 extern crate glutin;
 extern crate libc;
 
-use crate::gameboy::Gameboy;
-use crate::input::KeypadKey;
+use crate::gameboy::{Filter, Gameboy};
+use crate::screen::controls::Controls;
+use crate::shaders::{self, Pass, Preset};
 
 use std::ffi::CString;
 use std::iter::repeat;
 use std::mem;
 use std::ptr;
-use std::str;
 
 use gl::types::*;
 use glutin::event::{ElementState, VirtualKeyCode};
 
+// A shader pass ready to run, see `shaders::Pass`
+struct Program {
+    program: GLuint,
+    filter: Option<Filter>,
+    scale: Option<u32>,
+    // What passes followed by others draw to, and its size in pixels
+    framebuffer: GLuint,
+    texture: GLuint,
+    size: (u32, u32),
+}
+
 pub struct Glcx {
-    #[allow(unused)]
     tex: GLuint,
-    #[allow(unused)]
-    program: GLuint,
-    #[allow(unused)]
-    frag: GLuint,
-    #[allow(unused)]
     vert: GLuint,
     #[allow(unused)]
     ebo: GLuint,
@@ -29,6 +34,32 @@ pub struct Glcx {
     vbo: GLuint,
     #[allow(unused)]
     vao: GLuint,
+    passes: Vec<Program>,
+    filter: Filter,
+    integer_scaling: bool,
+}
+
+// Where the screen goes in a window of `window` pixels, as x, y, width and
+// height. The aspect ratio is kept and the rest of the window left black.
+// Integer scaling only falls back to a fraction when the window is smaller
+// than the screen.
+pub fn viewport(window: (u32, u32), screen: (u32, u32), integer: bool) -> [i32; 4] {
+    let scale = f64::min(
+        window.0 as f64 / screen.0 as f64,
+        window.1 as f64 / screen.1 as f64,
+    );
+    let scale = match integer && scale >= 1.0 {
+        true => scale.floor(),
+        false => scale,
+    };
+    let width = (screen.0 as f64 * scale).round() as u32;
+    let height = (screen.1 as f64 * scale).round() as u32;
+    [
+        (window.0.saturating_sub(width) / 2) as i32,
+        (window.1.saturating_sub(height) / 2) as i32,
+        width as i32,
+        height as i32,
+    ]
 }
 
 // pub fn render(gameboy: &mut Gameboy) {
@@ -79,9 +110,10 @@ pub struct Glcx {
 // }
 
 pub fn process_window(
-    _window: &glutin::window::Window,
+    window: &glutin::window::Window,
     wevent: &glutin::event::WindowEvent,
     gameboy: &mut Gameboy,
+    controls: &mut Controls,
     focused: &mut bool,
 ) -> glutin::event_loop::ControlFlow {
     match wevent {
@@ -91,31 +123,20 @@ pub fn process_window(
         }
         glutin::event::WindowEvent::KeyboardInput { input, .. } => {
             if let Some(virt_keycode) = input.virtual_keycode {
-                let button = match virt_keycode {
-                    VirtualKeyCode::A => KeypadKey::A,
-                    VirtualKeyCode::B => KeypadKey::B,
-                    VirtualKeyCode::Z => KeypadKey::Select,
-                    VirtualKeyCode::X => KeypadKey::Start,
-
-                    VirtualKeyCode::Left => KeypadKey::Left,
-                    VirtualKeyCode::Right => KeypadKey::Right,
-                    VirtualKeyCode::Down => KeypadKey::Down,
-                    VirtualKeyCode::Up => KeypadKey::Up,
-
-                    _ => return glutin::event_loop::ControlFlow::Poll,
-                };
+                let key = key_name(virt_keycode);
                 match input.state {
-                    ElementState::Pressed => gameboy.keydown(button),
-                    ElementState::Released => gameboy.keyup(button),
-                }
+                    ElementState::Pressed => controls.key_down(gameboy, &key),
+                    ElementState::Released => controls.key_up(gameboy, &key),
+                };
             }
 
             glutin::event_loop::ControlFlow::Poll
         }
-        glutin::event::WindowEvent::Resized(glutin::dpi::PhysicalSize {
-            width: _,
-            height: _,
-        }) => glutin::event_loop::ControlFlow::Poll,
+        // The screen is laid out again on the next draw
+        glutin::event::WindowEvent::Resized(_) => {
+            window.request_redraw();
+            glutin::event_loop::ControlFlow::Poll
+        }
         glutin::event::WindowEvent::CloseRequested => {
             glutin::event_loop::ControlFlow::Exit
         }
@@ -123,32 +144,93 @@ pub fn process_window(
     }
 }
 
-// Shader sources
+// glutin already names its keys the way the bindings expect
+fn key_name(key: VirtualKeyCode) -> String {
+    format!("{:?}", key)
+}
+
+// Shared by every pass, which only differ in their fragment shader
 const VERTEX: &str = r"#version 150 core
 in vec2 position;
-in vec3 color;
 in vec2 texcoord;
-out vec3 Color;
 out vec2 Texcoord;
+// Set when reading what a pass drew, its rows go bottom up
+uniform bool flip;
 void main() {
-   Color = color;
-   Texcoord = texcoord;
+   Texcoord = flip ? vec2(texcoord.x, 1.0 - texcoord.y) : texcoord;
    gl_Position = vec4(position, 0.0, 1.0);
 }
 ";
 
-const FRAGMENT: &str = r"#version 150 core
-in vec3 Color;
-in vec2 Texcoord;
-out vec4 outColor;
-uniform sampler2D tex;
-void main() {
-   outColor = texture(tex, Texcoord);
+unsafe fn info_log(
+    object: GLuint,
+    get: unsafe fn(GLuint, GLenum, *mut GLint),
+    log: unsafe fn(GLuint, GLsizei, *mut GLsizei, *mut GLchar),
+) -> String {
+    let mut len: GLint = 0;
+    get(object, gl::INFO_LOG_LENGTH, &mut len);
+    let mut buf = repeat(0u8).take(len.max(1) as usize).collect::<Vec<_>>();
+    log(
+        object,
+        len,
+        ptr::null_mut(),
+        buf.as_mut_ptr() as *mut GLchar,
+    );
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).trim_end().to_string()
+}
+
+unsafe fn compile_shader(kind: GLenum, source: &str) -> Result<GLuint, String> {
+    let shader = gl::CreateShader(kind);
+    let src = CString::new(source).map_err(|e| e.to_string())?;
+    gl::ShaderSource(shader, 1, &src.as_ptr(), ptr::null::<i32>());
+    gl::CompileShader(shader);
+
+    let mut status = gl::FALSE as GLint;
+    gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+    if status == (gl::TRUE as GLint) {
+        return Ok(shader);
+    }
+    let log = info_log(shader, gl::GetShaderiv, gl::GetShaderInfoLog);
+    gl::DeleteShader(shader);
+    Err(log)
+}
+
+unsafe fn check_program_link(program: GLuint) -> Result<(), String> {
+    let mut status = gl::FALSE as GLint;
+    gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+    if status == (gl::TRUE as GLint) {
+        return Ok(());
+    }
+    Err(info_log(program, gl::GetProgramiv, gl::GetProgramInfoLog))
+}
+
+unsafe fn set_filter(texture: GLuint, filter: Filter) {
+    let filter = match filter {
+        Filter::Nearest => gl::NEAREST,
+        Filter::Linear => gl::LINEAR,
+    };
+    gl::BindTexture(gl::TEXTURE_2D, texture);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, filter as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, filter as i32);
+}
+
+unsafe fn new_texture() -> GLuint {
+    let mut texture = 0;
+    gl::GenTextures(1, &mut texture);
+    gl::BindTexture(gl::TEXTURE_2D, texture);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+    texture
+}
+
+unsafe fn uniform(program: GLuint, name: &str) -> GLint {
+    let name = CString::new(name).unwrap();
+    gl::GetUniformLocation(program, name.as_ptr())
 }
-";
 
 impl Glcx {
-    pub fn new() -> Glcx {
+    pub fn new(filter: Filter, integer_scaling: bool) -> Glcx {
         unsafe {
             let mut vao = 0;
             gl::GenVertexArrays(1, &mut vao);
@@ -158,11 +240,11 @@ impl Glcx {
             gl::GenBuffers(1, &mut vbo);
 
             const VERTICES: &[f32] = &[
-                //  Position   Color             Texcoords
-                -1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, // Top-left
-                1.0, 1.0, 0.0, 1.0, 0.0, 1.0, 0.0, // Top-right
-                1.0, -1.0, 0.0, 0.0, 1.0, 1.0, 1.0, // Bottom-right
-                -1.0, -1.0, 1.0, 1.0, 1.0, 0.0, 1.0, // Bottom-left
+                //  Position   Texcoords
+                -1.0, 1.0, 0.0, 0.0, // Top-left
+                1.0, 1.0, 1.0, 0.0, // Top-right
+                1.0, -1.0, 1.0, 1.0, // Bottom-right
+                -1.0, -1.0, 0.0, 1.0, // Bottom-left
             ];
             gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
             gl::BufferData(
@@ -185,124 +267,125 @@ impl Glcx {
                 gl::STATIC_DRAW,
             );
 
-            let vert = gl::CreateShader(gl::VERTEX_SHADER);
-            let src = CString::new(VERTEX).unwrap();
-            gl::ShaderSource(vert, 1, &src.as_ptr(), std::ptr::null::<i32>());
-            gl::CompileShader(vert);
-            // Glcx::check_shader_compile(&gl, vert);
-
-            // Create and compile the fragment shader
-            let frag = gl::CreateShader(gl::FRAGMENT_SHADER);
-            let src = CString::new(FRAGMENT).unwrap();
-            gl::ShaderSource(frag, 1, &src.as_ptr(), std::ptr::null::<i32>());
-            gl::CompileShader(frag);
-            // TODO: check_shader_compile(frag)
-
-            let program = gl::CreateProgram();
-            gl::AttachShader(program, vert);
-            gl::AttachShader(program, frag);
-            let buf = CString::new("outColor").unwrap();
-            gl::BindFragDataLocation(program, 0, buf.as_ptr());
-            gl::LinkProgram(program);
-            // TODO: check_program_link(program)
-            assert_eq!(gl::GetError(), 0);
-            gl::UseProgram(program);
-
-            let buf = CString::new("position").unwrap();
-            let pos_attrib = gl::GetAttribLocation(program, buf.as_ptr());
-            gl::EnableVertexAttribArray(pos_attrib as u32);
+            // Attribute locations are bound before linking, so every pass
+            // reads the quad the same way
+            let stride = (4 * mem::size_of::<GLfloat>()) as i32;
+            gl::EnableVertexAttribArray(0);
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, stride, ptr::null());
+            gl::EnableVertexAttribArray(1);
             gl::VertexAttribPointer(
-                pos_attrib as u32,
+                1,
                 2,
                 gl::FLOAT,
                 gl::FALSE,
-                (7 * mem::size_of::<GLfloat>()) as i32,
-                std::ptr::null(),
-            );
-
-            let buf = CString::new("color").unwrap();
-            let col_attrib = gl::GetAttribLocation(program, buf.as_ptr());
-            gl::EnableVertexAttribArray(col_attrib as u32);
-            gl::VertexAttribPointer(
-                col_attrib as u32,
-                3,
-                gl::FLOAT,
-                gl::FALSE,
-                (7 * mem::size_of::<GLfloat>()) as i32,
+                stride,
                 (2 * mem::size_of::<GLfloat>()) as *const _,
             );
 
-            let buf = CString::new("texcoord").unwrap();
-            let tex_attrib = gl::GetAttribLocation(program, buf.as_ptr());
-            gl::EnableVertexAttribArray(tex_attrib as u32);
-            gl::VertexAttribPointer(
-                tex_attrib as u32,
-                2,
-                gl::FLOAT,
-                gl::FALSE,
-                (7 * mem::size_of::<GLfloat>()) as i32,
-                (5 * mem::size_of::<GLfloat>()) as *const _,
-            );
-
-            // Load textures
-            let mut tex = 0;
-            gl::GenTextures(1, &mut tex);
+            let vert = compile_shader(gl::VERTEX_SHADER, VERTEX).unwrap();
 
             gl::ActiveTexture(gl::TEXTURE0);
-            gl::BindTexture(gl::TEXTURE_2D, tex);
-            let buf = CString::new("tex").unwrap();
-            gl::Uniform1i(gl::GetUniformLocation(program, buf.as_ptr()), 0);
+            let tex = new_texture();
 
-            gl::TexParameteri(
-                gl::TEXTURE_2D,
-                gl::TEXTURE_WRAP_S,
-                gl::CLAMP_TO_EDGE as i32,
-            );
-            gl::TexParameteri(
-                gl::TEXTURE_2D,
-                gl::TEXTURE_WRAP_T,
-                gl::CLAMP_TO_EDGE as i32,
-            );
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-
-            Glcx {
+            let mut cx = Glcx {
                 tex,
-                program,
-                frag,
                 vert,
                 ebo,
                 vbo,
                 vao,
+                passes: Vec::new(),
+                filter,
+                integer_scaling,
+            };
+            cx.set_preset(&shaders::builtin()[0]).unwrap();
+            cx
+        }
+    }
+
+    // Compiles every pass of `preset` and switches to it. The passes in use
+    // stay when one of them fails, the error has the compiler output.
+    pub fn set_preset(&mut self, preset: &Preset) -> Result<(), String> {
+        let mut passes = Vec::new();
+        for (i, pass) in preset.passes.iter().enumerate() {
+            let last = i + 1 == preset.passes.len();
+            match unsafe { self.program(pass, last) } {
+                Ok(program) => passes.push(program),
+                Err(e) => {
+                    unsafe { Glcx::delete(passes) };
+                    return Err(format!("{} shader, pass {}: {}", preset.name, i + 1, e));
+                }
             }
         }
+        let old = std::mem::replace(&mut self.passes, passes);
+        unsafe { Glcx::delete(old) };
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    unsafe fn check_program_link(_gl: &Glcx, program: GLuint) {
-        let mut status = gl::FALSE as GLint;
-        gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
-        if status == (gl::TRUE as GLint) {
-            return;
+    unsafe fn program(&self, pass: &Pass, last: bool) -> Result<Program, String> {
+        let source = format!("{}{}", shaders::HEADER, pass.source);
+        let frag = compile_shader(gl::FRAGMENT_SHADER, &source)?;
+
+        let program = gl::CreateProgram();
+        gl::AttachShader(program, self.vert);
+        gl::AttachShader(program, frag);
+        for (location, name) in [(0, "position"), (1, "texcoord")] {
+            let name = CString::new(name).unwrap();
+            gl::BindAttribLocation(program, location, name.as_ptr());
+        }
+        let buf = CString::new("outColor").unwrap();
+        gl::BindFragDataLocation(program, 0, buf.as_ptr());
+        gl::LinkProgram(program);
+        // The program keeps what it needs from the shader
+        gl::DeleteShader(frag);
+        if let Err(e) = check_program_link(program) {
+            gl::DeleteProgram(program);
+            return Err(e);
         }
 
-        let mut len: GLint = 0;
-        gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
-        let mut buf = repeat(0u8).take(len as usize).collect::<Vec<_>>();
-        gl::GetProgramInfoLog(
+        let (mut framebuffer, mut texture) = (0, 0);
+        if !last {
+            texture = new_texture();
+            gl::GenFramebuffers(1, &mut framebuffer);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                texture,
+                0,
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        Ok(Program {
             program,
-            len,
-            ptr::null_mut(),
-            buf.as_mut_ptr() as *mut GLchar,
-        );
-        panic!("{}", str::from_utf8(&buf).unwrap());
+            filter: pass.filter,
+            scale: pass.scale,
+            framebuffer,
+            texture,
+            size: (0, 0),
+        })
     }
 
-    pub fn draw(&self, width: u32, height: u32, data: &[u8]) {
+    unsafe fn delete(passes: Vec<Program>) {
+        for pass in passes {
+            gl::DeleteProgram(pass.program);
+            if pass.framebuffer != 0 {
+                gl::DeleteFramebuffers(1, &pass.framebuffer);
+                gl::DeleteTextures(1, &pass.texture);
+            }
+        }
+    }
+
+    // Draws the screen, `window` being the size of the drawable area in pixels
+    pub fn draw(&mut self, window: (u32, u32), width: u32, height: u32, data: &[u8]) {
         unsafe {
-            gl::ClearColor(0.0, 0.0, 1.0, 1.0);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::Viewport(0, 0, window.0 as i32, window.1 as i32);
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
             gl::Clear(gl::COLOR_BUFFER_BIT);
+            let [x, y, w, h] = viewport(window, (width, height), self.integer_scaling);
 
+            gl::BindTexture(gl::TEXTURE_2D, self.tex);
             gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
@@ -316,7 +399,72 @@ impl Glcx {
             );
             assert_eq!(gl::GetError(), 0);
 
-            gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, std::ptr::null());
+            // Each pass reads what the one before drew
+            let mut source = (self.tex, (width, height));
+            let count = self.passes.len();
+            for (i, pass) in self.passes.iter_mut().enumerate() {
+                let output = match pass.scale {
+                    Some(scale) if i + 1 < count => (width * scale, height * scale),
+                    _ => (w as u32, h as u32),
+                };
+                if i + 1 < count {
+                    if pass.size != output {
+                        gl::BindTexture(gl::TEXTURE_2D, pass.texture);
+                        gl::TexImage2D(
+                            gl::TEXTURE_2D,
+                            0,
+                            gl::RGBA8 as i32,
+                            output.0 as i32,
+                            output.1 as i32,
+                            0,
+                            gl::RGBA,
+                            gl::UNSIGNED_BYTE,
+                            ptr::null(),
+                        );
+                        pass.size = output;
+                    }
+                    gl::BindFramebuffer(gl::FRAMEBUFFER, pass.framebuffer);
+                    gl::Viewport(0, 0, output.0 as i32, output.1 as i32);
+                } else {
+                    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                    gl::Viewport(x, y, w, h);
+                }
+
+                set_filter(source.0, pass.filter.unwrap_or(self.filter));
+                let program = pass.program;
+                gl::UseProgram(program);
+                gl::Uniform1i(uniform(program, "tex"), 0);
+                gl::Uniform1i(uniform(program, "flip"), (i > 0) as i32);
+                let (sw, sh) = source.1;
+                gl::Uniform2f(uniform(program, "source_size"), sw as f32, sh as f32);
+                gl::Uniform2f(
+                    uniform(program, "output_size"),
+                    output.0 as f32,
+                    output.1 as f32,
+                );
+                gl::Uniform2f(
+                    uniform(program, "screen_size"),
+                    width as f32,
+                    height as f32,
+                );
+
+                gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, ptr::null());
+                source = (pass.texture, output);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::viewport;
+
+    #[test]
+    fn letterboxes_the_screen() {
+        // Whole multiples only, centered
+        assert_eq!(viewport((800, 600), (160, 144), true), [80, 12, 640, 576]);
+        assert_eq!(viewport((800, 600), (160, 144), false), [66, 0, 667, 600]);
+        // Too small for even one pixel per pixel
+        assert_eq!(viewport((80, 144), (160, 144), true), [0, 36, 80, 72]);
+    }
+}
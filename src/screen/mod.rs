@@ -1,8 +1,16 @@
+pub mod controls;
+
 #[cfg(target_arch = "wasm32")]
 pub mod web;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod debugger;
+
 #[cfg(not(target_arch = "wasm32"))]
 pub mod desktop;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod text;
+
 #[cfg(not(target_arch = "wasm32"))]
 pub mod tui;
@@ -0,0 +1,245 @@
+use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+use std::env;
+
+// Draws the screen with plain characters, for terminals without any image
+// protocol or behind SSH and tmux.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum TextMode {
+    // Two pixels per cell, one in the foreground and one in the background
+    HalfBlock,
+    // Two by four dots per cell, dark pixels are drawn
+    Braille,
+    // Characters picked by brightness, two pixels per cell
+    Ascii,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ColorDepth {
+    TrueColor,
+    Indexed,
+}
+
+impl ColorDepth {
+    // Terminals advertise 24-bit color through COLORTERM, which does not
+    // always make it through SSH, everything else gets the 256 color palette
+    pub fn detect() -> ColorDepth {
+        match env::var("COLORTERM").as_deref() {
+            Ok("truecolor") | Ok("24bit") => ColorDepth::TrueColor,
+            _ => ColorDepth::Indexed,
+        }
+    }
+
+    fn color(self, [r, g, b]: [u8; 3]) -> Color {
+        match self {
+            ColorDepth::TrueColor => Color::Rgb(r, g, b),
+            ColorDepth::Indexed => Color::Indexed(xterm_index(r, g, b)),
+        }
+    }
+}
+
+// Closest entry of the xterm 256 color palette, either in the 6x6x6 color
+// cube or on the gray ramp
+fn xterm_index(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |v: u8| {
+        (0..6)
+            .min_by_key(|&i| (LEVELS[i] as i32 - v as i32).abs())
+            .unwrap()
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = [LEVELS[ri], LEVELS[gi], LEVELS[bi]];
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray_level = 8 + 10 * gray;
+
+    let distance = |[cr, cg, cb]: [u8; 3]| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(cr, r) + d(cg, g) + d(cb, b)
+    };
+    if distance([gray_level; 3]) < distance(cube) {
+        232 + gray
+    } else {
+        16 + 36 * ri as u8 + 6 * gi as u8 + bi as u8
+    }
+}
+
+fn luma([r, g, b]: [u8; 3]) -> u32 {
+    (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+}
+
+pub struct TextScreen<'a> {
+    // RGBA, as kept by the GPU
+    pub image: &'a [u8],
+    pub width: u32,
+    pub height: u32,
+    pub mode: TextMode,
+    pub depth: ColorDepth,
+}
+
+impl TextScreen<'_> {
+    fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let i = (y * self.width + x) as usize * 4;
+        [self.image[i], self.image[i + 1], self.image[i + 2]]
+    }
+
+    // Sub-cell resolution of each mode
+    fn dots(&self) -> (u16, u16) {
+        match self.mode {
+            TextMode::HalfBlock | TextMode::Ascii => (1, 2),
+            TextMode::Braille => (2, 4),
+        }
+    }
+}
+
+impl Widget for TextScreen<'_> {
+    // Scales the screen to fit `area` with square pixels, cells being about
+    // twice as tall as they are wide, and centers it
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let (dx, dy) = self.dots();
+        let (max_w, max_h) = (
+            area.width as u32 * dx as u32,
+            area.height as u32 * dy as u32,
+        );
+        if max_w == 0 || max_h == 0 {
+            return;
+        }
+        let (w, h) = if max_w * self.height <= max_h * self.width {
+            (max_w, (max_w * self.height / self.width).max(1))
+        } else {
+            ((max_h * self.width / self.height).max(1), max_h)
+        };
+        let cols = w.div_ceil(dx as u32) as u16;
+        let rows = h.div_ceil(dy as u32) as u16;
+        let left = area.x + (area.width - cols) / 2;
+        let top = area.y + (area.height - rows) / 2;
+
+        // Nearest neighbour, dots past the scaled screen stay blank
+        let sample = |x: u32, y: u32| -> Option<[u8; 3]> {
+            if x >= w || y >= h {
+                return None;
+            }
+            Some(self.pixel(x * self.width / w, y * self.height / h))
+        };
+
+        for row in 0..rows {
+            for col in 0..cols {
+                let (x, y) = (col as u32 * dx as u32, row as u32 * dy as u32);
+                let Some(cell) = buf.cell_mut((left + col, top + row)) else {
+                    continue;
+                };
+                match self.mode {
+                    TextMode::HalfBlock => {
+                        let upper = sample(x, y).unwrap_or_default();
+                        let lower = sample(x, y + 1).unwrap_or(upper);
+                        cell.set_char('▀')
+                            .set_fg(self.depth.color(upper))
+                            .set_bg(self.depth.color(lower));
+                    }
+                    TextMode::Ascii => {
+                        const RAMP: &[u8] = b"@%#*+=-:. ";
+                        let upper = sample(x, y).unwrap_or_default();
+                        let lower = sample(x, y + 1).unwrap_or(upper);
+                        let average = [0, 1, 2]
+                            .map(|i| ((upper[i] as u16 + lower[i] as u16) / 2) as u8);
+                        let shade = luma(average) as usize * RAMP.len() / 256;
+                        cell.set_char(RAMP[shade] as char)
+                            .set_fg(self.depth.color(average));
+                    }
+                    TextMode::Braille => {
+                        // Dot numbering of the Unicode braille patterns
+                        const BITS: [[u32; 2]; 4] =
+                            [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+                        let mut bits = 0;
+                        let mut ink: Option<[u8; 3]> = None;
+                        for (j, line) in BITS.iter().enumerate() {
+                            for (i, bit) in line.iter().enumerate() {
+                                let Some(p) = sample(x + i as u32, y + j as u32) else {
+                                    continue;
+                                };
+                                if luma(p) < 128 {
+                                    bits |= bit;
+                                    if ink.map_or(true, |c| luma(p) < luma(c)) {
+                                        ink = Some(p);
+                                    }
+                                }
+                            }
+                        }
+                        let c = char::from_u32(0x2800 + bits).unwrap();
+                        cell.set_char(c);
+                        if let Some(ink) = ink {
+                            cell.set_fg(self.depth.color(ink));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{xterm_index, ColorDepth, TextMode, TextScreen};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+
+    // Four by four, white with a black top left pixel and a red bottom row
+    fn image() -> Vec<u8> {
+        let mut image = vec![255; 4 * 4 * 4];
+        image[..3].copy_from_slice(&[0, 0, 0]);
+        for x in 0..4 {
+            image[(12 + x) * 4..(12 + x) * 4 + 3].copy_from_slice(&[255, 0, 0]);
+        }
+        image
+    }
+
+    fn render(mode: TextMode, area: Rect) -> Buffer {
+        let image = image();
+        let mut buf = Buffer::empty(area);
+        TextScreen {
+            image: &image,
+            width: 4,
+            height: 4,
+            mode,
+            depth: ColorDepth::TrueColor,
+        }
+        .render(area, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn half_blocks_pack_two_rows() {
+        let buf = render(TextMode::HalfBlock, Rect::new(0, 0, 4, 2));
+        let cell = &buf[(0, 0)];
+        assert_eq!(cell.symbol(), "▀");
+        assert_eq!(cell.fg, Color::Rgb(0, 0, 0));
+        assert_eq!(cell.bg, Color::Rgb(255, 255, 255));
+        assert_eq!(buf[(3, 1)].bg, Color::Rgb(255, 0, 0));
+
+        // Wider areas keep the aspect ratio and center the screen
+        let buf = render(TextMode::HalfBlock, Rect::new(0, 0, 10, 2));
+        assert_eq!(buf[(0, 0)].symbol(), " ");
+        assert_eq!(buf[(3, 0)].fg, Color::Rgb(0, 0, 0));
+    }
+
+    #[test]
+    fn braille_draws_dark_pixels() {
+        let buf = render(TextMode::Braille, Rect::new(0, 0, 2, 1));
+        // Top left dot plus the red row, which is dark enough
+        assert_eq!(buf[(0, 0)].symbol(), "\u{28C1}");
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 0, 0));
+        assert_eq!(buf[(1, 0)].symbol(), "\u{28C0}");
+
+        let buf = render(TextMode::Ascii, Rect::new(0, 0, 4, 2));
+        assert_eq!(buf[(1, 0)].symbol(), " ");
+        assert_eq!(buf[(0, 0)].symbol(), "+");
+    }
+
+    #[test]
+    fn maps_colors_to_the_xterm_palette() {
+        assert_eq!(xterm_index(0, 0, 0), 16);
+        assert_eq!(xterm_index(255, 255, 255), 231);
+        assert_eq!(xterm_index(255, 0, 0), 196);
+        assert_eq!(xterm_index(128, 128, 128), 244);
+        assert_eq!(ColorDepth::Indexed.color([0, 0, 0]), Color::Indexed(16));
+    }
+}
@@ -1,5 +1,10 @@
-use crate::gameboy::Gameboy;
+use crate::gameboy::{Gameboy, RenderOptions, TerminalRenderer};
 use crate::input::KeypadKey;
+use crate::keys::{Action, Hotkey, KeyBindings};
+use crate::scale::Scaler;
+use crate::screen::controls::Controls;
+use crate::screen::debugger::Debugger;
+use crate::screen::text::{ColorDepth, TextMode, TextScreen};
 use std::env;
 use std::{
     error::Error,
@@ -12,10 +17,13 @@ use ratatui::{
     crossterm::{
         event::{
             self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind,
+            KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+            PushKeyboardEnhancementFlags,
         },
         execute,
         terminal::{
-            disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+            disable_raw_mode, enable_raw_mode, supports_keyboard_enhancement,
+            EnterAlternateScreen, LeaveAlternateScreen,
         },
     },
     Terminal,
@@ -24,40 +32,63 @@ use ratatui::{
 use image::DynamicImage;
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
-    style::Color,
     text::{Line, Text},
     widgets::{Block, Borders, Paragraph, Wrap},
     Frame,
 };
 use ratatui_image::{
-    picker::Picker,
-    protocol::{Protocol, StatefulProtocol},
+    picker::{Picker, ProtocolType},
+    protocol::StatefulProtocol,
     Resize, StatefulImage,
 };
 
 const MAX_SCALE: u32 = 4;
 
-pub fn run(gameboy: &mut Gameboy) -> Result<(), Box<dyn Error>> {
+pub fn run(gameboy: &mut Gameboy, options: &RenderOptions) -> Result<(), Box<dyn Error>> {
+    // setup terminal
+    enable_raw_mode()?;
+    // Terminals speaking the kitty keyboard protocol report key releases,
+    // the others only repeat presses while a key is held
+    let enhanced = supports_keyboard_enhancement().unwrap_or(false);
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    if enhanced {
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                    | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+            )
+        )?;
+    }
+
     let original_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic| {
+        if enhanced {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
         disable_raw_mode().unwrap();
         ratatui::crossterm::execute!(io::stdout(), LeaveAlternateScreen).unwrap();
         original_hook(panic);
     }));
 
-    // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let app = App::new(&mut terminal, gameboy);
+    let mut app = App::new(&mut terminal, gameboy, options);
+    app.scale = options.scale.clamp(1, MAX_SCALE);
+    app.held.hold = match enhanced {
+        true => None,
+        false => Some(options.key_hold),
+    };
 
     // run app
     let res = run_app(&mut terminal, app, gameboy);
 
     // restore terminal
+    if enhanced {
+        execute!(terminal.backend_mut(), PopKeyboardEnhancementFlags)?;
+    }
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
@@ -80,7 +111,7 @@ fn run_app<B: Backend>(
 ) -> io::Result<()> {
     let mut last_tick = Instant::now();
     loop {
-        terminal.draw(|f| ui(f, &mut app))?;
+        terminal.draw(|f| ui(f, &mut app, gameboy))?;
 
         let timeout = app
             .tick_rate
@@ -88,31 +119,41 @@ fn run_app<B: Backend>(
             .unwrap_or_else(|| Duration::from_secs(0));
         if ratatui::crossterm::event::poll(timeout)? {
             if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    if let KeyCode::Char(c) = key.code {
-                        app.on_key(c, gameboy);
-                    } else if let KeyCode::Up = key.code {
-                        gameboy.keydown(KeypadKey::Up);
-                        app.last_key = Some(KeypadKey::Up);
-                    } else if let KeyCode::Down = key.code {
-                        gameboy.keydown(KeypadKey::Down);
-                        app.last_key = Some(KeypadKey::Down);
-                    } else if let KeyCode::Left = key.code {
-                        gameboy.keydown(KeypadKey::Left);
-                        app.last_key = Some(KeypadKey::Left);
-                    } else if let KeyCode::Right = key.code {
-                        gameboy.keydown(KeypadKey::Right);
-                        app.last_key = Some(KeypadKey::Right);
+                // The debugger takes its keys first, hex digits included
+                if app.debugger.visible
+                    && key.kind != KeyEventKind::Release
+                    && app.debugger.on_key(gameboy, &mut app.controls, key.code)
+                {
+                    continue;
+                }
+                let name = key_name(key.code)
+                    .filter(|name| app.controls.keys.action(name).is_some());
+                match (name, key.kind) {
+                    (Some(name), KeyEventKind::Release) => {
+                        if app.held.release(&name) {
+                            app.controls.key_up(gameboy, &name);
+                        }
+                    }
+                    (Some(name), _) => {
+                        if app.held.press(&name, Instant::now()) {
+                            app.controls.key_down(gameboy, &name);
+                        }
                     }
+                    (None, KeyEventKind::Press) => {
+                        if let KeyCode::Char(c) = key.code {
+                            app.on_key(c);
+                        }
+                    }
+                    (None, _) => {}
                 }
             }
         }
+        for name in app.held.expired(Instant::now()) {
+            app.controls.key_up(gameboy, &name);
+        }
         if last_tick.elapsed() >= app.tick_rate {
             app.on_tick(gameboy);
-            gameboy.frame();
-            if let Some(key) = app.last_key.take() {
-                gameboy.keyup(key);
-            }
+            app.debugger.run(gameboy, &mut app.controls);
             last_tick = Instant::now();
         }
         if app.should_quit {
@@ -121,35 +162,116 @@ fn run_app<B: Backend>(
     }
 }
 
+// Same names as the desktop frontend, see `KeyBindings`
+fn key_name(code: KeyCode) -> Option<String> {
+    let name = match code {
+        KeyCode::Char(' ') => String::from("Space"),
+        KeyCode::Char(c) if c.is_ascii_digit() => format!("Key{}", c),
+        KeyCode::Char(c) => c.to_ascii_uppercase().to_string(),
+        KeyCode::Enter => String::from("Return"),
+        KeyCode::Backspace => String::from("Back"),
+        KeyCode::Tab => String::from("Tab"),
+        KeyCode::Esc => String::from("Escape"),
+        KeyCode::Left => String::from("Left"),
+        KeyCode::Right => String::from("Right"),
+        KeyCode::Up => String::from("Up"),
+        KeyCode::Down => String::from("Down"),
+        KeyCode::F(n) => format!("F{}", n),
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Gap between repeats after which a repeating key counts as released
+const REPEAT_WINDOW: Duration = Duration::from_millis(150);
+
+// Bound keys currently held down. Without release events a key counts as
+// held for `hold` after it is pressed, long enough for the terminal to start
+// repeating it, and then for as long as the repeats keep coming.
+#[derive(Default)]
+struct HeldKeys {
+    hold: Option<Duration>,
+    // Each key with the time it expires at
+    keys: Vec<(String, Instant)>,
+}
+
+impl HeldKeys {
+    // Returns true for keys that were not already down
+    fn press(&mut self, name: &str, now: Instant) -> bool {
+        let hold = self.hold.unwrap_or_default();
+        match self.keys.iter_mut().find(|(key, _)| key == name) {
+            Some((_, expires)) => {
+                *expires = (*expires).max(now + REPEAT_WINDOW.min(hold));
+                false
+            }
+            None => {
+                self.keys.push((name.to_string(), now + hold));
+                true
+            }
+        }
+    }
+
+    fn release(&mut self, name: &str) -> bool {
+        let len = self.keys.len();
+        self.keys.retain(|(key, _)| key != name);
+        self.keys.len() != len
+    }
+
+    fn expired(&mut self, now: Instant) -> Vec<String> {
+        if self.hold.is_none() {
+            return Vec::new();
+        }
+        let (expired, held) = self
+            .keys
+            .drain(..)
+            .partition(|&(_, expires)| now >= expires);
+        self.keys = held;
+        expired.into_iter().map(|(key, _)| key).collect()
+    }
+}
+
+enum Renderer {
+    Image,
+    Text(TextMode),
+}
+
+// Terminal image protocol state, when the terminal has one
+struct Graphics {
+    picker: Picker,
+    image_fit_state: StatefulProtocol,
+}
+
 struct App {
     should_quit: bool,
     scale: u32,
-    last_key: Option<KeypadKey>,
+    controls: Controls,
+    debugger: Debugger,
+    held: HeldKeys,
     tick_rate: Duration,
     split_percent: u16,
 
     image_static_offset: (u16, u16),
 
-    picker: Picker,
+    renderer: Renderer,
+    depth: ColorDepth,
+    // Last frame as RGBA, for the text renderers, and its size
+    frame: Vec<u8>,
+    size: (u32, u32),
     image_source: DynamicImage,
-    image_static: Protocol,
-    image_fit_state: StatefulProtocol,
-}
-
-fn size() -> Rect {
-    Rect::new(0, 0, 30, 16)
+    graphics: Option<Graphics>,
 }
 
 #[inline]
-fn get_image(gameboy: &mut Gameboy, scale: u32) -> image::DynamicImage {
+fn get_image(gameboy: &mut Gameboy, scaler: Scaler, scale: u32) -> image::DynamicImage {
     // let harvest_moon = "/Users/rapha/harvest-moon.png";
     // image::io::Reader::open(harvest_moon).unwrap().decode().unwrap()
 
     let width = gameboy.width;
     let height = gameboy.height;
 
-    // Get the raw image data as a vector
-    let input: &[u8] = gameboy.image();
+    // The upscaler goes first, nearest neighbour covers the rest of the scale
+    let input = scaler.upscale(gameboy.image(), width, height);
+    let (width, height) = (width * scaler.factor(), height * scaler.factor());
 
     // Allocate a new buffer for the RGB image, 3 bytes per pixel
     let mut output_data = vec![0u8; width as usize * height as usize * 3];
@@ -163,53 +285,111 @@ fn get_image(gameboy: &mut Gameboy, scale: u32) -> image::DynamicImage {
     }
 
     let mut buffer = image::ImageBuffer::from_raw(width, height, output_data).unwrap();
-    if scale > 1 {
+    let target = gameboy.width * scale;
+    if target > width {
         buffer = image::imageops::resize(
             &buffer,
-            width * scale,
-            height * scale,
+            target,
+            gameboy.height * scale,
             image::imageops::FilterType::Nearest,
         );
     }
     image::DynamicImage::ImageRgb8(buffer)
 }
 
-impl App {
-    pub fn new<B: Backend>(_: &mut Terminal<B>, gameboy: &mut Gameboy) -> Self {
-        let image_source = get_image(gameboy, 1);
-
-        let mut picker = Picker::from_query_stdio().unwrap();
-        picker.set_background_color([0, 0, 0, 0]);
-
-        let image_static = picker
-            .new_protocol(image_source.clone(), size(), Resize::Fit(None))
-            .unwrap();
+impl Graphics {
+    fn new(picker: Picker, image_source: &DynamicImage) -> Graphics {
         let image_fit_state = picker.new_resize_protocol(image_source.clone());
+        Graphics {
+            picker,
+            image_fit_state,
+        }
+    }
+}
+
+impl App {
+    pub fn new<B: Backend>(
+        _: &mut Terminal<B>,
+        gameboy: &mut Gameboy,
+        options: &RenderOptions,
+    ) -> Self {
+        let image_source = get_image(gameboy, options.scaler, 1);
+
+        // The picker falls back to half blocks of its own when it finds no
+        // image protocol, the text renderer does that better
+        let picker = match options.terminal {
+            TerminalRenderer::Auto => Picker::from_query_stdio()
+                .ok()
+                .filter(|p| p.protocol_type() != ProtocolType::Halfblocks),
+            TerminalRenderer::Image => Picker::from_query_stdio().ok(),
+            _ => None,
+        };
+        let graphics = picker.map(|mut picker| {
+            picker.set_background_color([0, 0, 0, 0]);
+            Graphics::new(picker, &image_source)
+        });
+        let renderer = match options.terminal {
+            TerminalRenderer::Braille => Renderer::Text(TextMode::Braille),
+            TerminalRenderer::Ascii => Renderer::Text(TextMode::Ascii),
+            _ if graphics.is_some() => Renderer::Image,
+            _ => Renderer::Text(TextMode::HalfBlock),
+        };
 
         Self {
             should_quit: false,
             scale: 1,
             tick_rate: Duration::from_millis(5),
             split_percent: 40,
-            picker,
-            last_key: None,
+            controls: Controls::new(options),
+            debugger: Debugger::default(),
+            held: HeldKeys::default(),
+
+            renderer,
+            depth: ColorDepth::detect(),
+            frame: gameboy.image().to_vec(),
+            size: (gameboy.width, gameboy.height),
             image_source,
-
-            image_static,
-            image_fit_state,
+            graphics,
 
             image_static_offset: (0, 0),
         }
     }
-    pub fn on_key(&mut self, c: char, gameboy: &mut Gameboy) {
+    pub fn on_key(&mut self, c: char) {
         match c {
             'q' => {
                 self.should_quit = true;
             }
+            'd' => {
+                self.debugger.visible = !self.debugger.visible;
+            }
             'i' => {
-                self.picker
-                    .set_protocol_type(self.picker.protocol_type().next());
-                self.reset_images();
+                if let Some(ref mut graphics) = self.graphics {
+                    graphics
+                        .picker
+                        .set_protocol_type(graphics.picker.protocol_type().next());
+                    self.reset_images();
+                }
+            }
+            'm' => {
+                self.renderer = match self.renderer {
+                    Renderer::Image => Renderer::Text(TextMode::HalfBlock),
+                    Renderer::Text(TextMode::HalfBlock) => {
+                        Renderer::Text(TextMode::Braille)
+                    }
+                    Renderer::Text(TextMode::Braille) => Renderer::Text(TextMode::Ascii),
+                    Renderer::Text(TextMode::Ascii) if self.graphics.is_some() => {
+                        Renderer::Image
+                    }
+                    Renderer::Text(TextMode::Ascii) => {
+                        Renderer::Text(TextMode::HalfBlock)
+                    }
+                };
+            }
+            'u' => {
+                // Screenshots follow the terminal's upscaler
+                let scaler = self.controls.scaler;
+                let next = Scaler::ALL.iter().position(|&s| s == scaler).unwrap() + 1;
+                self.controls.scaler = Scaler::ALL[next % Scaler::ALL.len()];
             }
             'o' => {
                 if self.scale >= MAX_SCALE {
@@ -244,59 +424,59 @@ impl App {
             'l' => {
                 self.image_static_offset.0 += 1;
             }
-            'a' | 'A' => {
-                gameboy.keydown(KeypadKey::A);
-                self.last_key = Some(KeypadKey::A);
-            }
-            'b' | 'B' => {
-                gameboy.keydown(KeypadKey::B);
-                self.last_key = Some(KeypadKey::B);
-            }
-            'z' | 'Z' => {
-                gameboy.keydown(KeypadKey::Select);
-                self.last_key = Some(KeypadKey::Select);
-            }
-            'x' | 'X' => {
-                gameboy.keydown(KeypadKey::Start);
-                self.last_key = Some(KeypadKey::Start);
-            }
             _ => {}
         }
     }
 
     fn reset_images(&mut self) {
-        self.image_static = self
-            .picker
-            .new_protocol(self.image_source.clone(), size(), Resize::Fit(None))
-            .unwrap();
-        self.image_fit_state = self.picker.new_resize_protocol(self.image_source.clone());
+        if let Some(ref mut graphics) = self.graphics {
+            let picker = graphics.picker;
+            *graphics = Graphics::new(picker, &self.image_source);
+        }
     }
 
     #[inline]
     pub fn on_tick(&mut self, gameboy: &mut Gameboy) {
-        self.image_source = get_image(gameboy, self.scale);
-        self.image_static = self
-            .picker
-            .new_protocol(self.image_source.clone(), size(), Resize::Fit(None))
-            .unwrap();
-        self.image_fit_state = self.picker.new_resize_protocol(self.image_source.clone());
+        self.frame.copy_from_slice(gameboy.image());
+        if let Renderer::Image = self.renderer {
+            self.image_source = get_image(gameboy, self.controls.scaler, self.scale);
+            self.reset_images();
+        }
     }
 
-    fn render_resized_image(&mut self, f: &mut Frame<'_>, resize: Resize, area: Rect) {
+    fn render_screen(&mut self, f: &mut Frame<'_>, resize: Resize, area: Rect) {
         let title = format!(
             "Gameboy on {} terminal",
             env::var("TERM").unwrap_or("unknown".to_string())
         );
-        let (state, name, _color) = (&mut self.image_fit_state, title, Color::Black);
-        let block = block(&name);
+        let block = block(&title);
         let inner_area = block.inner(area);
-        let image = StatefulImage::default().resize(resize);
-        f.render_stateful_widget(image, inner_area, state);
+        match (&self.renderer, &mut self.graphics) {
+            (Renderer::Image, Some(graphics)) => {
+                let image = StatefulImage::default().resize(resize);
+                f.render_stateful_widget(
+                    image,
+                    inner_area,
+                    &mut graphics.image_fit_state,
+                );
+            }
+            (Renderer::Text(mode), _) => {
+                let screen = TextScreen {
+                    image: &self.frame,
+                    width: self.size.0,
+                    height: self.size.1,
+                    mode: *mode,
+                    depth: self.depth,
+                };
+                f.render_widget(screen, inner_area);
+            }
+            (Renderer::Image, None) => {}
+        }
         f.render_widget(block, area);
     }
 }
 
-fn ui(f: &mut Frame<'_>, app: &mut App) {
+fn ui(f: &mut Frame<'_>, app: &mut App, gameboy: &Gameboy) {
     let outer_block = Block::default();
 
     let chunks = Layout::default()
@@ -311,27 +491,62 @@ fn ui(f: &mut Frame<'_>, app: &mut App) {
         .split(outer_block.inner(f.area()));
     f.render_widget(outer_block, f.area());
 
-    app.render_resized_image(f, Resize::Fit(None), chunks[0]);
+    app.render_screen(f, Resize::Fit(None), chunks[0]);
+    if app.debugger.visible {
+        app.debugger.render(f, chunks[1], gameboy);
+        return;
+    }
 
     let block_right_bottom = block("Controls");
     let area = block_right_bottom.inner(chunks[1]);
-    f.render_widget(
-        paragraph(vec![
-            Line::from("Controls:"),
-            Line::from("arrows: movement"),
-            Line::from("Key a/A: A"),
-            Line::from("Key s/S: B"),
-            Line::from("Key z/Z: select"),
-            Line::from("Key x/X: start"),
-            Line::from("H/L: resize splits"),
-            Line::from(format!("o: scale image (current: {:?})", app.scale)),
-            Line::from(format!(
-                "i: cycle image protocols (current: {:?})",
-                app.picker.protocol_type()
-            )),
-        ]),
-        area,
-    );
+    let mut lines = vec![Line::from("Controls:")];
+    lines.extend(binding_lines(&app.controls.keys));
+    lines.extend([
+        Line::from("H/L: resize splits"),
+        Line::from(format!("o: scale image (current: {:?})", app.scale)),
+        Line::from(format!(
+            "u: cycle upscalers (current: {})",
+            app.controls.scaler.name()
+        )),
+        Line::from(match app.renderer {
+            Renderer::Image => String::from("m: cycle renderers (current: image)"),
+            Renderer::Text(mode) => format!("m: cycle renderers (current: {:?})", mode),
+        }),
+    ]);
+    if let Some(ref graphics) = app.graphics {
+        lines.push(Line::from(format!(
+            "i: cycle image protocols (current: {:?})",
+            graphics.picker.protocol_type()
+        )));
+    }
+    lines.push(Line::from("d: debugger"));
+    lines.push(Line::from("q: quit"));
+    if let Some(ref status) = app.controls.status {
+        lines.push(Line::from(""));
+        lines.push(Line::from(status.as_str()));
+    }
+    f.render_widget(paragraph(lines), area);
+}
+
+// One line per button and hotkey, with the keys currently bound to it
+fn binding_lines(keys: &KeyBindings) -> Vec<Line<'static>> {
+    let actions = KeypadKey::ALL
+        .into_iter()
+        .map(Action::Button)
+        .chain(Hotkey::ALL.into_iter().map(Action::Hotkey));
+    actions
+        .filter_map(|action| {
+            let bound: Vec<&str> = keys.keys(action).collect();
+            if bound.is_empty() {
+                return None;
+            }
+            Some(Line::from(format!(
+                "{}: {}",
+                bound.join("/"),
+                action.name()
+            )))
+        })
+        .collect()
 }
 
 fn paragraph<'a, T: Into<Text<'a>>>(str: T) -> Paragraph<'a> {
@@ -341,3 +556,40 @@ fn paragraph<'a, T: Into<Text<'a>>>(str: T) -> Paragraph<'a> {
 fn block(name: &str) -> Block<'_> {
     Block::default().borders(Borders::ALL).title(name)
 }
+
+#[cfg(test)]
+mod test {
+    use super::HeldKeys;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn held_keys_expire_without_repeats() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut held = HeldKeys {
+            hold: Some(ms(500)),
+            ..HeldKeys::default()
+        };
+        assert!(held.press("B", start));
+        assert!(held.press("Right", start + ms(100)));
+        // Repeats keep a key down, and only for a short while once they stop
+        for t in (600..1000).step_by(30) {
+            assert!(!held.press("Right", start + ms(t)));
+        }
+
+        assert!(held.expired(start + ms(450)).is_empty());
+        assert_eq!(held.expired(start + ms(550)), vec![String::from("B")]);
+        assert!(held.expired(start + ms(1000)).is_empty());
+        assert_eq!(held.expired(start + ms(1200)), vec![String::from("Right")]);
+    }
+
+    #[test]
+    fn released_keys_never_expire() {
+        let start = Instant::now();
+        let mut held = HeldKeys::default();
+        held.press("A", start);
+        assert!(held.expired(start + Duration::from_secs(10)).is_empty());
+        assert!(held.release("A"));
+        assert!(!held.release("A"));
+    }
+}
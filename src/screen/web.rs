@@ -1,9 +1,10 @@
 extern crate console_error_panic_hook;
 
-use crate::gameboy::Gameboy;
-use crate::input::KeypadKey;
+use crate::gameboy::{Gameboy, RenderOptions};
+use crate::scale::Scaler;
+use crate::screen::controls::Controls;
 
-use core::cell::{RefCell, RefMut};
+use core::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
@@ -37,9 +38,60 @@ fn request_animation_frame(f: &Closure<dyn FnMut()>) {
         .expect("should register `requestAnimationFrame` OK");
 }
 
+// Same names as the desktop frontend, see `KeyBindings`
+fn key_name(event: &web_sys::KeyboardEvent) -> Option<String> {
+    let key = event.key();
+    let name = match key.as_str() {
+        " " => String::from("Space"),
+        "Enter" => String::from("Return"),
+        "Backspace" => String::from("Back"),
+        "ArrowLeft" => String::from("Left"),
+        "ArrowRight" => String::from("Right"),
+        "ArrowUp" => String::from("Up"),
+        "ArrowDown" => String::from("Down"),
+        k if k.len() == 1 && k.as_bytes()[0].is_ascii_digit() => format!("Key{}", k),
+        k if k.len() == 1 => k.to_ascii_uppercase(),
+        k @ ("Tab" | "Escape") => k.to_string(),
+        k if k.starts_with('F') && k[1..].parse::<u8>().is_ok() => k.to_string(),
+        _ => return None,
+    };
+    Some(name)
+}
+
 // TODO: Move to WebGL tex2d
 #[wasm_bindgen]
 pub async fn render(rom: Vec<u8>) -> Result<(), JsValue> {
+    run(rom, RenderOptions::default())
+}
+
+// Like `render`, with bindings given one per line as `action=key,key`
+#[wasm_bindgen]
+pub async fn render_with_keys(rom: Vec<u8>, bindings: String) -> Result<(), JsValue> {
+    let mut options = RenderOptions::default();
+    for binding in bindings.lines().map(str::trim).filter(|b| !b.is_empty()) {
+        options
+            .keys
+            .apply(binding)
+            .map_err(|e| JsValue::from_str(&e))?;
+    }
+    run(rom, options)
+}
+
+// Like `render`, drawing through one of the upscalers of `Scaler` by name
+#[wasm_bindgen]
+pub async fn render_scaled(rom: Vec<u8>, scaler: String) -> Result<(), JsValue> {
+    let scaler = Scaler::from_name(&scaler)
+        .ok_or_else(|| JsValue::from_str(&format!("unknown scaler {:?}", scaler)))?;
+    run(
+        rom,
+        RenderOptions {
+            scaler,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+fn run(rom: Vec<u8>, options: RenderOptions) -> Result<(), JsValue> {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
 
     let document = web_sys::window().unwrap().document().unwrap();
@@ -49,8 +101,12 @@ pub async fn render(rom: Vec<u8>) -> Result<(), JsValue> {
         .create_element("canvas")?
         .dyn_into::<web_sys::HtmlCanvasElement>()?;
     game.unwrap().append_child(&canvas)?;
-    canvas.set_width(160);
-    canvas.set_height(144);
+    let gb = Rc::new(RefCell::new(Gameboy::new(rom, None)));
+    let (width, height) = (gb.borrow().width, gb.borrow().height);
+    let scaler = options.scaler;
+    let factor = scaler.factor();
+    canvas.set_width(width * factor);
+    canvas.set_height(height * factor);
 
     let context = canvas
         .get_context("2d")
@@ -59,63 +115,35 @@ pub async fn render(rom: Vec<u8>) -> Result<(), JsValue> {
         .dyn_into::<CanvasRenderingContext2d>()
         .unwrap();
 
-    // if let Ok((data, filepath)) = load_rom("./../pokemon-blue.gb") {
-    let mut gb = Gameboy::new(rom, None);
+    let controls = Rc::new(RefCell::new(Controls::new(&options)));
 
     let f = Rc::new(RefCell::new(None));
     let g = f.clone();
-    // let mut i = 0;
-    let current_key_code: Rc<RefCell<i32>> = Rc::new(RefCell::new(0));
-    gb.frame();
+    gb.borrow_mut().frame();
 
     {
-        let current_key_code = current_key_code.clone();
+        let gb = gb.clone();
+        let controls = controls.clone();
         *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-            // if i >= 10 {
-            //     let _ = f.borrow_mut().take();
-            //     return;
-            // }
-
-            // i += 1;
-
-            let key: RefMut<_> = current_key_code.borrow_mut();
-
-            // log_u32(*key);
-            match *key {
-                // A
-                65 => gb.keydown(KeypadKey::A),
-                -65 => gb.keyup(KeypadKey::A),
-                // S
-                83 => gb.keydown(KeypadKey::B),
-                -83 => gb.keyup(KeypadKey::B),
-                // Z
-                90 => gb.keydown(KeypadKey::Select),
-                -90 => gb.keyup(KeypadKey::Select),
-                // X
-                88 => gb.keydown(KeypadKey::Start),
-                -88 => gb.keyup(KeypadKey::Start),
-
-                // Left
-                37 => gb.keydown(KeypadKey::Left),
-                -37 => gb.keyup(KeypadKey::Left),
-                // Right
-                39 => gb.keydown(KeypadKey::Right),
-                -39 => gb.keyup(KeypadKey::Right),
-                // Up
-                38 => gb.keydown(KeypadKey::Up),
-                -38 => gb.keyup(KeypadKey::Up),
-                // Down
-                40 => gb.keydown(KeypadKey::Down),
-                -40 => gb.keyup(KeypadKey::Down),
-                _ => (),
+            let mut gb = gb.borrow_mut();
+            let mut controls = controls.borrow_mut();
+            controls.run(&mut gb);
+            if let Some(status) = controls.status.take() {
+                log(status);
             }
 
-            gb.frame();
-            let data: &mut [u8] = gb.image_mut();
+            let mut scaled;
+            let data: &mut [u8] = match scaler {
+                Scaler::Nearest => gb.image_mut(),
+                _ => {
+                    scaled = scaler.upscale(gb.image(), width, height);
+                    &mut scaled
+                }
+            };
             let _image_data = match ImageData::new_with_u8_clamped_array_and_sh(
                 wasm_bindgen::Clamped(data),
-                160,
-                144,
+                width * factor,
+                height * factor,
             ) {
                 Ok(d) => {
                     context.put_image_data(&d, 0.0, 0.0).ok();
@@ -128,28 +156,27 @@ pub async fn render(rom: Vec<u8>) -> Result<(), JsValue> {
             request_animation_frame(f.borrow().as_ref().unwrap());
         }) as Box<dyn FnMut()>));
     }
-    {
-        let current_key_code = current_key_code.clone();
-        let closure =
-            Closure::<dyn FnMut(_)>::new(move |event: web_sys::KeyboardEvent| {
-                let key = event.key_code();
-                *current_key_code.borrow_mut() = key as i32;
-            });
-        window().add_event_listener_with_callback(
-            "keydown",
-            closure.as_ref().unchecked_ref(),
-        )?;
-        closure.forget();
-    }
-    {
-        let current_key_code = current_key_code.clone();
+    for (listener, down) in [("keydown", true), ("keyup", false)] {
+        let gb = gb.clone();
+        let controls = controls.clone();
         let closure =
             Closure::<dyn FnMut(_)>::new(move |event: web_sys::KeyboardEvent| {
-                let key = event.key_code();
-                *current_key_code.borrow_mut() = (key as i32) * -1;
+                let Some(name) = key_name(&event) else {
+                    return;
+                };
+                let mut gb = gb.borrow_mut();
+                let mut controls = controls.borrow_mut();
+                let bound = match down {
+                    true => controls.key_down(&mut gb, &name),
+                    false => controls.key_up(&mut gb, &name),
+                };
+                // Keeps the page from scrolling or tabbing away
+                if bound {
+                    event.prevent_default();
+                }
             });
         window().add_event_listener_with_callback(
-            "keyup",
+            listener,
             closure.as_ref().unchecked_ref(),
         )?;
         closure.forget();
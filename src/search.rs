@@ -0,0 +1,277 @@
+use crate::gameboy::{Gameboy, MemoryBank};
+use std::ops::RangeInclusive;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum SearchRegion {
+    Wram,
+    Hram,
+    CartRam,
+}
+
+impl SearchRegion {
+    // Every bank of the region with the addresses it is mapped at, so banks
+    // the game has switched away from, or RAM it has disabled, are scanned too.
+    fn banks(self, gameboy: &Gameboy) -> Vec<(Option<MemoryBank>, RangeInclusive<u16>)> {
+        match self {
+            SearchRegion::Wram => {
+                let banks = if gameboy.is_color() { 7 } else { 1 };
+                let mut wram = vec![(Some(MemoryBank::Wram(0)), 0xC000..=0xCFFF)];
+                wram.extend(
+                    (1..=banks).map(|n| (Some(MemoryBank::Wram(n)), 0xD000..=0xDFFF)),
+                );
+                wram
+            }
+            SearchRegion::Hram => vec![(None, 0xFF80..=0xFFFE)],
+            SearchRegion::CartRam => (0..)
+                .map(MemoryBank::CartRam)
+                .take_while(|&bank| gameboy.peek_bank(bank, 0).is_some())
+                .map(|bank| (Some(bank), 0xA000..=0xBFFF))
+                .collect(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum SearchWidth {
+    Byte,
+    Word,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum SearchFilter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u16),
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Candidate {
+    pub address: u16,
+    // The bank `address` is in, for banked regions
+    pub bank: Option<MemoryBank>,
+    pub value: u16,
+}
+
+pub struct MemorySearch {
+    width: SearchWidth,
+    candidates: Vec<Candidate>,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Watch {
+    pub address: u16,
+    pub bank: Option<MemoryBank>,
+    pub width: SearchWidth,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Cheat {
+    pub address: u16,
+    pub bank: Option<MemoryBank>,
+    pub width: SearchWidth,
+    pub value: u16,
+}
+
+// Where the CPU sees the start of a bank
+fn window(bank: MemoryBank) -> u16 {
+    match bank {
+        MemoryBank::Rom(0) => 0x0000,
+        MemoryBank::Rom(_) => 0x4000,
+        MemoryBank::Vram(_) => 0x8000,
+        MemoryBank::CartRam(_) => 0xA000,
+        MemoryBank::Wram(0) => 0xC000,
+        MemoryBank::Wram(_) => 0xD000,
+    }
+}
+
+fn peek(gameboy: &Gameboy, bank: Option<MemoryBank>, address: u16) -> u8 {
+    match bank {
+        Some(bank) => gameboy
+            .peek_bank(bank, address.wrapping_sub(window(bank)))
+            .unwrap_or(0xFF),
+        None => gameboy.peek(address),
+    }
+}
+
+fn poke(gameboy: &mut Gameboy, bank: Option<MemoryBank>, address: u16, value: u8) {
+    match bank {
+        Some(bank) => {
+            gameboy.poke_bank(bank, address.wrapping_sub(window(bank)), value);
+        }
+        None => gameboy.poke(address, value),
+    }
+}
+
+fn read(
+    gameboy: &Gameboy,
+    bank: Option<MemoryBank>,
+    address: u16,
+    width: SearchWidth,
+) -> u16 {
+    match width {
+        SearchWidth::Byte => peek(gameboy, bank, address) as u16,
+        SearchWidth::Word => {
+            (peek(gameboy, bank, address) as u16)
+                | ((peek(gameboy, bank, address.wrapping_add(1)) as u16) << 8)
+        }
+    }
+}
+
+impl MemorySearch {
+    // Snapshots every address of the given regions. Each snapshot is the
+    // baseline the next `filter` call compares against.
+    pub fn new(gameboy: &Gameboy, regions: &[SearchRegion], width: SearchWidth) -> Self {
+        let mut candidates = Vec::new();
+        for region in regions {
+            // Words stay within a bank
+            for (bank, range) in region.banks(gameboy) {
+                let end = match width {
+                    SearchWidth::Byte => *range.end(),
+                    SearchWidth::Word => *range.end() - 1,
+                };
+                for address in *range.start()..=end {
+                    candidates.push(Candidate {
+                        address,
+                        bank,
+                        value: read(gameboy, bank, address, width),
+                    });
+                }
+            }
+        }
+
+        MemorySearch { width, candidates }
+    }
+
+    pub fn filter(&mut self, gameboy: &Gameboy, filter: SearchFilter) -> usize {
+        let width = self.width;
+        self.candidates.retain_mut(|candidate| {
+            let current = read(gameboy, candidate.bank, candidate.address, width);
+            let keep = match filter {
+                SearchFilter::Equal => current == candidate.value,
+                SearchFilter::Changed => current != candidate.value,
+                SearchFilter::Increased => current > candidate.value,
+                SearchFilter::Decreased => current < candidate.value,
+                SearchFilter::Value(v) => current == v,
+            };
+            candidate.value = current;
+            keep
+        });
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    pub fn width(&self) -> SearchWidth {
+        self.width
+    }
+
+    pub fn watch(&self, candidate: &Candidate) -> Watch {
+        Watch {
+            address: candidate.address,
+            bank: candidate.bank,
+            width: self.width,
+        }
+    }
+
+    pub fn cheat(&self, candidate: &Candidate, value: u16) -> Cheat {
+        Cheat {
+            address: candidate.address,
+            bank: candidate.bank,
+            width: self.width,
+            value,
+        }
+    }
+}
+
+impl Watch {
+    pub fn read(&self, gameboy: &Gameboy) -> u16 {
+        read(gameboy, self.bank, self.address, self.width)
+    }
+}
+
+impl Cheat {
+    pub fn apply(&self, gameboy: &mut Gameboy) {
+        let [low, high] = self.value.to_le_bytes();
+        poke(gameboy, self.bank, self.address, low);
+        if self.width == SearchWidth::Word {
+            poke(gameboy, self.bank, self.address.wrapping_add(1), high);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MemorySearch, SearchFilter, SearchRegion, SearchWidth};
+    use crate::gameboy::{Gameboy, MemoryBank};
+
+    fn gameboy() -> Gameboy {
+        Gameboy::new(vec![0; 0x8000], None)
+    }
+
+    #[test]
+    fn narrows_down_changed_byte() {
+        let mut gb = gameboy();
+        let mut search = MemorySearch::new(&gb, &[SearchRegion::Hram], SearchWidth::Byte);
+        assert_eq!(search.candidates().len(), 0x7F);
+
+        gb.poke(0xFF90, gb.peek(0xFF90).wrapping_add(1));
+        assert_eq!(search.filter(&gb, SearchFilter::Changed), 1);
+        assert_eq!(search.candidates()[0].address, 0xFF90);
+    }
+
+    #[test]
+    fn word_value_and_cheat() {
+        let mut gb = gameboy();
+        gb.poke(0xC100, 0x34);
+        gb.poke(0xC101, 0x12);
+
+        let mut search = MemorySearch::new(&gb, &[SearchRegion::Wram], SearchWidth::Word);
+        search.filter(&gb, SearchFilter::Value(0x1234));
+        let candidate = search.candidates()[0];
+        assert_eq!(candidate.address, 0xC100);
+
+        let cheat = search.cheat(&candidate, 999);
+        gb.add_cheat(cheat);
+        gb.frame();
+        assert_eq!(search.watch(&candidate).read(&gb), 999);
+    }
+
+    #[test]
+    fn scans_every_bank() {
+        // CGB game on an MBC1 with 4 banks of RAM, left disabled
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x03;
+        let mut gb = Gameboy::new(rom, None);
+        assert_eq!(gb.peek(0xA010), 0xFF);
+        gb.poke_bank(MemoryBank::CartRam(2), 0x10, 0x5A);
+        gb.poke_bank(MemoryBank::Wram(5), 0x20, 0x5A);
+
+        let regions = [SearchRegion::Wram, SearchRegion::CartRam];
+        let mut search = MemorySearch::new(&gb, &regions, SearchWidth::Byte);
+        assert_eq!(search.candidates().len(), 8 * 0x1000 + 4 * 0x2000);
+        search.filter(&gb, SearchFilter::Value(0x5A));
+        let found: Vec<_> = search
+            .candidates()
+            .iter()
+            .map(|c| (c.address, c.bank))
+            .collect();
+        assert!(found.contains(&(0xD020, Some(MemoryBank::Wram(5)))));
+        assert!(found.contains(&(0xA010, Some(MemoryBank::CartRam(2)))));
+
+        let candidate = search
+            .candidates()
+            .iter()
+            .find(|c| c.bank == Some(MemoryBank::CartRam(2)))
+            .copied()
+            .unwrap();
+        search.cheat(&candidate, 0x77).apply(&mut gb);
+        assert_eq!(gb.peek_bank(MemoryBank::CartRam(2), 0x10), Some(0x77));
+        assert_eq!(search.watch(&candidate).read(&gb), 0x77);
+    }
+}
@@ -0,0 +1,567 @@
+use crate::gpu::{self, ColorCorrection, Gpu};
+use crate::mmu::StrResult;
+use crate::state::{StateReader, StateWriter};
+
+// The SGB picture, with the Game Boy screen in the middle of the border
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 224;
+const LEFT: usize = 48;
+const TOP: usize = 40;
+
+// The SGB only listens to cartridges that say they support it
+pub fn supported(rom: impl Fn(u16) -> u8) -> bool {
+    rom(0x146) == 0x03 && rom(0x14B) == 0x33
+}
+
+// Receives command packets sent through the P14 and P15 lines of the joypad
+// register. Pulling both low starts a packet, then each pulse of one of them
+// is a bit, P15 for a one, least significant first. A packet is 16 bytes and a
+// stop bit, and the first byte of a command tells how many packets it spans.
+#[derive(Default)]
+pub struct Packets {
+    packet: [u8; 16],
+    // Bits of the current packet received so far, None between packets
+    bit: Option<usize>,
+    // Lines written last
+    lines: u8,
+    command: Vec<u8>,
+    // Complete commands for the SGB to run, MLT_REQ is handled here
+    pub received: Vec<Vec<u8>>,
+    // Joypads asked for by MLT_REQ, minus one, and the one being read
+    players: u8,
+    pub player: u8,
+}
+
+impl Packets {
+    // `lines` are bits 4 and 5 of the value written to the joypad register
+    pub fn write(&mut self, lines: u8) {
+        let previous = std::mem::replace(&mut self.lines, lines);
+        match (self.bit, lines) {
+            (_, 0x00) => {
+                self.packet = [0; 16];
+                self.bit = Some(0);
+            }
+            (Some(bit), 0x10 | 0x20) if previous == 0x30 => {
+                if bit == 128 {
+                    self.bit = None;
+                    self.receive();
+                    return;
+                }
+                if lines == 0x10 {
+                    self.packet[bit / 8] |= 1 << (bit % 8);
+                }
+                self.bit = Some(bit + 1);
+            }
+            // Reading the next joypad, as P15 goes back high
+            (None, 0x30) if previous & 0x20 == 0 && previous != 0 => {
+                self.player = (self.player + 1) & self.players;
+            }
+            _ => {}
+        }
+    }
+
+    fn receive(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() < packets * 16 {
+            return;
+        }
+        let command = std::mem::take(&mut self.command);
+        if command[0] >> 3 == 0x11 {
+            self.players = match command[1] & 0x03 {
+                1 => 1,
+                3 => 3,
+                _ => 0,
+            };
+            self.player = 0;
+        }
+        self.received.push(command);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.packet);
+        w.u8(self.bit.map_or(0xFF, |bit| bit as u8));
+        w.u8(self.lines);
+        w.buffer(&self.command);
+        w.u8(self.players);
+        w.u8(self.player);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        r.bytes(&mut self.packet)?;
+        self.bit = match r.u8()? {
+            0xFF => None,
+            bit => Some(bit as usize),
+        };
+        self.lines = r.u8()?;
+        let len = r.u32()? as usize;
+        if len > 7 * 16 {
+            return Err("Save state has an invalid SGB packet");
+        }
+        self.command = vec![0; len];
+        r.bytes(&mut self.command)?;
+        self.players = r.u8()?;
+        self.player = r.u8()?;
+        Ok(())
+    }
+}
+
+// Data the SGB copies out of the screen, see `Gpu::screen_tiles`
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Transfer {
+    Palettes,
+    // Which half of the 256 tiles
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+// The 1-A palette the SGB starts with
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+pub struct Sgb {
+    // Colors of the Game Boy screen, color 0 of the first one is used for
+    // color 0 everywhere
+    palettes: [[u16; 4]; 4],
+    // Palette of each 8x8 cell of the screen, 20 by 18
+    attributes: [u8; 360],
+    // Sent by PAL_TRN and ATTR_TRN, picked from by PAL_SET and ATTR_SET
+    system_palettes: Vec<[u16; 4]>,
+    attribute_files: Vec<u8>,
+    // Border tiles in the SNES 4 bits per pixel format, then its map of 32x32
+    // entries and palettes 4 to 7
+    tiles: Vec<u8>,
+    border: Vec<u8>,
+    // MASK_EN: 0 shows the screen, 1 freezes it, 2 blacks it out, 3 fills it
+    // with color 0
+    mask: u8,
+    transfer: Option<Transfer>,
+    pub frame: Box<[u8; WIDTH * HEIGHT * 4]>,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Sgb {
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0; 360],
+            system_palettes: vec![[0; 4]; 512],
+            attribute_files: vec![0; 45 * 90],
+            tiles: vec![0; 256 * 32],
+            border: vec![0; 0x880],
+            mask: 0,
+            transfer: None,
+            frame: Box::new([0xFF; WIDTH * HEIGHT * 4]),
+        }
+    }
+}
+
+impl Sgb {
+    pub fn command(&mut self, data: &[u8]) {
+        let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        match data[0] >> 3 {
+            // PAL01, PAL23, PAL03 and PAL12
+            command @ 0x00..=0x03 => {
+                let (a, b) = [(0, 1), (2, 3), (0, 3), (1, 2)][command as usize];
+                self.palettes[0][0] = word(1);
+                for i in 0..3 {
+                    self.palettes[a][i + 1] = word(3 + i * 2);
+                    self.palettes[b][i + 1] = word(9 + i * 2);
+                }
+            }
+            0x04 => self.attribute_blocks(data),
+            0x05 => self.attribute_lines(data),
+            0x06 => self.attribute_divide(data),
+            0x07 => self.attribute_characters(data),
+            // PAL_SET
+            0x0A => {
+                for (i, palette) in self.palettes.iter_mut().enumerate() {
+                    *palette = self.system_palettes[word(1 + i * 2) as usize & 0x1FF];
+                }
+                if data[9] & 0x80 != 0 {
+                    self.attribute_file(data[9] & 0x3F);
+                }
+                if data[9] & 0x40 != 0 {
+                    self.mask = 0;
+                }
+            }
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x13 => self.transfer = Some(Transfer::Tiles(data[1] as usize & 0x01)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            // ATTR_SET
+            0x16 => {
+                self.attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = 0;
+                }
+            }
+            // MASK_EN
+            0x17 => self.mask = data[1] & 0x03,
+            // Sound, SNES code and the rest are not emulated
+            _ => {}
+        }
+    }
+
+    fn fill(
+        &mut self,
+        x: impl Fn(usize) -> bool,
+        y: impl Fn(usize) -> bool,
+        palette: u8,
+    ) {
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            if x(i % 20) && y(i / 20) {
+                *attribute = palette & 0x03;
+            }
+        }
+    }
+
+    // ATTR_BLK, rectangles with a palette inside, on their border and outside
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        for set in data[2..].chunks(6).take(data[1] as usize & 0x1F) {
+            if set.len() < 6 {
+                break;
+            }
+            let (control, palettes) = (set[0] & 0x07, set[1]);
+            let [x1, y1, x2, y2] =
+                [set[2], set[3], set[4], set[5]].map(|c| c as usize & 0x1F);
+            let [inside, border, outside] =
+                [0, 2, 4].map(|shift| (palettes >> shift) & 0x03);
+            // With only the inside or the outside set, the border goes along
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some(border),
+                _ => None,
+            };
+            for y in 0..18 {
+                for x in 0..20 {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = match (within, edge) {
+                        (true, true) => border,
+                        (true, false) => (control & 0x01 != 0).then_some(inside),
+                        (false, _) => (control & 0x04 != 0).then_some(outside),
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * 20 + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN, whole rows or columns
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let n = line as usize & 0x1F;
+            let palette = (line >> 5) & 0x03;
+            match line & 0x80 != 0 {
+                true => self.fill(|_| true, |y| y == n, palette),
+                false => self.fill(|x| x == n, |_| true, palette),
+            }
+        }
+    }
+
+    // ATTR_DIV, two halves and the line between them
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on = (data[1] >> 4) & 0x03;
+        let n = data[2] as usize & 0x1F;
+        if data[1] & 0x40 != 0 {
+            self.fill(|_| true, |y| y < n, before);
+            self.fill(|_| true, |y| y == n, on);
+            self.fill(|_| true, |y| y > n, after);
+        } else {
+            self.fill(|x| x < n, |_| true, before);
+            self.fill(|x| x == n, |_| true, on);
+            self.fill(|x| x > n, |_| true, after);
+        }
+    }
+
+    // ATTR_CHR, one cell after the other from a starting point
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize % 20, data[2] as usize % 18);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(360);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let Some(&byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            self.attributes[y * 20 + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == 18 {
+                    y = 0;
+                    x = (x + 1) % 20;
+                }
+            } else {
+                x += 1;
+                if x == 20 {
+                    x = 0;
+                    y = (y + 1) % 18;
+                }
+            }
+        }
+    }
+
+    // One of the 45 files of ATTR_TRN, 2 bits per cell with the first cell in
+    // the top bits
+    fn attribute_file(&mut self, n: u8) {
+        let Some(file) = self.attribute_files.chunks(90).nth(n as usize) else {
+            return;
+        };
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (file[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+        }
+    }
+
+    // Called as a frame ends
+    pub fn vblank(&mut self, gpu: &Gpu) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = gpu.screen_tiles();
+            match transfer {
+                Transfer::Palettes => {
+                    for (palette, colors) in
+                        self.system_palettes.iter_mut().zip(data.chunks(8))
+                    {
+                        for (i, color) in palette.iter_mut().enumerate() {
+                            *color =
+                                u16::from_le_bytes([colors[i * 2], colors[i * 2 + 1]]);
+                        }
+                    }
+                }
+                Transfer::Tiles(half) => {
+                    self.tiles[half * 0x1000..][..0x1000].copy_from_slice(&data);
+                }
+                Transfer::Border => self.border.copy_from_slice(&data[..0x880]),
+                Transfer::Attributes => {
+                    self.attribute_files.copy_from_slice(&data[..45 * 90]);
+                }
+            }
+        }
+        self.draw(gpu.shades());
+    }
+
+    fn draw(&mut self, shades: &[u8]) {
+        let rgb = |color: u16| {
+            let channel = |shift: u16| ((color >> shift) & 0x1F) as u8;
+            ColorCorrection::None.apply(channel(0), channel(5), channel(10))
+        };
+        let backdrop = rgb(self.palettes[0][0]);
+        let mut put = |x: usize, y: usize, color: [u8; 3]| {
+            let i = (y * WIDTH + x) * 4;
+            self.frame[i..i + 3].copy_from_slice(&color);
+        };
+
+        // A frozen screen keeps the last frame
+        if self.mask != 1 {
+            for y in 0..gpu::HEIGHT {
+                for x in 0..gpu::WIDTH {
+                    let shade = shades[y * gpu::WIDTH + x] as usize;
+                    let palette = self.attributes[(y / 8) * 20 + x / 8] as usize;
+                    let color = match (self.mask, shade) {
+                        (2, _) => [0, 0, 0],
+                        (3, _) | (_, 0) => backdrop,
+                        _ => rgb(self.palettes[palette][shade]),
+                    };
+                    put(LEFT + x, TOP + y, color);
+                }
+            }
+        }
+
+        // Transparent border pixels show color 0, the screen is drawn over
+        // whatever the border has under it
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let screen = (LEFT..LEFT + gpu::WIDTH).contains(&x)
+                    && (TOP..TOP + gpu::HEIGHT).contains(&y);
+                if screen {
+                    continue;
+                }
+                let i = ((y / 8) * 32 + x / 8) * 2;
+                let entry = u16::from_le_bytes([self.border[i], self.border[i + 1]]);
+                let tile = &self.tiles[(entry as usize & 0xFF) * 32..][..32];
+                let px = if entry & 0x4000 != 0 {
+                    7 - x % 8
+                } else {
+                    x % 8
+                };
+                let py = if entry & 0x8000 != 0 {
+                    7 - y % 8
+                } else {
+                    y % 8
+                };
+                let bit = 7 - px;
+                let index = [
+                    tile[py * 2],
+                    tile[py * 2 + 1],
+                    tile[16 + py * 2],
+                    tile[17 + py * 2],
+                ]
+                .iter()
+                .enumerate()
+                .fold(0, |index, (plane, byte)| {
+                    index | ((byte >> bit) & 1) << plane
+                });
+                let color = match index {
+                    0 => backdrop,
+                    _ => {
+                        let palette = (entry as usize >> 10) & 0x03;
+                        let at = 0x800 + (palette * 16 + index as usize) * 2;
+                        rgb(u16::from_le_bytes([self.border[at], self.border[at + 1]]))
+                    }
+                };
+                put(x, y, color);
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for color in self.palettes.iter().chain(&self.system_palettes).flatten() {
+            w.u16(*color);
+        }
+        w.bytes(&self.attributes);
+        w.bytes(&self.attribute_files);
+        w.bytes(&self.tiles);
+        w.bytes(&self.border);
+        w.u8(self.mask);
+        w.u8(match self.transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::Tiles(half)) => 2 + half as u8,
+            Some(Transfer::Border) => 4,
+            Some(Transfer::Attributes) => 5,
+        });
+        w.bytes(&self.frame[..]);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        for color in self
+            .palettes
+            .iter_mut()
+            .chain(self.system_palettes.iter_mut())
+            .flatten()
+        {
+            *color = r.u16()?;
+        }
+        r.bytes(&mut self.attributes)?;
+        r.bytes(&mut self.attribute_files)?;
+        r.bytes(&mut self.tiles)?;
+        r.bytes(&mut self.border)?;
+        self.mask = r.u8()?;
+        self.transfer = match r.u8()? {
+            1 => Some(Transfer::Palettes),
+            half @ 2..=3 => Some(Transfer::Tiles(half as usize - 2)),
+            4 => Some(Transfer::Border),
+            5 => Some(Transfer::Attributes),
+            _ => None,
+        };
+        r.bytes(&mut self.frame[..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Packets, Sgb, HEIGHT, LEFT, TOP, WIDTH};
+    use crate::gpu;
+    use crate::input::Keypad;
+
+    // Pulses the joypad lines the way games do
+    fn send(keypad: &mut Keypad, packet: [u8; 16]) {
+        keypad.wb(0x00);
+        keypad.wb(0x30);
+        for bit in 0..128 {
+            let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+            keypad.wb(if one { 0x10 } else { 0x20 });
+            keypad.wb(0x30);
+        }
+        keypad.wb(0x20);
+        keypad.wb(0x30);
+    }
+
+    fn pixel(sgb: &Sgb, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * WIDTH + x) * 4;
+        [sgb.frame[i], sgb.frame[i + 1], sgb.frame[i + 2]]
+    }
+
+    #[test]
+    fn receives_packets_through_the_joypad() {
+        let mut keypad = Keypad::default();
+        keypad.sgb = Some(Packets::default());
+        let mut pal01 = [0; 16];
+        pal01[0] = 0x01;
+        pal01[1] = 0x1F;
+        send(&mut keypad, pal01);
+        let packets = keypad.sgb.as_mut().unwrap();
+        assert_eq!(std::mem::take(&mut packets.received), vec![pal01.to_vec()]);
+
+        // MLT_REQ for two players, the ID in the low bits counts down
+        let mut mlt_req = [0; 16];
+        mlt_req[0] = 0x11 << 3 | 1;
+        mlt_req[1] = 0x01;
+        send(&mut keypad, mlt_req);
+        assert_eq!(keypad.rb() & 0x0F, 0x0F);
+        // Selecting and releasing P15 moves on to the next joypad
+        keypad.wb(0x10);
+        keypad.wb(0x30);
+        assert_eq!(keypad.rb() & 0x0F, 0x0E);
+        keypad.wb(0x20);
+        keypad.wb(0x30);
+        assert_eq!(keypad.rb() & 0x0F, 0x0E);
+        keypad.wb(0x10);
+        keypad.wb(0x30);
+        assert_eq!(keypad.rb() & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn colors_the_screen_by_attributes() {
+        let mut sgb = Sgb::default();
+        // PAL01 with a red palette 0 and a blue palette 1
+        let mut pal01 = [0; 16];
+        pal01[0] = 0x01;
+        pal01[1..3].copy_from_slice(&0x7FFFu16.to_le_bytes());
+        pal01[3..5].copy_from_slice(&0x001Fu16.to_le_bytes());
+        pal01[9..11].copy_from_slice(&0x7C00u16.to_le_bytes());
+        sgb.command(&pal01);
+        // ATTR_BLK giving palette 1 to the left half
+        let mut attr_blk = [0; 16];
+        attr_blk[0] = 0x04 << 3 | 1;
+        attr_blk[1] = 1;
+        attr_blk[2..8].copy_from_slice(&[0x01, 0x01, 0, 0, 9, 17]);
+        sgb.command(&attr_blk);
+
+        let mut shades = vec![1; gpu::WIDTH * gpu::HEIGHT];
+        shades[0] = 0;
+        sgb.draw(&shades);
+        assert_eq!(pixel(&sgb, LEFT, TOP), [255, 255, 255]);
+        assert_eq!(pixel(&sgb, LEFT + 1, TOP), [0, 0, 255]);
+        assert_eq!(pixel(&sgb, LEFT + 80, TOP + 143), [255, 0, 0]);
+        // An empty border shows color 0
+        assert_eq!(pixel(&sgb, 0, 0), [255, 255, 255]);
+        assert_eq!(pixel(&sgb, WIDTH - 1, HEIGHT - 1), [255, 255, 255]);
+
+        // MASK_EN blacking out the screen
+        sgb.command(&[
+            0x17 << 3 | 1,
+            0x02,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        sgb.draw(&shades);
+        assert_eq!(pixel(&sgb, LEFT + 80, TOP + 143), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::gameboy::Filter;
+use std::path::Path;
+
+// Prepended to every pass. Passes read the previous one, or the Game Boy
+// screen for the first, through `tex` and write `outColor`. Sizes are in
+// pixels, `screen_size` being the Game Boy screen whatever the pass reads.
+pub const HEADER: &str = "#version 150 core
+in vec2 Texcoord;
+out vec4 outColor;
+uniform sampler2D tex;
+uniform vec2 source_size;
+uniform vec2 output_size;
+uniform vec2 screen_size;
+#line 1
+";
+
+// One fragment shader run over the whole output.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Pass {
+    pub source: String,
+    // How the input is sampled, the frontend's filter if unset
+    pub filter: Option<Filter>,
+    // Renders at this multiple of the screen size rather than straight to the
+    // window, for the passes after it to read
+    pub scale: Option<u32>,
+}
+
+impl Pass {
+    // Settings are read from comments in the source, `// filter: linear` and
+    // `// scale: 4`
+    pub fn parse(source: &str) -> Result<Pass, String> {
+        let mut pass = Pass {
+            source: source.to_string(),
+            filter: None,
+            scale: None,
+        };
+        for line in source.lines() {
+            let Some((key, value)) = line
+                .trim()
+                .strip_prefix("//")
+                .and_then(|comment| comment.split_once(':'))
+            else {
+                continue;
+            };
+            match (key.trim(), value.trim()) {
+                ("filter", "nearest") => pass.filter = Some(Filter::Nearest),
+                ("filter", "linear") => pass.filter = Some(Filter::Linear),
+                ("filter", value) => return Err(format!("unknown filter {:?}", value)),
+                ("scale", value) => match value.parse() {
+                    Ok(scale) if scale > 0 => pass.scale = Some(scale),
+                    _ => return Err(format!("invalid scale {:?}", value)),
+                },
+                _ => {}
+            }
+        }
+        Ok(pass)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub passes: Vec<Pass>,
+}
+
+impl Preset {
+    fn builtin(name: &str, passes: &[&str]) -> Preset {
+        Preset {
+            name: name.to_string(),
+            passes: passes.iter().map(|p| Pass::parse(p).unwrap()).collect(),
+        }
+    }
+
+    // Reads a single `.glsl` file, or a directory whose `.glsl` files are run
+    // in the order of their names
+    pub fn load(path: &Path) -> Result<Preset, String> {
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut files = Vec::new();
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path).map_err(error)? {
+                let file = entry.map_err(error)?.path();
+                if file.extension().is_some_and(|e| e == "glsl") {
+                    files.push(file);
+                }
+            }
+            files.sort();
+        } else {
+            files.push(path.to_path_buf());
+        }
+        if files.is_empty() {
+            return Err(format!("{}: no .glsl files", path.display()));
+        }
+
+        let mut passes = Vec::new();
+        for file in files {
+            let source = std::fs::read_to_string(&file)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            passes.push(
+                Pass::parse(&source).map_err(|e| format!("{}: {}", file.display(), e))?,
+            );
+        }
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        Ok(Preset {
+            name: name.to_string(),
+            passes,
+        })
+    }
+}
+
+const PLAIN: &str = "void main() {
+    outColor = texture(tex, Texcoord);
+}
+";
+
+const LCD: &str = "// filter: nearest
+void main() {
+    vec3 color = texture(tex, Texcoord).rgb;
+    // One window pixel wide lines between the Game Boy pixels
+    vec2 cell = fract(Texcoord * screen_size);
+    vec2 line = screen_size / output_size;
+    vec2 inside = step(line, cell);
+    outColor = vec4(color * mix(0.75, 1.0, inside.x * inside.y), 1.0);
+}
+";
+
+const DMG: &str = "void main() {
+    vec3 color = texture(tex, Texcoord).rgb;
+    float luma = dot(color, vec3(0.299, 0.587, 0.114));
+    // From the darkest to the lightest green of the original screen
+    vec3 dark = vec3(0.06, 0.22, 0.06);
+    vec3 light = vec3(0.61, 0.74, 0.06);
+    outColor = vec4(mix(dark, light, luma), 1.0);
+}
+";
+
+const SCANLINES: &str = "// filter: nearest
+void main() {
+    vec3 color = texture(tex, Texcoord).rgb;
+    // Darkens the lower half of each Game Boy line
+    float y = fract(Texcoord.y * screen_size.y);
+    outColor = vec4(color * mix(1.0, 0.7, smoothstep(0.5, 1.0, y)), 1.0);
+}
+";
+
+// Bleeds each pixel into its neighbours, like the beam of a tube
+const CRT_GLOW: &str = "// filter: nearest
+// scale: 4
+void main() {
+    vec2 texel = vec2(1.0 / source_size.x, 0.0);
+    vec3 color = texture(tex, Texcoord).rgb * 0.6
+        + texture(tex, Texcoord - texel).rgb * 0.2
+        + texture(tex, Texcoord + texel).rgb * 0.2;
+    outColor = vec4(color, 1.0);
+}
+";
+
+const CRT: &str = "// filter: linear
+void main() {
+    // Bulges the picture like the glass of a tube
+    vec2 uv = Texcoord * 2.0 - 1.0;
+    uv += uv * uv.yx * uv.yx * 0.06;
+    uv = uv * 0.5 + 0.5;
+    if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+        outColor = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    vec3 color = texture(tex, uv).rgb;
+    float scanline = 0.8 + 0.2 * sin(uv.y * screen_size.y * 6.2831853);
+    float vignette = pow(16.0 * uv.x * uv.y * (1.0 - uv.x) * (1.0 - uv.y), 0.2);
+    outColor = vec4(color * scanline * vignette, 1.0);
+}
+";
+
+// Presets that come with the emulator, the first one draws the screen as is
+pub fn builtin() -> Vec<Preset> {
+    vec![
+        Preset::builtin("none", &[PLAIN]),
+        Preset::builtin("lcd", &[LCD]),
+        Preset::builtin("dmg", &[DMG]),
+        Preset::builtin("scanlines", &[SCANLINES]),
+        Preset::builtin("crt", &[CRT_GLOW, CRT]),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{builtin, Pass, Preset};
+    use crate::gameboy::Filter;
+
+    #[test]
+    fn reads_pass_settings_from_comments() {
+        let pass = Pass::parse("// filter: linear\n//scale: 3\nvoid main() {}").unwrap();
+        assert_eq!(pass.filter, Some(Filter::Linear));
+        assert_eq!(pass.scale, Some(3));
+        assert_eq!(Pass::parse("void main() {}").unwrap().scale, None);
+
+        assert!(Pass::parse("// filter: bicubic").is_err());
+        assert!(Pass::parse("// scale: 0").is_err());
+        assert_eq!(builtin()[4].passes[0].scale, Some(4));
+    }
+
+    #[test]
+    fn loads_presets_from_directories() {
+        let dir =
+            std::env::temp_dir().join(format!("gameboy-preset-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("2-tint.glsl"), "void main() {}").unwrap();
+        std::fs::write(dir.join("1-blur.glsl"), "// scale: 2\nvoid main() {}").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a shader").unwrap();
+
+        let preset = Preset::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(preset.name.starts_with("gameboy-preset"));
+        let scales: Vec<_> = preset.passes.iter().map(|p| p.scale).collect();
+        assert_eq!(scales, vec![Some(2), None]);
+    }
+}
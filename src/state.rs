@@ -0,0 +1,253 @@
+use crate::mmu::StrResult;
+use crate::mode::GbMode;
+
+// Save states are a flat little endian dump of every component, in a fixed
+// order. The header ties them to a format version and to the cartridge.
+pub const MAGIC: &[u8; 4] = b"GBST";
+pub const VERSION: u8 = 4;
+
+const TRUNCATED: &str = "Save state is truncated";
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    // Prefixed with its length, for buffers whose size depends on the cartridge
+    pub fn buffer(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.bytes(v);
+    }
+
+    pub fn mode(&mut self, mode: GbMode) {
+        self.u8(match mode {
+            GbMode::Classic => 0,
+            GbMode::Color => 1,
+            GbMode::ColorAsClassic => 2,
+        });
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    fn take(&mut self, len: usize) -> StrResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(TRUNCATED);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> StrResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> StrResult<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> StrResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> StrResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> StrResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> StrResult<()> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    // Reads a length prefixed buffer, which has to match the size of `out`
+    pub fn buffer(&mut self, out: &mut [u8]) -> StrResult<()> {
+        if self.u32()? as usize != out.len() {
+            return Err("Save state does not match the cartridge memory");
+        }
+        self.bytes(out)
+    }
+
+    pub fn mode(&mut self) -> StrResult<GbMode> {
+        match self.u8()? {
+            0 => Ok(GbMode::Classic),
+            1 => Ok(GbMode::Color),
+            2 => Ok(GbMode::ColorAsClassic),
+            _ => Err("Save state has an invalid mode"),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gameboy::{Colorization, Gameboy, Target};
+
+    fn counter_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        // ld hl, C000; inc (hl); jr -3
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        rom
+    }
+
+    #[test]
+    fn restores_machine_state() {
+        let mut gb = Gameboy::new(counter_rom(), None);
+        gb.frame();
+        let state = gb.save_state();
+        gb.frame();
+        let expected = (gb.peek(0xC000), gb.registers().pc, gb.image().to_vec());
+
+        gb.frame();
+        gb.load_state(&state).unwrap();
+        gb.frame();
+        assert_eq!(
+            (gb.peek(0xC000), gb.registers().pc, gb.image().to_vec()),
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_state() {
+        let mut gb = Gameboy::new(counter_rom(), None);
+        gb.frame();
+        let state = gb.save_state();
+        gb.frame();
+        let before = gb.peek(0xC000);
+
+        let result = gb.load_state(&state[..state.len() - 1]);
+        assert_eq!(result, Err("Save state is truncated"));
+        assert_eq!(gb.peek(0xC000), before);
+        assert_eq!(gb.load_state(b"nope"), Err("Not a save state"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_banks() {
+        let mut rom = counter_rom();
+        // MBC5 with 2 ROM banks and no RAM
+        rom[0x147] = 0x19;
+        let mut gb = Gameboy::new(rom, None);
+        gb.frame();
+        let mut state = gb.save_state();
+        gb.frame();
+        let before = gb.peek(0xC000);
+
+        // The state ends with the ROM bank, the RAM bank and the RAM enable flag
+        let rombank = state.len() - 9;
+        state[rombank..rombank + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(gb.load_state(&state), Err("Save state is invalid"));
+        assert_eq!(gb.peek(0xC000), before);
+
+        state[rombank..rombank + 4].copy_from_slice(&1u32.to_le_bytes());
+        state[rombank + 4..rombank + 8].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(gb.load_state(&state), Err("Save state is invalid"));
+    }
+
+    #[test]
+    fn rejects_state_of_another_model() {
+        let mut dmg = Gameboy::with_target(counter_rom(), None, Target::GameBoy);
+        dmg.frame();
+        let state = dmg.save_state();
+
+        let mut sgb = Gameboy::with_target(counter_rom(), None, Target::SuperGameBoy);
+        sgb.load_boot_rom(vec![0; 0x100]).unwrap();
+        assert_eq!(
+            sgb.load_state(&state),
+            Err("Save state was made for another model")
+        );
+        // Still running the boot ROM
+        assert_eq!(sgb.registers().pc, 0);
+        sgb.step();
+        assert_eq!(sgb.registers().pc, 1);
+    }
+
+    #[test]
+    fn restores_colorization() {
+        let mut gb = Gameboy::new(counter_rom(), None);
+        gb.set_colorization(Colorization::Up);
+        // A blank screen in a light shade, which the two color differently
+        gb.poke(0xFF40, 0x91);
+        gb.poke(0xFF47, 0x55);
+        let state = gb.save_state();
+        gb.frame();
+        let up = gb.image().to_vec();
+
+        gb.load_state(&state).unwrap();
+        gb.set_colorization(Colorization::Left);
+        gb.frame();
+        assert_ne!(gb.image(), up);
+
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.colorization(), Some(Colorization::Up));
+        gb.frame();
+        assert_eq!(gb.image(), up);
+    }
+
+    #[test]
+    fn reset_keeps_cartridge_ram() {
+        let mut rom = vec![0; 0x8000];
+        // MBC3 with 8KB of RAM
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x02;
+        // ld a, 0A; ld (0000), a; ld hl, A000; inc (hl); ld hl, C000; inc (hl); jr -2
+        rom[0x100..0x10F].copy_from_slice(&[
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x21, 0x00, 0xA0, 0x34, 0x21, 0x00, 0xC0, 0x34,
+            0x18, 0xFE,
+        ]);
+
+        let mut fresh = Gameboy::new(rom.clone(), None);
+        fresh.frame();
+
+        let mut gb = Gameboy::new(rom, None);
+        gb.frame();
+        gb.frame();
+        gb.reset();
+        gb.frame();
+        assert_eq!(gb.peek(0xA000), 2);
+        assert_eq!(gb.peek(0xC000), fresh.peek(0xC000));
+        assert_eq!(gb.registers().pc, fresh.registers().pc);
+    }
+}
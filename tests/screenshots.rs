@@ -0,0 +1,177 @@
+// Screenshot regression tests. ROMs and their reference images are looked up
+// in $GAMEBOY_SCREENSHOTS (or tests/screenshots) and the tests are skipped
+// when they are missing:
+//
+//   dmg-acid2.gb  dmg-acid2.png  (img/reference-dmg.png upstream)
+//   cgb-acid2.gbc cgb-acid2.png  (img/reference.png upstream)
+
+use gameboy::gameboy::{ColorCorrection, Gameboy, Target};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+
+type Rgb = [u8; 3];
+
+fn screenshot_dir() -> PathBuf {
+    match env::var_os("GAMEBOY_SCREENSHOTS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/screenshots"),
+    }
+}
+
+fn load_reference(path: &Path) -> Vec<Rgb> {
+    let image = image::open(path).unwrap().to_rgb8();
+    assert_eq!(
+        (image.width() as usize, image.height() as usize),
+        (WIDTH, HEIGHT),
+        "{} is not a 160x144 screenshot",
+        path.display()
+    );
+    image.pixels().map(|p| p.0).collect()
+}
+
+// The emulator uses its own DMG shades, so those colors are not compared
+// directly. Every reference color has to map onto a single
+// output color (the one most of its pixels got, the largest color on a tie)
+// and no two reference colors may share one. Returns whether each pixel
+// matched.
+fn compare(reference: &[Rgb], actual: &[Rgb]) -> Vec<bool> {
+    let mut counts: HashMap<Rgb, HashMap<Rgb, usize>> = HashMap::new();
+    for (r, a) in reference.iter().zip(actual) {
+        *counts.entry(*r).or_default().entry(*a).or_default() += 1;
+    }
+
+    let mut mapping: Vec<(Rgb, Rgb, usize)> = counts
+        .into_iter()
+        .map(|(r, seen)| {
+            let (a, n) = seen.into_iter().max_by_key(|&(a, n)| (n, a)).unwrap();
+            (r, a, n)
+        })
+        .collect();
+    // Larger areas win when two reference colors claim the same output color,
+    // then larger colors, so the outcome does not depend on hashing
+    mapping.sort_by(|a, b| (b.2, b.0).cmp(&(a.2, a.0)));
+    let mut taken = Vec::new();
+    let mut expected = HashMap::new();
+    for (r, a, _) in mapping {
+        if !taken.contains(&a) {
+            taken.push(a);
+            expected.insert(r, a);
+        }
+    }
+
+    reference
+        .iter()
+        .zip(actual)
+        .map(|(r, a)| expected.get(r) == Some(a))
+        .collect()
+}
+
+fn save(path: &Path, pixels: &[Rgb]) {
+    let data: Vec<u8> = pixels.iter().flatten().copied().collect();
+    image::save_buffer(
+        path,
+        &data,
+        WIDTH as u32,
+        HEIGHT as u32,
+        image::ExtendedColorType::Rgb8,
+    )
+    .unwrap();
+}
+
+// Runs `rom` on `target` for `frames` frames and compares the screen against
+// `reference`, pixel for pixel when `exact` and through `compare` otherwise.
+// On failure the screen and a diff with mismatches in red are saved next to
+// the test binaries.
+fn assert_screenshot(name: &str, target: Target, frames: u32, exact: bool) {
+    let dir = screenshot_dir();
+    let rom = ["gb", "gbc"]
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)))
+        .find(|path| path.exists());
+    let reference = dir.join(format!("{}.png", name));
+    let (Some(rom), true) = (rom, reference.exists()) else {
+        eprintln!("{} not found in {}, skipping", name, dir.display());
+        return;
+    };
+
+    let mut gb = Gameboy::with_target(fs::read(rom).unwrap(), None, target);
+    // CGB colors widened from 5 bits, like the reference is made
+    if exact {
+        gb.set_color_correction(ColorCorrection::None);
+    }
+    for _ in 0..frames {
+        gb.frame();
+    }
+
+    let actual: Vec<Rgb> = gb.image().chunks(4).map(|p| [p[0], p[1], p[2]]).collect();
+    let reference = load_reference(&reference);
+    let matches = if exact {
+        reference.iter().zip(&actual).map(|(r, a)| r == a).collect()
+    } else {
+        compare(&reference, &actual)
+    };
+
+    let mismatches: Vec<(usize, usize)> = matches
+        .iter()
+        .enumerate()
+        .filter(|(_, &ok)| !ok)
+        .map(|(i, _)| (i % WIDTH, i / WIDTH))
+        .collect();
+    if mismatches.is_empty() {
+        return;
+    }
+
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let diff: Vec<Rgb> = actual
+        .iter()
+        .zip(&matches)
+        .map(|(p, &ok)| {
+            if ok {
+                let gray = ((p[0] as u32 + p[1] as u32 + p[2] as u32) / 6) as u8;
+                [gray, gray, gray]
+            } else {
+                [255, 0, 0]
+            }
+        })
+        .collect();
+    save(&out.join(format!("{}-actual.png", name)), &actual);
+    save(&out.join(format!("{}-diff.png", name)), &diff);
+
+    panic!(
+        "{}: {} pixels differ, first at {:?}; see {}",
+        name,
+        mismatches.len(),
+        &mismatches[..mismatches.len().min(8)],
+        out.join(format!("{}-diff.png", name)).display()
+    );
+}
+
+#[test]
+fn dmg_acid2() {
+    assert_screenshot("dmg-acid2", Target::GameBoy, 60, false);
+}
+
+#[test]
+fn cgb_acid2() {
+    assert_screenshot("cgb-acid2", Target::GameBoyColor, 60, true);
+}
+
+#[test]
+fn compare_ignores_palette() {
+    let (w, b) = ([255, 255, 255], [0, 0, 0]);
+    let (light, dark) = ([200, 200, 200], [10, 10, 10]);
+    let reference = [w, b, b, b, w];
+    assert!(compare(&reference, &[light, dark, dark, dark, light])
+        .iter()
+        .all(|&ok| ok));
+
+    let wrong = [light, dark, dark, light, light];
+    assert_eq!(
+        compare(&reference, &wrong),
+        vec![true, true, true, false, true]
+    );
+}
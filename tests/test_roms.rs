@@ -0,0 +1,234 @@
+// Runs Blargg and Mooneye test ROMs found under $GAMEBOY_TEST_ROMS (or
+// tests/roms) and writes a markdown compatibility report. ROMs are not
+// shipped with the repository, the test is skipped when none are found. Only
+// ROMs missing from tests/known_failures.txt fail the test.
+
+use gameboy::gameboy::{Gameboy, Target};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, panic, thread};
+
+const BLARGG_FRAMES: u32 = 60 * 120;
+const MOONEYE_FRAMES: u32 = 60 * 20;
+
+// Mooneye ROMs load these into B, C, D, E, H and L before hitting `LD B,B`
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Suite {
+    Blargg,
+    Mooneye,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+enum Outcome {
+    Passed,
+    Failed(String),
+    Timeout,
+    Crashed,
+}
+
+struct TestResult {
+    name: String,
+    suite: Suite,
+    outcome: Outcome,
+}
+
+fn rom_dir() -> PathBuf {
+    match env::var_os("GAMEBOY_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms"),
+    }
+}
+
+fn discover(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            discover(&path, roms);
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("gb") | Some("gbc")
+        ) {
+            roms.push(path);
+        }
+    }
+}
+
+// The suites are told apart by where they were checked out.
+fn suite(path: &Path) -> Suite {
+    let mooneye = path
+        .components()
+        .any(|c| c.as_os_str().to_string_lossy().contains("mooneye"));
+    if mooneye {
+        Suite::Mooneye
+    } else {
+        Suite::Blargg
+    }
+}
+
+// Mooneye lists the models a ROM passes on after the last dash of its name,
+// as in `boot_regs-dmgABC`, `di_timing-GS` or `boot_hwio-C`.
+fn target(path: &Path) -> Target {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let models = stem.rsplit_once('-').map_or("", |(_, models)| models);
+    if models.contains("dmg") || models.contains("mgb") || models.starts_with('G') {
+        Target::GameBoy
+    } else if models.contains("sgb") || models.starts_with('S') {
+        Target::SuperGameBoy
+    } else {
+        Target::GameBoyColor
+    }
+}
+
+fn known_failures() -> Vec<String> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/known_failures.txt");
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn run_blargg(gb: &mut Gameboy) -> Outcome {
+    match gb.run_until_serial_contains(&["Passed", "Failed"], BLARGG_FRAMES) {
+        Some("Passed") => Outcome::Passed,
+        Some(_) => Outcome::Failed(gb.serial_output().trim().replace('\n', " ")),
+        None => Outcome::Timeout,
+    }
+}
+
+fn run_mooneye(gb: &mut Gameboy) -> Outcome {
+    let breakpoint = Arc::new(AtomicBool::new(false));
+    let hit = breakpoint.clone();
+    gb.on_execute(0x0000..=0xFFFF, move |access| {
+        if access.value == 0x40 {
+            hit.store(true, Ordering::Relaxed);
+        }
+    });
+
+    let mut ticks = 0;
+    while ticks < MOONEYE_FRAMES * gameboy::gameboy::CYCLES {
+        ticks += gb.step();
+        if breakpoint.load(Ordering::Relaxed) {
+            let r = gb.registers();
+            let found = [r.b, r.c, r.d, r.e, r.h, r.l];
+            return if found == FIBONACCI {
+                Outcome::Passed
+            } else {
+                Outcome::Failed(format!("registers {:?}", found))
+            };
+        }
+    }
+    Outcome::Timeout
+}
+
+fn run(path: &Path) -> TestResult {
+    let suite = suite(path);
+    let rom = fs::read(path).unwrap();
+    let target = target(path);
+    let outcome = panic::catch_unwind(|| {
+        let mut gb = Gameboy::with_target(rom, None, target);
+        match suite {
+            Suite::Blargg => run_blargg(&mut gb),
+            Suite::Mooneye => run_mooneye(&mut gb),
+        }
+    })
+    .unwrap_or(Outcome::Crashed);
+
+    TestResult {
+        name: path.to_string_lossy().into_owned(),
+        suite,
+        outcome,
+    }
+}
+
+fn report(results: &[TestResult]) -> String {
+    let passed = results
+        .iter()
+        .filter(|r| r.outcome == Outcome::Passed)
+        .count();
+
+    let mut out = String::from("# Test ROM compatibility\n\n");
+    let _ = writeln!(out, "{} of {} passing\n", passed, results.len());
+    out.push_str("| ROM | Suite | Status |\n| --- | --- | --- |\n");
+    for result in results {
+        let status = match result.outcome {
+            Outcome::Passed => String::from("✅"),
+            Outcome::Failed(ref reason) => format!("❌ {}", reason),
+            Outcome::Timeout => String::from("⏱ Timeout"),
+            Outcome::Crashed => String::from("💥 Crashed"),
+        };
+        let _ = writeln!(out, "| {} | {:?} | {} |", result.name, result.suite, status);
+    }
+    out
+}
+
+#[test]
+fn test_roms() {
+    let dir = rom_dir();
+    let mut roms = Vec::new();
+    discover(&dir, &mut roms);
+    if roms.is_empty() {
+        eprintln!("no test ROMs found in {}, skipping", dir.display());
+        return;
+    }
+    roms.sort();
+
+    let queue = Mutex::new(roms.clone());
+    let results = Mutex::new(Vec::new());
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                let Some(path) = queue.lock().unwrap().pop() else {
+                    break;
+                };
+                let result = run(&path);
+                results.lock().unwrap().push(result);
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+    for result in results.iter_mut() {
+        if let Ok(name) = Path::new(&result.name).strip_prefix(&dir) {
+            result.name = name.to_string_lossy().replace('\\', "/");
+        }
+    }
+
+    let path = match env::var_os("GAMEBOY_TEST_REPORT") {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(env!("CARGO_TARGET_TMPDIR")).join("test-roms.md"),
+    };
+    fs::write(&path, report(&results)).unwrap();
+    eprintln!("report written to {}", path.display());
+
+    let known = known_failures();
+    let (failures, regressions): (Vec<_>, Vec<_>) = results
+        .iter()
+        .filter(|r| r.outcome != Outcome::Passed)
+        .map(|r| r.name.as_str())
+        .partition(|name| known.iter().any(|k| k == name));
+    let fixed: Vec<_> = known
+        .iter()
+        .filter(|k| results.iter().any(|r| &r.name == *k))
+        .filter(|k| !failures.contains(&k.as_str()))
+        .collect();
+    if !fixed.is_empty() {
+        eprintln!("passing now, remove from known_failures.txt: {:?}", fixed);
+    }
+    assert!(
+        regressions.is_empty(),
+        "failing test ROMs: {:?}",
+        regressions
+    );
+}